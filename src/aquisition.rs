@@ -0,0 +1,2656 @@
+use std::{
+    borrow::{Borrow, BorrowMut},
+    collections::BTreeMap,
+    f64::consts::PI,
+    fmt::Display,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local, NaiveDateTime};
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
+use itertools::Itertools;
+use nanonis::DatFile;
+use rustfft::{num_complex::Complex, FftPlanner};
+use serde::{Deserialize, Serialize};
+
+use crate::power_automate::WavegenSettings;
+
+const MIN_SPECTRUM_SAMPLES: usize = 8;
+// samples at the end of the first record matched against the second when splicing
+const OVERLAP_MATCH_SAMPLES: usize = 8;
+// rms mismatch of the overlap, relative to each channel's scale
+const OVERLAP_TOLERANCE: f64 = 1e-2;
+// default minimum prominence of an extremum, as a fraction of the channel's range
+const EXTREMUM_PROMINENCE: f64 = 0.5;
+// relative sample period difference that `combine_resampled` will interpolate across
+const MAX_PERIOD_MISMATCH: f64 = 1e-2;
+// without a rail, a smooth peak lingers within this many ADC steps of its top about as long as it
+// sits on it, while a saturated signal hits its limit head on
+const PEAK_SHOULDER_STEPS: f64 = 4.;
+// how many times longer than that shoulder a railless flat run has to be to count as clipped
+const CLIP_DWELL_FACTOR: usize = 4;
+
+const SP_PATTERN: &str = "Sample Period (ms)";
+const TIME_PATTERN: &str = "Time (s)";
+const PROBE_PATTERN: &str = "Capacitive Probe (m)";
+const CURRENT_PATTERN: &str = "Current (A)";
+const VOLTAGE_PATTERN: &str = "Voltage Monitor (V)";
+pub(crate) const PKPK_KEY: &str = "pkpk";
+pub(crate) const PERIOD_KEY: &str = "period_s";
+pub(crate) const SYMMETRY_KEY: &str = "symmetry_p";
+pub(crate) const OFFSET_KEY: &str = "offset";
+pub(crate) const GAIN_KEY: &str = "wavegen_gain";
+// the header `nanonis_save_history` starts every export with
+const NANONIS_EXPERIMENT: &str = "History Data";
+const NANONIS_DATE_FORMAT: &str = "%d.%m.%Y %H:%M:%S";
+const NANONIS_HEADER_KEYS: [&str; 4] = ["Experiment", "Date", "User", "Saved Date"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Channel {
+    Probe,
+    Current,
+    Voltage,
+}
+impl Channel {
+    pub const ALL: [Channel; 3] = [Channel::Probe, Channel::Current, Channel::Voltage];
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Channel::Probe => PROBE_PATTERN,
+            Channel::Current => CURRENT_PATTERN,
+            Channel::Voltage => VOLTAGE_PATTERN,
+        }
+    }
+    fn key(&self) -> &'static str {
+        match self {
+            Channel::Probe => "probe",
+            Channel::Current => "current",
+            Channel::Voltage => "voltage",
+        }
+    }
+}
+
+// header names of the signals, for Nanonis setups that name them differently
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelPatterns {
+    pub sample_period: String,
+    pub probe: String,
+    pub current: String,
+    pub voltage: String,
+}
+impl Default for ChannelPatterns {
+    fn default() -> Self {
+        Self {
+            sample_period: SP_PATTERN.into(),
+            probe: PROBE_PATTERN.into(),
+            current: CURRENT_PATTERN.into(),
+            voltage: VOLTAGE_PATTERN.into(),
+        }
+    }
+}
+impl ChannelPatterns {
+    pub fn channel(&self, channel: Channel) -> &str {
+        match channel {
+            Channel::Probe => &self.probe,
+            Channel::Current => &self.current,
+            Channel::Voltage => &self.voltage,
+        }
+    }
+    // calibrated files name their channels in the calibration attributes
+    fn calibrated(&self, attributes: &BTreeMap<String, String>) -> Self {
+        let mut patterns = self.clone();
+        for channel in Channel::ALL {
+            if let Some(label) = attributes.get(&calibration_key(channel, "label")) {
+                *match channel {
+                    Channel::Probe => &mut patterns.probe,
+                    Channel::Current => &mut patterns.current,
+                    Channel::Voltage => &mut patterns.voltage,
+                } = label.trim().to_string();
+            }
+        }
+        patterns
+    }
+    // an exact match first, then the name without its unit among differently formatted headers,
+    // then just its first word, so `Voltage Monitor (V)` still finds a `Voltage (V)` column
+    fn find(&self, headers: &[&str], channel: Channel) -> Result<usize> {
+        let pattern = self.channel(channel);
+        if let Some(i) = headers.iter().position(|h| h.trim() == pattern) {
+            return Ok(i);
+        }
+        let name = pattern
+            .split([' ', '\t'])
+            .take_while(|word| !word.starts_with(['(', '[']))
+            .join(" ");
+        let first_word = name.split(' ').next().unwrap_or_default();
+        find_column(headers, &name).or_else(|e| find_column(headers, first_word).map_err(|_| e))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DetrendMode {
+    Mean,
+    Linear,
+    Polynomial(usize),
+    MovingBaseline { window: usize },
+}
+impl Display for DetrendMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DetrendMode::Mean => write!(f, "mean"),
+            DetrendMode::Linear => write!(f, "linear"),
+            DetrendMode::Polynomial(n) => write!(f, "polynomial({n})"),
+            DetrendMode::MovingBaseline { window } => write!(f, "moving baseline({window})"),
+        }
+    }
+}
+
+// how the text readers split and parse a file, for exports from setups other than ours
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ReaderOptions {
+    pub patterns: ChannelPatterns,
+    // `None` picks whichever of tab, `;` and `,` splits the channel header into the most columns
+    pub delimiter: Option<char>,
+    pub decimal: DecimalSeparator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecimalSeparator {
+    // comma if any field of the first data row holds one, which a comma delimiter rules out
+    #[default]
+    Auto,
+    Point,
+    // as written by a nanonis on a European locale
+    Comma,
+}
+
+// candidates for `ReaderOptions::delimiter`, with tab last so it wins a tie
+const DELIMITERS: [char; 3] = [',', ';', '\t'];
+
+fn detect_delimiter(header: &str) -> char {
+    DELIMITERS
+        .into_iter()
+        .max_by_key(|&d| header.split(d).filter(|h| !h.trim().is_empty()).count())
+        .unwrap()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Dat,
+    Csv,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Dat => "dat",
+            OutputFormat::Csv => "csv",
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelLimits {
+    // rails of the channels to check, found however their headers are labelled. `None` falls back
+    // to the signal's own min/max, where only runs pinned to a single ADC code and far longer
+    // than a smooth peak would dwell there count
+    pub rails: BTreeMap<Channel, Option<(f64, f64)>>,
+    pub min_run: usize,
+    pub tolerance: f64,
+}
+impl Default for ChannelLimits {
+    fn default() -> Self {
+        Self {
+            rails: [(Channel::Probe, None), (Channel::Current, None)].into(),
+            min_run: 5,
+            tolerance: 1e-6,
+        }
+    }
+}
+
+// `raw * multiplier + offset`, written under `label`, e.g. `Drive Voltage (V)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    pub multiplier: f64,
+    pub offset: f64,
+    pub label: String,
+}
+
+// converts recorded signals to physical units before they are written. the raw label and factors
+// are kept as attributes, so the conversion can be undone and is never applied twice
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChannelCalibration {
+    pub channels: BTreeMap<Channel, Calibration>,
+}
+impl ChannelCalibration {
+    pub fn apply(&self, datfile: &mut DatFile) -> Result<()> {
+        let attributes = datfile
+            .attributes
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let patterns = ChannelPatterns::default().calibrated(&attributes);
+        for (&channel, calibration) in &self.channels {
+            if attributes.contains_key(&calibration_key(channel, "label")) {
+                continue;
+            }
+            let names = datfile.signals.keys().map(String::as_str).collect_vec();
+            let raw_label = names[patterns.find(&names, channel)?].to_string();
+            let mut signal = datfile.signals.remove(&raw_label).unwrap();
+            for v in &mut signal {
+                *v = *v * calibration.multiplier + calibration.offset;
+            }
+            datfile.signals.insert(calibration.label.clone(), signal);
+            let attrs = [
+                ("label", calibration.label.clone()),
+                ("raw_label", raw_label),
+                ("multiplier", format_attribute(calibration.multiplier)),
+                ("offset", format_attribute(calibration.offset)),
+            ];
+            let mut attributes = AcqAttributes(&mut datfile.attributes);
+            for (field, value) in attrs {
+                attributes.set_str(calibration_key(channel, field), value);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn calibration_key(channel: Channel, field: &str) -> String {
+    format!("calibration_{}_{field}", channel.key())
+}
+
+// a response counts as settled once the last `window` of `channel` passes `test`, in the
+// channel's calibrated units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SettleCriterion {
+    pub channel: Channel,
+    pub window: Duration,
+    pub test: SettleTest,
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SettleTest {
+    // standard deviation over the window
+    StdBelow(f64),
+    // magnitude of the least squares slope over the window, per second
+    SlopeBelow(f64),
+}
+impl SettleCriterion {
+    // the first sample count from `from` on at which the window ending there passes, checked
+    // every tenth of a window and at the end of `samples`. `None` if none passes
+    pub fn settled_at(&self, samples: &[f64], sample_period_ms: f64, from: usize) -> Option<usize> {
+        let n = ((self.window.as_secs_f64() * 1000. / sample_period_ms).round() as usize).max(2);
+        let first = from.max(n);
+        if first > samples.len() {
+            return None;
+        }
+        (first..=samples.len())
+            .step_by((n / 10).max(1))
+            .chain([samples.len()])
+            .find(|&end| self.passes(&samples[end - n..end], sample_period_ms))
+    }
+    fn passes(&self, window: &[f64], sample_period_ms: f64) -> bool {
+        let n = window.len() as f64;
+        let mean = window.iter().sum::<f64>() / n;
+        match self.test {
+            SettleTest::StdBelow(max) => {
+                let var = window.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+                var.sqrt() < max
+            }
+            SettleTest::SlopeBelow(max) => {
+                let t_mean = (n - 1.) / 2.;
+                let (cov, var) = window
+                    .iter()
+                    .enumerate()
+                    .fold((0., 0.), |(cov, var), (i, v)| {
+                        let dt = i as f64 - t_mean;
+                        (cov + dt * (v - mean), var + dt * dt)
+                    });
+                (cov / var).abs() * 1000. / sample_period_ms < max
+            }
+        }
+    }
+    // `settled_at` on the raw channel of a recording, calibrated on the fly
+    pub(crate) fn settled_in(
+        &self,
+        datfile: &DatFile,
+        calibration: &ChannelCalibration,
+        from: usize,
+    ) -> Result<Option<usize>> {
+        let names = datfile.signals.keys().map(String::as_str).collect_vec();
+        let name = names[ChannelPatterns::default().find(&names, self.channel)?];
+        let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+        let samples = &datfile.signals[name];
+        Ok(match calibration.channels.get(&self.channel) {
+            Some(c) => {
+                let calibrated = samples.iter().map(|v| v * c.multiplier).collect_vec();
+                self.settled_at(&calibrated, sample_period, from)
+            }
+            None => self.settled_at(samples, sample_period, from),
+        })
+    }
+}
+impl Display for SettleCriterion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (test, max) = match self.test {
+            SettleTest::StdBelow(max) => ("std", max),
+            SettleTest::SlopeBelow(max) => ("slope/s", max),
+        };
+        write!(
+            f,
+            "{} {test} < {max} over {} s",
+            self.channel.key(),
+            self.window.as_secs_f64()
+        )
+    }
+}
+
+// probe response relative to the voltage monitor at a single drive frequency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrequencyResponse {
+    pub frequency_hz: f64,
+    pub magnitude: f64,
+    pub phase_deg: f64,
+    pub coherence: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Extremum {
+    Maximum(f64),
+    Minimum(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipReport {
+    pub channel: String,
+    pub fraction: f64,
+    pub ranges_s: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aquisition {
+    pub probe: Vec<f64>,
+    pub current: Vec<f64>,
+    pub voltage: Vec<f64>,
+    pub wavegen_settings: WavegenSettings,
+    pub sample_period_ms: f64,
+    pub metadata: BTreeMap<String, String>,
+    pub patterns: ChannelPatterns,
+    // header labels that differ from the channel patterns
+    pub labels: BTreeMap<Channel, String>,
+    // other signals in the file, such as Bias or Z, carried along under their header labels
+    pub extra: BTreeMap<String, Vec<f64>>,
+}
+impl Aquisition {
+    // for data that didn't come from a file, with the default channel labels and no metadata
+    pub fn new(
+        probe: Vec<f64>,
+        current: Vec<f64>,
+        voltage: Vec<f64>,
+        wavegen_settings: WavegenSettings,
+        sample_period_ms: f64,
+    ) -> Result<Self> {
+        if probe.is_empty() {
+            bail!("Channels can't be empty");
+        }
+        if current.len() != probe.len() || voltage.len() != probe.len() {
+            bail!(
+                "Channels have different lengths: {} probe, {} current and {} voltage samples",
+                probe.len(),
+                current.len(),
+                voltage.len()
+            );
+        }
+        if sample_period_ms.is_nan() || sample_period_ms <= 0. {
+            bail!("Sample period must be positive, not {sample_period_ms} ms");
+        }
+        Ok(Self {
+            probe,
+            current,
+            voltage,
+            wavegen_settings,
+            sample_period_ms,
+            metadata: BTreeMap::new(),
+            patterns: ChannelPatterns::default(),
+            labels: BTreeMap::new(),
+            extra: BTreeMap::new(),
+        })
+    }
+    pub fn label(&self, channel: Channel) -> &str {
+        self.labels
+            .get(&channel)
+            .map_or(self.patterns.channel(channel), String::as_str)
+    }
+    // written as a `key\tvalue\t` header line, so neither may hold tabs or line breaks, and the
+    // key can't shadow the sample period or settings attributes
+    pub fn set_meta(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        if key.trim().is_empty() {
+            bail!("Metadata keys can't be empty");
+        }
+        if let Some(c) = key
+            .chars()
+            .chain(value.chars())
+            .find(|c| matches!(c, '\t' | '\r' | '\n'))
+        {
+            bail!("Metadata `{key}` contains {c:?}, which can't be written to a header");
+        }
+        let reserved = self.wavegen_settings.attributes().map(|(k, _)| k);
+        if key == self.patterns.sample_period || reserved.contains(&key) {
+            bail!("`{key}` is a reserved attribute");
+        }
+        self.metadata.insert(key, value);
+        Ok(())
+    }
+    pub fn get_meta(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+    // when the nanonis saved the data, from its `Date` header or the `Saved Date` of its other
+    // exports. `None` for data that didn't come from the nanonis or a date in neither its format
+    // nor RFC 3339
+    pub fn acquired_date(&self) -> Option<DateTime<Local>> {
+        let date = self
+            .get_meta("Date")
+            .or_else(|| self.get_meta("Saved Date"))?
+            .trim();
+        match NaiveDateTime::parse_from_str(date, NANONIS_DATE_FORMAT) {
+            Ok(naive) => naive.and_local_timezone(Local).earliest(),
+            Err(_) => DateTime::parse_from_rfc3339(date)
+                .ok()
+                .map(|d| d.with_timezone(&Local)),
+        }
+    }
+    pub fn set_acquired_date(&mut self, date: DateTime<Local>) {
+        let date = date.format(NANONIS_DATE_FORMAT).to_string();
+        self.metadata.insert("Date".into(), date);
+    }
+    pub fn experiment(&self) -> Option<&str> {
+        self.get_meta("Experiment")
+    }
+    pub fn scale_channel(&mut self, channel: Channel, factor: f64, unit: &str) {
+        for v in self.channel_mut(channel) {
+            *v *= factor;
+        }
+        let label = with_unit(self.label(channel), unit);
+        self.labels.insert(channel, label);
+    }
+    pub fn channel(&self, channel: Channel) -> &[f64] {
+        match channel {
+            Channel::Probe => &self.probe,
+            Channel::Current => &self.current,
+            Channel::Voltage => &self.voltage,
+        }
+    }
+    pub fn channel_mut(&mut self, channel: Channel) -> &mut Vec<f64> {
+        match channel {
+            Channel::Probe => &mut self.probe,
+            Channel::Current => &mut self.current,
+            Channel::Voltage => &mut self.voltage,
+        }
+    }
+    // samples recorded on every channel
+    pub fn len(&self) -> usize {
+        Channel::ALL
+            .map(|c| self.channel(c).len())
+            .into_iter()
+            .min()
+            .unwrap()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // one sample of every channel at a time, in `Channel::ALL` order
+    pub fn rows(&self) -> impl Iterator<Item = [f64; 3]> + '_ {
+        (0..self.len()).map(|i| Channel::ALL.map(|c| self.channel(c)[i]))
+    }
+    // the three channels followed by the extra ones, in the order they are written
+    fn columns(&self) -> Vec<(&str, &[f64])> {
+        Channel::ALL
+            .map(|c| (self.label(c), self.channel(c)))
+            .into_iter()
+            .chain(self.extra.iter().map(|(k, v)| (k.as_str(), v.as_slice())))
+            .collect()
+    }
+    fn column_rows<'a>(columns: &'a [(&str, &[f64])]) -> impl Iterator<Item = Vec<f64>> + 'a {
+        let len = columns.iter().map(|(_, v)| v.len()).min().unwrap_or(0);
+        (0..len).map(|i| columns.iter().map(|(_, v)| v[i]).collect())
+    }
+    pub fn times_s(&self) -> impl Iterator<Item = f64> + '_ {
+        (0..self.len()).map(|i| i as f64 * self.sample_period_ms / 1000.)
+    }
+    pub fn detrend(&self, channel: Channel, mode: DetrendMode) -> Result<Self> {
+        let signal = self.channel(channel);
+        let baseline = match mode {
+            DetrendMode::Mean => polyfit_eval(&self.times_s().collect_vec(), signal, 0)?,
+            DetrendMode::Linear => polyfit_eval(&self.times_s().collect_vec(), signal, 1)?,
+            DetrendMode::Polynomial(n) => polyfit_eval(&self.times_s().collect_vec(), signal, n)?,
+            DetrendMode::MovingBaseline { window } => moving_average(signal, window)?,
+        };
+        let mut aq = self.clone();
+        for (s, b) in aq.channel_mut(channel).iter_mut().zip(baseline) {
+            *s -= b;
+        }
+        aq.metadata
+            .insert(format!("{} detrend", self.label(channel)), mode.to_string());
+        Ok(aq)
+    }
+    // drops the runs at either end where `channel` changes by less than `threshold` per second
+    // between samples, such as the rests around a ramp. only the ends are trimmed, and a record
+    // that never moves that fast is returned whole
+    pub fn trim_flat(&self, channel: Channel, threshold: f64) -> Aquisition {
+        let min_step = threshold.abs() * self.sample_period_ms / 1000.;
+        let moving = |pair: &[f64]| (pair[1] - pair[0]).abs() >= min_step;
+        let signal = self.channel(channel);
+        let steps = || signal.windows(2);
+        let (Some(first), Some(last)) = (steps().position(moving), steps().rposition(moving))
+        else {
+            return self.clone();
+        };
+        // each step spans the sample after it too
+        let range = first..last + 2;
+        let mut aq = self.clone();
+        for channel in Channel::ALL {
+            *aq.channel_mut(channel) = self.channel(channel)[range.clone()].to_vec();
+        }
+        for signal in aq.extra.values_mut() {
+            *signal = signal[range.clone()].to_vec();
+        }
+        aq.metadata.insert(
+            format!("{} trim_flat", self.label(channel)),
+            format!("{}..{}", range.start, range.end),
+        );
+        aq
+    }
+    pub fn decimate(&self, factor: usize) -> Result<Self> {
+        if factor == 0 {
+            bail!("Decimation factor must be at least 1");
+        }
+        let mut aq = self.clone();
+        for channel in Channel::ALL {
+            *aq.channel_mut(channel) = boxcar_decimate(self.channel(channel), factor);
+        }
+        for signal in aq.extra.values_mut() {
+            *signal = boxcar_decimate(signal, factor);
+        }
+        aq.sample_period_ms *= factor as f64;
+        Ok(aq)
+    }
+    pub fn resample(&self, target_len: usize) -> Result<Self> {
+        if target_len < 2 {
+            bail!("Cannot resample to fewer than 2 samples");
+        }
+        let mut aq = self.clone();
+        for channel in Channel::ALL {
+            let signal = self.channel(channel);
+            if signal.len() < 2 {
+                bail!("`{}` has fewer than 2 samples", self.label(channel));
+            }
+            let step = (signal.len() - 1) as f64 / (target_len - 1) as f64;
+            *aq.channel_mut(channel) = interpolate(signal, target_len, step);
+        }
+        for signal in aq.extra.values_mut() {
+            if signal.len() >= 2 {
+                let step = (signal.len() - 1) as f64 / (target_len - 1) as f64;
+                *signal = interpolate(signal, target_len, step);
+            }
+        }
+        let len = self.len();
+        aq.sample_period_ms = self.sample_period_ms * (len - 1) as f64 / (target_len - 1) as f64;
+        Ok(aq)
+    }
+    pub fn detect_clipping(&self, limits: &ChannelLimits) -> Vec<ClipReport> {
+        Channel::ALL
+            .into_iter()
+            .filter_map(|c| {
+                let rail = limits.rails.get(&c)?;
+                Some(clip_report(
+                    self.label(c),
+                    self.channel(c),
+                    *rail,
+                    limits,
+                    self.sample_period_ms,
+                ))
+            })
+            .collect()
+    }
+    // single-sided amplitude spectrum, returned as (frequency in Hz, amplitude)
+    pub fn spectrum(&self, channel: Channel, hann: bool) -> Result<(Vec<f64>, Vec<f64>)> {
+        let signal = self.channel(channel);
+        let n = signal.len();
+        if n < MIN_SPECTRUM_SAMPLES {
+            bail!(
+                "`{}` has {n} samples, need at least {MIN_SPECTRUM_SAMPLES} for a spectrum",
+                self.label(channel)
+            );
+        }
+        if self.sample_period_ms.is_nan() || self.sample_period_ms <= 0. {
+            bail!("Invalid sample period {} ms", self.sample_period_ms);
+        }
+        let window: Vec<f64> = if hann {
+            (0..n)
+                .map(|i| 0.5 - 0.5 * (2. * PI * i as f64 / (n - 1) as f64).cos())
+                .collect()
+        } else {
+            vec![1.; n]
+        };
+        let mut buffer: Vec<Complex<f64>> = signal
+            .iter()
+            .zip(&window)
+            .map(|(x, w)| Complex::new(x * w, 0.))
+            .collect();
+        FftPlanner::new().plan_fft_forward(n).process(&mut buffer);
+
+        let gain: f64 = window.iter().sum();
+        let df = 1. / (n as f64 * self.sample_period_ms / 1000.);
+        let bins = n / 2 + 1;
+        let freqs = (0..bins).map(|k| k as f64 * df).collect();
+        let mags = buffer[..bins]
+            .iter()
+            .enumerate()
+            .map(|(k, x)| {
+                // DC and nyquist have no mirrored bin to fold in
+                let fold = if k == 0 || 2 * k == n { 1. } else { 2. };
+                x.norm() * fold / gain
+            })
+            .collect();
+        Ok((freqs, mags))
+    }
+    // rms difference between the voltage monitor and the commanded trapezoid,
+    // after shifting the trapezoid to best match the measurement
+    pub fn voltage_tracking_error(&self) -> f64 {
+        let voltage = self.channel(Channel::Voltage);
+        let n = voltage.len();
+        if n == 0 {
+            return f64::NAN;
+        }
+        let settings = self.wavegen_settings;
+        let reference = settings.generate_waveform(self.sample_period_ms, n);
+        let shift_s = best_lag(&reference, voltage) as f64 * self.sample_period_ms / 1000.;
+        let sum_sq: f64 = voltage
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let t = i as f64 * self.sample_period_ms / 1000.;
+                (v - settings.value_at(t - shift_s)).powi(2)
+            })
+            .sum();
+        (sum_sq / n as f64).sqrt()
+    }
+    // averages the cross spectrum of the probe and voltage monitor over each whole drive
+    // cycle in the record, using a single-bin DFT at `frequency_hz`
+    pub fn frequency_response(&self, frequency_hz: f64) -> Result<FrequencyResponse> {
+        if frequency_hz <= 0. || self.sample_period_ms <= 0. {
+            bail!("Frequency and sample period must be positive");
+        }
+        let cycle_len = 1000. / (frequency_hz * self.sample_period_ms);
+        let (probe, voltage) = (self.channel(Channel::Probe), self.channel(Channel::Voltage));
+        let len = probe.len().min(voltage.len());
+        let n_cycles = (len as f64 / cycle_len).floor() as usize;
+        if n_cycles == 0 || cycle_len < 2. {
+            bail!("Record does not hold a whole sampled cycle at {frequency_hz} Hz");
+        }
+        let omega = 2. * PI / cycle_len;
+        let mut cross = Complex::new(0., 0.);
+        let (mut probe_power, mut voltage_power) = (0., 0.);
+        for k in 0..n_cycles {
+            let start = (k as f64 * cycle_len).round() as usize;
+            let end = (((k + 1) as f64 * cycle_len).round() as usize).min(len);
+            let p = single_bin_dft(probe, start..end, omega);
+            let v = single_bin_dft(voltage, start..end, omega);
+            cross += p * v.conj();
+            probe_power += p.norm_sqr();
+            voltage_power += v.norm_sqr();
+        }
+        if voltage_power == 0. {
+            bail!("Voltage monitor has no component at {frequency_hz} Hz");
+        }
+        let response = cross / voltage_power;
+        let coherence = if probe_power > 0. {
+            cross.norm_sqr() / (probe_power * voltage_power)
+        } else {
+            0.
+        };
+        Ok(FrequencyResponse {
+            frequency_hz,
+            magnitude: response.norm(),
+            phase_deg: response.arg().to_degrees(),
+            coherence,
+        })
+    }
+    // splices `other` onto the end of `self`, dropping the samples they share
+    pub fn combine(&self, other: &Aquisition) -> Result<Self> {
+        if self.sample_period_ms != other.sample_period_ms {
+            bail!(
+                "Cannot combine sample periods of {} and {} ms",
+                self.sample_period_ms,
+                other.sample_period_ms
+            );
+        }
+        self.splice(other)
+    }
+    // like `combine`, but first interpolates `other` onto this sample period if they differ slightly
+    pub fn combine_resampled(&self, other: &Aquisition) -> Result<Self> {
+        let mismatch = (other.sample_period_ms / self.sample_period_ms - 1.).abs();
+        if mismatch == 0. {
+            return self.splice(other);
+        }
+        if mismatch.is_nan() || mismatch > MAX_PERIOD_MISMATCH {
+            bail!(
+                "Sample periods of {} and {} ms differ by more than {}%",
+                self.sample_period_ms,
+                other.sample_period_ms,
+                MAX_PERIOD_MISMATCH * 100.
+            );
+        }
+        self.splice(&other.resample_to_period(self.sample_period_ms)?)
+    }
+    pub fn resample_to_period(&self, sample_period_ms: f64) -> Result<Self> {
+        if sample_period_ms.is_nan() || sample_period_ms <= 0. {
+            bail!("Sample period must be positive");
+        }
+        let len = self.len();
+        if len < 2 {
+            bail!("Cannot resample fewer than 2 samples");
+        }
+        let ratio = sample_period_ms / self.sample_period_ms;
+        let target_len = ((len - 1) as f64 / ratio).floor() as usize + 1;
+        let mut aq = self.clone();
+        for channel in Channel::ALL {
+            *aq.channel_mut(channel) = interpolate(self.channel(channel), target_len, ratio);
+        }
+        for signal in aq.extra.values_mut() {
+            if signal.len() >= 2 {
+                *signal = interpolate(signal, target_len, ratio);
+            }
+        }
+        aq.sample_period_ms = sample_period_ms;
+        Ok(aq)
+    }
+    fn splice(&self, other: &Aquisition) -> Result<Self> {
+        if self.wavegen_settings != other.wavegen_settings {
+            bail!("Cannot combine aquisitions with different settings");
+        }
+        if self.channel_header() != other.channel_header() {
+            bail!("Cannot combine aquisitions with different channels");
+        }
+        let k = OVERLAP_MATCH_SAMPLES;
+        let (len, other_len) = (self.len(), other.len());
+        if len < k || other_len < k {
+            bail!("Need at least {k} samples in each aquisition to find their overlap");
+        }
+        let scales = Channel::ALL.map(|c| {
+            let max = self.channel(c).iter().fold(0., |m: f64, v| m.max(v.abs()));
+            if max > 0. {
+                max
+            } else {
+                1.
+            }
+        });
+        let mismatch = |j: usize| {
+            let sum_sq: f64 = Channel::ALL
+                .iter()
+                .zip(scales)
+                .map(|(&c, scale)| {
+                    let tail = &self.channel(c)[len - k..];
+                    let head = &other.channel(c)[j..j + k];
+                    tail.iter()
+                        .zip(head)
+                        .map(|(a, b)| ((a - b) / scale).powi(2))
+                        .sum::<f64>()
+                })
+                .sum();
+            (sum_sq / (k * Channel::ALL.len()) as f64).sqrt()
+        };
+        let (start, rms) = (0..=other_len - k)
+            .map(|j| (j, mismatch(j)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        if rms > OVERLAP_TOLERANCE {
+            bail!("Aquisitions do not overlap");
+        }
+        let mut aq = self.clone();
+        for channel in Channel::ALL {
+            aq.channel_mut(channel)
+                .extend_from_slice(&other.channel(channel)[start + k..]);
+        }
+        aq.extra.retain(|key, _| other.extra.contains_key(key));
+        for (key, signal) in &mut aq.extra {
+            signal.extend(other.extra[key].iter().skip(start + k));
+        }
+        Ok(aq)
+    }
+    pub fn align_phase(&self, reference: &Aquisition) -> Result<Self> {
+        if self.len() != reference.len() {
+            bail!(
+                "Cannot align aquisitions of {} and {} samples",
+                self.len(),
+                reference.len()
+            );
+        }
+        if self.wavegen_settings != reference.wavegen_settings
+            || self.sample_period_ms != reference.sample_period_ms
+        {
+            bail!("Cannot align aquisitions with different settings");
+        }
+        let len = self.len();
+        let period_samples =
+            (self.wavegen_settings.period.as_secs_f64() * 1000. / self.sample_period_ms) as usize;
+        let max_lag = if period_samples > 0 {
+            period_samples.min(len)
+        } else {
+            len
+        };
+        let centered = |s: &[f64]| {
+            let mean = s.iter().sum::<f64>() / s.len().max(1) as f64;
+            s.iter().map(|v| v - mean).collect_vec()
+        };
+        let (x, r) = (
+            centered(self.channel(Channel::Voltage)),
+            centered(reference.channel(Channel::Voltage)),
+        );
+        let lag = (0..max_lag)
+            .map(|k| (k, (0..len).map(|i| r[i] * x[(i + k) % len]).sum::<f64>()))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(0, |(k, _)| k);
+        let mut aq = self.clone();
+        for channel in Channel::ALL {
+            aq.channel_mut(channel).rotate_left(lag);
+        }
+        for signal in aq.extra.values_mut() {
+            let lag = lag.min(signal.len());
+            signal.rotate_left(lag);
+        }
+        Ok(aq)
+    }
+    pub fn find_extrema(&self, channel: Channel) -> Vec<(usize, Extremum)> {
+        let (lo, hi) = self
+            .channel(channel)
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            });
+        self.find_extrema_with(channel, (hi - lo) * EXTREMUM_PROMINENCE)
+    }
+    pub fn find_extrema_with(
+        &self,
+        channel: Channel,
+        min_prominence: f64,
+    ) -> Vec<(usize, Extremum)> {
+        find_extrema(self.channel(channel), min_prominence)
+    }
+    // the inverse of `from_datfile_with`, with the signals under their current labels
+    pub fn to_datfile(&self) -> DatFile {
+        DatFile {
+            attributes: self.header_attributes().into_iter().collect(),
+            signals: self
+                .columns()
+                .into_iter()
+                .map(|(label, signal)| (label.to_string(), signal.to_vec()))
+                .collect(),
+        }
+    }
+    pub fn from_datfile(datfile: &DatFile) -> Result<Self> {
+        Self::from_datfile_with(datfile, &ChannelPatterns::default())
+    }
+    pub fn from_datfile_with(datfile: &DatFile, patterns: &ChannelPatterns) -> Result<Self> {
+        let attributes = datfile
+            .attributes
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let patterns = &patterns.calibrated(&attributes);
+        let names = datfile.signals.keys().map(String::as_str).collect_vec();
+        let mut labels = BTreeMap::new();
+        let mut signal = |channel: Channel| -> Result<Vec<f64>> {
+            let name = names[patterns.find(&names, channel)?];
+            if name != patterns.channel(channel) {
+                labels.insert(channel, name.to_string());
+            }
+            Ok(datfile.signals[name].clone())
+        };
+        let (probe, current, voltage) = (
+            signal(Channel::Probe)?,
+            signal(Channel::Current)?,
+            signal(Channel::Voltage)?,
+        );
+        let found =
+            Channel::ALL.map(|c| labels.get(&c).map_or(patterns.channel(c), String::as_str));
+        let extra = datfile
+            .signals
+            .iter()
+            .filter(|(name, _)| !found.contains(&name.as_str()))
+            .map(|(name, signal)| (name.clone(), signal.clone()))
+            .collect();
+        let mut metadata = attributes;
+        let (sample_period_ms, settings) = take_settings(&mut metadata, patterns)?;
+        Ok(Self {
+            probe,
+            current,
+            voltage,
+            wavegen_settings: settings,
+            sample_period_ms,
+            metadata,
+            patterns: patterns.clone(),
+            labels,
+            extra,
+        })
+    }
+    pub fn read_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read_from_path(path)
+    }
+    pub fn read_from_file_with(path: impl AsRef<Path>, patterns: &ChannelPatterns) -> Result<Self> {
+        let options = ReaderOptions {
+            patterns: patterns.clone(),
+            ..Default::default()
+        };
+        Self::read_from_file_with_options(path, &options)
+    }
+    pub fn read_from_file_with_options(
+        path: impl AsRef<Path>,
+        options: &ReaderOptions,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        #[cfg(feature = "parquet")]
+        if path.extension().is_some_and(|e| e == "parquet") {
+            return Self::read_parquet_with(path, &options.patterns)
+                .with_context(|| format!("Failed to read `{}`", path.display()));
+        }
+        if is_csv_path(path) {
+            return Self::read_csv_with(open_maybe_gzip(path)?, &options.patterns)
+                .with_context(|| format!("Failed to read `{}`", path.display()));
+        }
+        Self::read_from_reader_with_options(open_maybe_gzip(path)?, options)
+            .with_context(|| format!("Failed to read `{}`", path.display()))
+    }
+    // reads a file this aquisition was written to back, catching a truncated or corrupted write
+    pub fn verify_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let written = Self::read_from_file_with(path, &self.patterns)?;
+        for channel in Channel::ALL {
+            let (expected, found) = (self.channel(channel).len(), written.channel(channel).len());
+            if expected != found {
+                bail!(
+                    "`{}` holds {found} samples of `{}`, but {expected} were written",
+                    path.display(),
+                    self.label(channel)
+                );
+            }
+        }
+        if written.sample_period_ms != self.sample_period_ms {
+            bail!(
+                "`{}` has a sample period of {} ms, but {} ms was written",
+                path.display(),
+                written.sample_period_ms,
+                self.sample_period_ms
+            );
+        }
+        Ok(())
+    }
+    pub fn read_from_path(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read_from_file_with(path, &ChannelPatterns::default())
+    }
+    pub fn read_from_reader<R: Read>(reader: R) -> Result<Self> {
+        Self::read_from_reader_with(reader, &ChannelPatterns::default())
+    }
+    pub fn read_from_reader_with<R: Read>(reader: R, patterns: &ChannelPatterns) -> Result<Self> {
+        let options = ReaderOptions {
+            patterns: patterns.clone(),
+            ..Default::default()
+        };
+        Self::read_from_reader_with_options(reader, &options)
+    }
+    pub fn read_from_reader_with_options<R: Read>(
+        reader: R,
+        options: &ReaderOptions,
+    ) -> Result<Self> {
+        let mut lines = BufReader::new(reader).lines();
+        // split once the delimiter is known from the channel header
+        let mut header_lines = vec![];
+        for line in &mut lines {
+            let line = line?;
+            if line.trim() == "[DATA]" {
+                break;
+            }
+            header_lines.push(line);
+        }
+        let header = lines.next().context("File has no channel header")??;
+        let delimiter = options
+            .delimiter
+            .unwrap_or_else(|| detect_delimiter(&header));
+        if delimiter == ',' && options.decimal == DecimalSeparator::Comma {
+            bail!("A comma can't be both the delimiter and the decimal separator");
+        }
+        let mut metadata = BTreeMap::new();
+        for line in &header_lines {
+            let Some((key, value)) = line.split(delimiter).next_tuple() else {
+                continue;
+            };
+            // CRLF files without the trailing tab leave a `\r` on the value
+            metadata.insert(key.trim().to_string(), value.trim().to_string());
+        }
+        let (sample_period_ms, settings) = take_settings(&mut metadata, &options.patterns)?;
+        let patterns = &options.patterns.calibrated(&metadata);
+        let headers = header
+            .split(delimiter)
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .collect_vec();
+        let (probe_i, current_i, voltage_i) = (
+            patterns.find(&headers, Channel::Probe)?,
+            patterns.find(&headers, Channel::Current)?,
+            patterns.find(&headers, Channel::Voltage)?,
+        );
+        let labels = [
+            (Channel::Probe, probe_i),
+            (Channel::Current, current_i),
+            (Channel::Voltage, voltage_i),
+        ]
+        .into_iter()
+        .filter(|(c, i)| headers[*i] != patterns.channel(*c))
+        .map(|(c, i)| (c, headers[i].to_string()))
+        .collect();
+        // columns are found by name, so any others are kept whatever order they come in
+        let extra_columns = (0..headers.len())
+            .filter(|i| ![probe_i, current_i, voltage_i].contains(i))
+            .collect_vec();
+        let mut probe = vec![];
+        let mut current = vec![];
+        let mut voltage = vec![];
+        let mut extra = vec![vec![]; extra_columns.len()];
+        let mut comma_decimal = match options.decimal {
+            DecimalSeparator::Auto => None,
+            DecimalSeparator::Point => Some(false),
+            DecimalSeparator::Comma => Some(true),
+        };
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = line
+                .split(delimiter)
+                .filter(|v| !v.trim().is_empty())
+                .collect_vec();
+            let comma =
+                *comma_decimal.get_or_insert_with(|| fields.iter().any(|v| v.contains(',')));
+            let values = fields
+                .into_iter()
+                .map(|v| {
+                    if !comma {
+                        return parse_attribute(v);
+                    }
+                    let v = v.trim();
+                    v.replace(',', ".")
+                        .parse()
+                        .with_context(|| format!("`{v}` is not a number"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if values.len() != headers.len() {
+                bail!(
+                    "A data row has {} values for {} columns",
+                    values.len(),
+                    headers.len()
+                );
+            }
+            probe.push(values[probe_i]);
+            current.push(values[current_i]);
+            voltage.push(values[voltage_i]);
+            for (signal, &i) in extra.iter_mut().zip(&extra_columns) {
+                signal.push(values[i]);
+            }
+        }
+        let extra = extra_columns
+            .iter()
+            .map(|&i| headers[i].to_string())
+            .zip(extra)
+            .collect();
+        Ok(Self {
+            probe,
+            current,
+            voltage,
+            wavegen_settings: settings,
+            sample_period_ms,
+            metadata,
+            patterns: patterns.clone(),
+            labels,
+            extra,
+        })
+    }
+    pub fn channel_header(&self) -> [&str; 3] {
+        Channel::ALL.map(|c| self.label(c))
+    }
+    pub fn header_attributes(&self) -> Vec<(String, String)> {
+        let settings = self.wavegen_settings;
+        let mut attrs = vec![(
+            self.patterns.sample_period.clone(),
+            format_attribute(self.sample_period_ms),
+        )];
+        attrs.extend(settings.attributes());
+        attrs.extend(self.metadata.clone());
+        attrs
+    }
+    pub fn write_to_writer<W: Write>(&self, mut writer: W, header: bool) -> Result<()> {
+        if header {
+            for (key, value) in self.header_attributes() {
+                writeln!(writer, "{key}\t{value}\t")?;
+            }
+            writeln!(writer)?;
+            writeln!(writer, "[DATA]")?;
+            writeln!(writer, "{}", self.columns().iter().map(|c| c.0).join("\t"))?;
+        }
+        self.write_data_only(writer)
+    }
+    // laid out like a `nanonis_save_history` export, CRLF line endings included, so Gwyddion and
+    // the nanonis viewer open it. the wavegen settings and metadata follow the nanonis attributes
+    pub fn write_nanonis_dat<W: Write>(&self, mut writer: W) -> Result<()> {
+        let date = self
+            .metadata
+            .get("Date")
+            .cloned()
+            .unwrap_or_else(|| Local::now().format(NANONIS_DATE_FORMAT).to_string());
+        let user = self.metadata.get("User").cloned().unwrap_or_default();
+        let saved = self
+            .metadata
+            .get("Saved Date")
+            .cloned()
+            .unwrap_or_else(|| Local::now().format(NANONIS_DATE_FORMAT).to_string());
+        let experiment = self.experiment().unwrap_or(NANONIS_EXPERIMENT).to_string();
+        let mut attrs = vec![
+            ("Experiment".to_string(), experiment),
+            ("Date".into(), date),
+            ("User".into(), user),
+            ("Saved Date".into(), saved),
+            (SP_PATTERN.into(), format_attribute(self.sample_period_ms)),
+        ];
+        attrs.extend(self.wavegen_settings.attributes());
+        attrs.extend(
+            self.metadata
+                .iter()
+                .filter(|(key, _)| !NANONIS_HEADER_KEYS.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        for (key, value) in attrs {
+            write!(writer, "{key}\t{value}\t\r\n")?;
+        }
+        write!(writer, "\r\n[DATA]\r\n")?;
+        let columns = self.columns();
+        write!(writer, "{}\r\n", columns.iter().map(|c| c.0).join("\t"))?;
+        for row in Self::column_rows(&columns) {
+            write!(
+                writer,
+                "{}\r\n",
+                row.iter().map(|x| format!("{x:E}")).join("\t")
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    // like `write_to_writer` with a header, but only the given channels in the given order
+    pub fn write_selected<W: Write>(&self, mut writer: W, channels: &[Channel]) -> Result<()> {
+        let Some(first) = channels.first() else {
+            bail!("No channels selected to write");
+        };
+        let len = self.channel(*first).len();
+        if let Some(c) = channels.iter().find(|c| self.channel(**c).len() != len) {
+            bail!(
+                "`{}` has {} samples but `{}` has {len}",
+                self.label(*c),
+                self.channel(*c).len(),
+                self.label(*first)
+            );
+        }
+        for (key, value) in self.header_attributes() {
+            writeln!(writer, "{key}\t{value}\t")?;
+        }
+        writeln!(writer)?;
+        writeln!(writer, "[DATA]")?;
+        writeln!(
+            writer,
+            "{}",
+            channels.iter().map(|c| self.label(*c)).join("\t")
+        )?;
+        for i in 0..len {
+            writeln!(
+                writer,
+                "{}",
+                channels.iter().map(|c| self.channel(*c)[i]).join("\t")
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> Result<()> {
+        for (key, value) in self.header_attributes() {
+            writeln!(writer, "# {key}={value}")?;
+        }
+        let mut csv = csv::Writer::from_writer(writer);
+        let columns = self.columns();
+        csv.write_record(
+            [TIME_PATTERN]
+                .into_iter()
+                .chain(columns.iter().map(|c| c.0)),
+        )?;
+        for (t, row) in self.times_s().zip(Self::column_rows(&columns)) {
+            csv.write_record([t].into_iter().chain(row).map(|x| x.to_string()))?;
+        }
+        csv.flush()?;
+        Ok(())
+    }
+    pub fn read_csv<R: Read>(reader: R) -> Result<Self> {
+        Self::read_csv_with(reader, &ChannelPatterns::default())
+    }
+    // the inverse of `write_csv`: `# key=value` attribute lines, then a header row led by the
+    // time column, which is rebuilt from the sample period rather than read
+    pub fn read_csv_with<R: Read>(reader: R, patterns: &ChannelPatterns) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let mut attributes = BTreeMap::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                bail!("CSV file has no header row");
+            }
+            let Some(attribute) = line.trim_end().strip_prefix("# ") else {
+                break;
+            };
+            let (key, value) = attribute
+                .split_once('=')
+                .with_context(|| format!("Malformed CSV attribute `{attribute}`"))?;
+            attributes.insert(key.to_string(), value.to_string());
+        }
+        let mut csv = csv::Reader::from_reader(line.as_bytes().chain(reader));
+        let headers = csv.headers()?.clone();
+        let mut signals = headers
+            .iter()
+            .filter(|h| *h != TIME_PATTERN)
+            .map(|h| (h.to_string(), vec![]))
+            .collect::<BTreeMap<_, _>>();
+        for record in csv.records() {
+            let record = record?;
+            for (name, value) in headers.iter().zip(&record) {
+                if let Some(signal) = signals.get_mut(name) {
+                    signal.push(
+                        value.trim().parse().with_context(|| {
+                            format!("Invalid value `{value}` in column `{name}`")
+                        })?,
+                    );
+                }
+            }
+        }
+        Self::from_datfile_with(
+            &DatFile {
+                attributes,
+                signals,
+            },
+            patterns,
+        )
+    }
+    #[cfg(feature = "parquet")]
+    pub fn write_parquet(&self, path: impl AsRef<Path>) -> Result<()> {
+        use arrow::{
+            array::{ArrayRef, Float64Array},
+            record_batch::RecordBatch,
+        };
+        use parquet::{
+            arrow::ArrowWriter,
+            file::{metadata::KeyValue, properties::WriterProperties},
+        };
+        use std::sync::Arc;
+
+        let mut columns: Vec<(&str, ArrayRef)> = vec![(
+            TIME_PATTERN,
+            Arc::new(Float64Array::from_iter_values(self.times_s())),
+        )];
+        for (label, signal) in self.columns() {
+            columns.push((label, Arc::new(Float64Array::from(signal.to_vec()))));
+        }
+        let batch = RecordBatch::try_from_iter(columns)?;
+        let metadata = self
+            .header_attributes()
+            .into_iter()
+            .map(|(k, v)| KeyValue::new(k, v))
+            .collect();
+        let props = WriterProperties::builder()
+            .set_key_value_metadata(Some(metadata))
+            .build();
+        let file = std::fs::File::create(path)?;
+        let mut writer = ArrowWriter::try_new(file, batch.schema(), Some(props))?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    }
+    #[cfg(feature = "parquet")]
+    pub fn read_parquet(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read_parquet_with(path, &ChannelPatterns::default())
+    }
+    // the inverse of `write_parquet`, read through `from_datfile_with` so the channels are found
+    // the same way as in a .dat file
+    #[cfg(feature = "parquet")]
+    pub fn read_parquet_with(path: impl AsRef<Path>, patterns: &ChannelPatterns) -> Result<Self> {
+        use arrow::array::Float64Array;
+        use parquet::arrow::{
+            arrow_reader::ParquetRecordBatchReaderBuilder, ARROW_SCHEMA_META_KEY,
+        };
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(path)?)?;
+        let attributes = builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .into_iter()
+            .flatten()
+            .filter(|kv| kv.key != ARROW_SCHEMA_META_KEY)
+            .map(|kv| (kv.key.clone(), kv.value.clone().unwrap_or_default()))
+            .collect();
+        let mut signals = BTreeMap::<String, Vec<f64>>::new();
+        for batch in builder.build()? {
+            let batch = batch?;
+            for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+                if field.name() == TIME_PATTERN {
+                    continue;
+                }
+                let values = column
+                    .as_any()
+                    .downcast_ref::<Float64Array>()
+                    .with_context(|| format!("Column `{}` is not a float column", field.name()))?;
+                signals
+                    .entry(field.name().clone())
+                    .or_default()
+                    .extend(values.iter().map(|v| v.unwrap_or(f64::NAN)));
+            }
+        }
+        Self::from_datfile_with(
+            &DatFile {
+                attributes,
+                signals,
+            },
+            patterns,
+        )
+    }
+    pub fn write_as(&self, path: impl AsRef<Path>, format: OutputFormat) -> Result<()> {
+        let path = path.as_ref();
+        #[cfg(feature = "parquet")]
+        if format == OutputFormat::Parquet {
+            return self.write_parquet(path);
+        }
+        let writer = BufWriter::new(std::fs::File::create(path)?);
+        if is_gzip_path(path) {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            self.write_formatted(&mut encoder, format)?;
+            encoder.finish()?.flush()?;
+            Ok(())
+        } else {
+            self.write_formatted(writer, format)
+        }
+    }
+    pub fn write_gz(&self, path: impl AsRef<Path>) -> Result<()> {
+        let writer = BufWriter::new(std::fs::File::create(path)?);
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        self.write_to_writer(&mut encoder, true)?;
+        encoder.finish()?.flush()?;
+        Ok(())
+    }
+    fn write_formatted<W: Write>(&self, writer: W, format: OutputFormat) -> Result<()> {
+        match format {
+            OutputFormat::Dat => self.write_to_writer(writer, true),
+            OutputFormat::Csv => self.write_csv(writer),
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => bail!("Parquet can only be written to a path"),
+        }
+    }
+    pub fn write_data_only<W: Write>(&self, mut writer: W) -> Result<()> {
+        for row in Self::column_rows(&self.columns()) {
+            writeln!(writer, "{}", row.iter().join("\t"))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    pub fn write_append(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if is_gzip_path(path) {
+            bail!("Cannot append to compressed file `{}`", path.display());
+        }
+        let mut file = OpenOptions::new().read(true).append(true).open(path)?;
+        // only the header is checked, however many rows are already there
+        let mut lines = BufReader::new(&file)
+            .lines()
+            .skip_while(|l| l.as_ref().is_ok_and(|l| l.trim() != "[DATA]"));
+        if lines.next().transpose()?.is_none() {
+            bail!("`{}` has no [DATA] block to append to", path.display());
+        }
+        match lines.next().transpose()? {
+            Some(h)
+                if h.split('\t')
+                    .map(str::trim)
+                    .eq(self.columns().iter().map(|c| c.0)) => {}
+            _ => bail!("`{}` has a different channel layout", path.display()),
+        }
+        let mut last = [0];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            writeln!(file)?;
+        }
+        self.write_data_only(BufWriter::new(file))
+    }
+}
+
+// writes the header with the first aquisition, then only the rows of each one after it, flushing
+// every time, so a capture that dies part way leaves a readable file of what came before
+pub struct AquisitionWriter<W: Write> {
+    writer: W,
+    // set by the first append
+    columns: Option<Vec<String>>,
+    rows: usize,
+}
+impl AquisitionWriter<FileSink> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(FileSink::create(path)?))
+    }
+}
+impl<W: Write> AquisitionWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            columns: None,
+            rows: 0,
+        }
+    }
+    pub fn append(&mut self, aq: &Aquisition) -> Result<()> {
+        let columns = aq.columns().iter().map(|c| c.0.to_string()).collect_vec();
+        match &self.columns {
+            None => {
+                aq.write_to_writer(&mut self.writer, true)?;
+                self.columns = Some(columns);
+            }
+            Some(header) if *header == columns => aq.write_data_only(&mut self.writer)?,
+            Some(header) => bail!(
+                "Cannot append channels `{}` under the header `{}`",
+                columns.join(", "),
+                header.join(", ")
+            ),
+        }
+        self.rows += aq.len();
+        Ok(())
+    }
+    // rows written so far
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// a file being written, compressed if its name ends in `.gz`. every flush of a compressed one
+// ends a deflate block, so what was written before a crash can still be decompressed
+pub enum FileSink {
+    Plain(BufWriter<std::fs::File>),
+    Gzip(GzEncoder<BufWriter<std::fs::File>>),
+}
+impl FileSink {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Could not create `{}`", path.display()))?;
+        let writer = BufWriter::new(file);
+        Ok(if is_gzip_path(path) {
+            Self::Gzip(GzEncoder::new(writer, Compression::default()))
+        } else {
+            Self::Plain(writer)
+        })
+    }
+    // writes the gzip trailer, if any, and flushes everything to the file
+    pub fn close(self) -> Result<()> {
+        match self {
+            Self::Plain(mut writer) => writer.flush()?,
+            Self::Gzip(encoder) => encoder.finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
+impl Write for FileSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// sets `attributes` in the header of the .dat file at `path`, compressed or not, over any
+// already there, and keeps only the data rows in `rows`. it is copied through a temporary file
+// beside it, so the data is never all in memory and a failure leaves the original as it was
+pub fn rewrite_header(
+    path: impl AsRef<Path>,
+    attributes: &BTreeMap<String, String>,
+    rows: Range<usize>,
+) -> Result<()> {
+    let path = path.as_ref();
+    let mut lines = open_maybe_gzip(path)?.lines();
+    let mut header = vec![];
+    for line in &mut lines {
+        let line = line?;
+        if line.trim() == "[DATA]" {
+            break;
+        }
+        if let Some((key, value)) = line.split('\t').next_tuple() {
+            header.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let columns = lines
+        .next()
+        .with_context(|| format!("`{}` has no [DATA] block", path.display()))??;
+    for (key, value) in attributes {
+        match header.iter_mut().find(|(k, _)| k == key) {
+            Some(existing) => existing.1 = value.clone(),
+            None => header.push((key.clone(), value.clone())),
+        }
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = std::path::PathBuf::from(tmp);
+    // named like the original so it is compressed the same way
+    let mut sink = match FileSink::create(&tmp)? {
+        FileSink::Plain(writer) if is_gzip_path(path) => {
+            FileSink::Gzip(GzEncoder::new(writer, Compression::default()))
+        }
+        sink => sink,
+    };
+    for (key, value) in &header {
+        writeln!(sink, "{key}\t{value}\t")?;
+    }
+    writeln!(sink)?;
+    writeln!(sink, "[DATA]")?;
+    writeln!(sink, "{columns}")?;
+    for line in lines.skip(rows.start).take(rows.len()) {
+        writeln!(sink, "{}", line?)?;
+    }
+    sink.close()?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("Could not replace `{}`", path.display()))?;
+    Ok(())
+}
+
+// linear interpolation of `signal` at every `step` samples
+fn interpolate(signal: &[f64], target_len: usize, step: f64) -> Vec<f64> {
+    (0..target_len)
+        .map(|i| {
+            let x = i as f64 * step;
+            let j = (x.floor() as usize).min(signal.len() - 2);
+            signal[j] + (signal[j + 1] - signal[j]) * (x - j as f64)
+        })
+        .collect()
+}
+
+// averages each full bin of `factor` samples, dropping any trailing partial bin
+pub fn boxcar_decimate(signal: &[f64], factor: usize) -> Vec<f64> {
+    signal
+        .chunks_exact(factor)
+        .map(|bin| bin.iter().sum::<f64>() / factor as f64)
+        .collect()
+}
+
+// the header and samples of `channel` in a recording, raw or calibrated
+pub(crate) fn find_signal(datfile: &DatFile, channel: Channel) -> Option<(&str, &[f64])> {
+    let names = datfile.signals.keys().map(String::as_str).collect_vec();
+    let patterns = ChannelPatterns::default().calibrated(&datfile.attributes);
+    let name = names[patterns.find(&names, channel).ok()?];
+    Some((name, &datfile.signals[name]))
+}
+
+pub fn clip_report(
+    channel: &str,
+    signal: &[f64],
+    rail: Option<(f64, f64)>,
+    limits: &ChannelLimits,
+    sample_period_ms: f64,
+) -> ClipReport {
+    let (lo, hi) = rail.unwrap_or_else(|| {
+        signal
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            })
+    });
+    // noise keeps a real flat top off a single code, so railless runs must sit within half a step
+    let step = match rail {
+        Some(_) => None,
+        None => match adc_step(signal) {
+            Some(step) => Some(step),
+            None => {
+                return ClipReport {
+                    channel: channel.to_string(),
+                    fraction: 0.,
+                    ranges_s: vec![],
+                }
+            }
+        },
+    };
+    let tol = match step {
+        Some(step) => step / 2.,
+        None => (hi - lo).abs() * limits.tolerance,
+    };
+    let pinned = |v: f64| {
+        if v <= lo + tol {
+            Some(false)
+        } else if v >= hi - tol {
+            Some(true)
+        } else {
+            None
+        }
+    };
+    let mut clipped = 0;
+    let mut ranges_s = vec![];
+    for (level, run) in &signal.iter().enumerate().group_by(|(_, v)| pinned(**v)) {
+        let run = run.collect_vec();
+        let Some(top) = level else {
+            continue;
+        };
+        if run.len() < limits.min_run.max(1) {
+            continue;
+        }
+        let (start, end) = (run[0].0, run[run.len() - 1].0);
+        if let Some(step) = step {
+            let extreme = if top { hi } else { lo };
+            let near = |v: &&f64| (**v - extreme).abs() <= PEAK_SHOULDER_STEPS * step;
+            let shoulder = signal[..start].iter().rev().take_while(near).count()
+                + signal[end + 1..].iter().take_while(near).count();
+            if run.len() < CLIP_DWELL_FACTOR * shoulder.max(1) {
+                continue;
+            }
+        }
+        clipped += run.len();
+        ranges_s.push((
+            start as f64 * sample_period_ms / 1000.,
+            (end + 1) as f64 * sample_period_ms / 1000.,
+        ));
+    }
+    ClipReport {
+        channel: channel.to_string(),
+        fraction: clipped as f64 / signal.len().max(1) as f64,
+        ranges_s,
+    }
+}
+
+// the smallest change between consecutive samples, taken as the ADC resolution
+fn adc_step(signal: &[f64]) -> Option<f64> {
+    signal
+        .windows(2)
+        .map(|w| (w[1] - w[0]).abs())
+        .filter(|d| *d > 0.)
+        .min_by(f64::total_cmp)
+}
+
+pub fn is_gzip_path(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "gz")
+}
+
+// `.csv`, or `.csv.gz` as written by `write_as`
+fn is_csv_path(path: &Path) -> bool {
+    let path = match is_gzip_path(path) {
+        true => Path::new(path.file_stem().unwrap_or_default()),
+        false => path,
+    };
+    path.extension().is_some_and(|e| e == "csv")
+}
+
+// sniffs the gzip magic bytes so compressed files open regardless of their name
+fn open_maybe_gzip(path: &Path) -> Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    if is_gzip_path(path) || reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+// index of the first crossing of the signal's midpoint with positive slope, the
+// hysteresis band keeps noise and the trapezium's flat tops from triggering it
+pub fn rising_crossing(signal: &[f64]) -> Option<usize> {
+    let (lo, hi) = signal
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+            (lo.min(v), hi.max(v))
+        });
+    if hi - lo <= f64::EPSILON * hi.abs().max(lo.abs()).max(1.) {
+        return None;
+    }
+    let mid = (lo + hi) / 2.;
+    let band = (hi - lo) * 0.1;
+    let mut armed = false;
+    for (i, &v) in signal.iter().enumerate() {
+        if v < mid - band {
+            armed = true;
+        } else if armed && v >= mid {
+            return Some(i);
+        }
+    }
+    None
+}
+
+// alternating maxima and minima that the signal moves at least `min_prominence` away from on
+// both sides. the first index of a flat top is reported, and the unconfirmed extremum at
+// either end of the record is left out
+pub fn find_extrema(signal: &[f64], min_prominence: f64) -> Vec<(usize, Extremum)> {
+    if signal.is_empty() || min_prominence.is_nan() || min_prominence <= 0. {
+        return vec![];
+    }
+    let mut extrema = vec![];
+    let (mut max_i, mut min_i) = (0, 0);
+    // unknown until the signal first swings by the prominence
+    let mut rising = None;
+    for (i, &v) in signal.iter().enumerate() {
+        if v > signal[max_i] {
+            max_i = i;
+        }
+        if v < signal[min_i] {
+            min_i = i;
+        }
+        match rising {
+            None if v - signal[min_i] >= min_prominence => {
+                rising = Some(true);
+                max_i = i;
+            }
+            None if signal[max_i] - v >= min_prominence => {
+                rising = Some(false);
+                min_i = i;
+            }
+            Some(true) if signal[max_i] - v >= min_prominence => {
+                extrema.push((max_i, Extremum::Maximum(signal[max_i])));
+                rising = Some(false);
+                min_i = i;
+            }
+            Some(false) if v - signal[min_i] >= min_prominence => {
+                extrema.push((min_i, Extremum::Minimum(signal[min_i])));
+                rising = Some(true);
+                max_i = i;
+            }
+            _ => {}
+        }
+    }
+    extrema
+}
+
+// per-sample mean and sample standard deviation of aquisitions already aligned to each other
+pub fn average_aquisitions(
+    repeats: &[Aquisition],
+) -> Result<(Aquisition, BTreeMap<Channel, Vec<f64>>)> {
+    let Some(first) = repeats.first() else {
+        bail!("No aquisitions to average");
+    };
+    if repeats.iter().any(|aq| aq.len() != first.len()) {
+        bail!("Cannot average aquisitions of different lengths");
+    }
+    let n = repeats.len() as f64;
+    let mut mean = first.clone();
+    let mut std = BTreeMap::new();
+    for channel in Channel::ALL {
+        let (means, stds) = (0..first.channel(channel).len())
+            .map(|i| {
+                let values = repeats.iter().map(|aq| aq.channel(channel)[i]);
+                let m = values.clone().sum::<f64>() / n;
+                let var = values.map(|v| (v - m).powi(2)).sum::<f64>() / (n - 1.).max(1.);
+                (m, var.sqrt())
+            })
+            .unzip();
+        *mean.channel_mut(channel) = means;
+        std.insert(channel, stds);
+    }
+    // the repeats' extra channels aren't averaged
+    mean.extra.clear();
+    Ok((mean, std))
+}
+
+// `Capacitive Probe (m)` becomes `Capacitive Probe Std (m)`
+pub fn std_label(label: &str) -> String {
+    match label.rsplit_once(" (") {
+        Some((name, unit)) => format!("{name} Std ({unit}"),
+        None => format!("{label} Std"),
+    }
+}
+
+// phase is referenced to the start of `signal` so bins from different ranges line up
+fn single_bin_dft(signal: &[f64], range: Range<usize>, omega: f64) -> Complex<f64> {
+    range
+        .map(|i| signal[i] * Complex::from_polar(1., -omega * i as f64))
+        .sum()
+}
+
+// lag (in samples) by which `reference` must be delayed to best match `signal`,
+// from the peak of their circular cross-correlation
+fn best_lag(reference: &[f64], signal: &[f64]) -> usize {
+    let n = reference.len().min(signal.len());
+    if n == 0 {
+        return 0;
+    }
+    let to_complex = |x: &[f64]| -> Vec<Complex<f64>> {
+        let mean = x[..n].iter().sum::<f64>() / n as f64;
+        x[..n].iter().map(|v| Complex::new(v - mean, 0.)).collect()
+    };
+    let mut planner = FftPlanner::new();
+    let forward = planner.plan_fft_forward(n);
+    let mut r = to_complex(reference);
+    let mut s = to_complex(signal);
+    forward.process(&mut r);
+    forward.process(&mut s);
+    let mut corr: Vec<_> = s.iter().zip(&r).map(|(s, r)| s * r.conj()).collect();
+    planner.plan_fft_inverse(n).process(&mut corr);
+    corr.iter()
+        .position_max_by(|a, b| a.re.total_cmp(&b.re))
+        .unwrap_or(0)
+}
+
+// f64's Display is the shortest string that parses back to the same value,
+// so attributes never lose precision (e.g. a 0.25 s period)
+pub fn format_attribute(value: f64) -> String {
+    format!("{value}")
+}
+
+// accepts both integer ("1") and float ("0.25") spellings, and the "0,25" a German locale writes.
+// Display formats with a `.` whatever the locale, so our own files never need the fallback
+pub fn parse_attribute(value: &str) -> Result<f64> {
+    let value = value.trim();
+    parse_decimal(value).with_context(|| format!("`{value}` is not a number"))
+}
+
+// a comma is only read as the decimal separator when it can't be a thousands separator next to
+// a `.`, i.e. when there is exactly one and no dot
+pub(crate) fn parse_decimal(value: &str) -> Option<f64> {
+    if let Ok(v) = value.parse::<f64>() {
+        return Some(v);
+    }
+    if value.matches(',').count() == 1 && !value.contains('.') {
+        return value.replace(',', ".").parse().ok();
+    }
+    None
+}
+
+// typed access to header attributes, so the keys the driver writes are the keys read back.
+// wraps `&attributes` to read them and `&mut attributes` to also write them
+#[derive(Debug, Clone, Copy)]
+pub struct AcqAttributes<A>(pub A);
+impl<A: Borrow<BTreeMap<String, String>>> AcqAttributes<A> {
+    pub fn get_str(&self, key: &str) -> Result<&str> {
+        self.0
+            .borrow()
+            .get(key)
+            .map(|v| v.trim())
+            .with_context(|| format!("Missing the `{key}` attribute"))
+    }
+    pub fn get_f64(&self, key: &str) -> Result<f64> {
+        let value = self.get_str(key)?;
+        parse_attribute(value).with_context(|| format!("Attribute `{key}` is not a number"))
+    }
+    // a missing key is `None`, but one that is present must be a number
+    pub fn get_f64_opt(&self, key: &str) -> Result<Option<f64>> {
+        match self.0.borrow().contains_key(key) {
+            true => self.get_f64(key).map(Some),
+            false => Ok(None),
+        }
+    }
+    fn get_seconds(&self, key: &str) -> Result<Duration> {
+        seconds_to_duration(self.get_f64(key)?)
+            .with_context(|| format!("Attribute `{key}` is not a valid duration"))
+    }
+    pub fn pkpk(&self) -> Result<f64> {
+        self.get_f64(PKPK_KEY)
+    }
+    pub fn period(&self) -> Result<Duration> {
+        self.get_seconds(PERIOD_KEY)
+    }
+    pub fn sample_period_ms(&self) -> Result<f64> {
+        self.get_f64(SP_PATTERN)
+    }
+    pub fn sample_period(&self) -> Result<Duration> {
+        seconds_to_duration(self.sample_period_ms()? / 1000.)
+            .with_context(|| format!("Attribute `{SP_PATTERN}` is not a valid duration"))
+    }
+    pub fn gain(&self) -> Result<f64> {
+        self.get_f64(GAIN_KEY)
+    }
+    // settings missing from the header are left at their defaults
+    pub fn settings(&self) -> Result<WavegenSettings> {
+        let defaults = WavegenSettings::default();
+        let period = match self.0.borrow().contains_key(PERIOD_KEY) {
+            true => self.period()?,
+            false => defaults.period,
+        };
+        Ok(WavegenSettings {
+            pkpk: self.get_f64_opt(PKPK_KEY)?.unwrap_or(defaults.pkpk),
+            period,
+            symmetry_p: self
+                .get_f64_opt(SYMMETRY_KEY)?
+                .unwrap_or(defaults.symmetry_p),
+            offset: self.get_f64_opt(OFFSET_KEY)?.unwrap_or(defaults.offset),
+        })
+    }
+}
+impl<A: BorrowMut<BTreeMap<String, String>>> AcqAttributes<A> {
+    pub fn set_str(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.0.borrow_mut().insert(key.into(), value.into());
+    }
+    pub fn set_f64(&mut self, key: impl Into<String>, value: f64) {
+        self.set_str(key, format_attribute(value));
+    }
+    pub fn set_sample_period_ms(&mut self, sample_period_ms: f64) {
+        self.set_f64(SP_PATTERN, sample_period_ms);
+    }
+    pub fn set_gain(&mut self, gain: f64) {
+        self.set_f64(GAIN_KEY, gain);
+    }
+    pub fn set_settings(&mut self, settings: WavegenSettings) {
+        for (key, value) in settings.attributes() {
+            self.set_str(key, value);
+        }
+    }
+}
+
+// removes the sample period and settings from `metadata`, leaving the free-form attributes
+fn take_settings(
+    metadata: &mut BTreeMap<String, String>,
+    patterns: &ChannelPatterns,
+) -> Result<(f64, WavegenSettings)> {
+    let attributes = AcqAttributes(&*metadata);
+    let sample_period_ms = attributes.get_f64(&patterns.sample_period)?;
+    let settings = attributes.settings()?;
+    metadata.remove(&patterns.sample_period);
+    for key in [PKPK_KEY, PERIOD_KEY, SYMMETRY_KEY, OFFSET_KEY] {
+        metadata.remove(key);
+    }
+    Ok((sample_period_ms, settings))
+}
+
+fn seconds_to_duration(seconds: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(seconds)
+        .with_context(|| format!("`{seconds}` is not a valid duration"))
+}
+
+// swaps the `(unit)` suffix of a header label, or appends one
+fn with_unit(label: &str, unit: &str) -> String {
+    match label.rsplit_once('(') {
+        Some((name, _)) if label.ends_with(')') => format!("{}({unit})", name),
+        _ => format!("{label} ({unit})"),
+    }
+}
+
+fn find_column(headers: &[&str], name: &str) -> Result<usize> {
+    let name = name.to_lowercase();
+    headers
+        .iter()
+        .position(|h| h.to_lowercase().contains(&name))
+        .with_context(|| {
+            format!(
+                "File has no `{name}` channel, found: {}",
+                headers.iter().map(|h| format!("`{h}`")).join(", ")
+            )
+        })
+}
+
+// least squares polynomial fit of `y` against `x`, evaluated at each `x`
+fn polyfit_eval(x: &[f64], y: &[f64], degree: usize) -> Result<Vec<f64>> {
+    if x.len() <= degree {
+        bail!(
+            "Cannot fit a degree {degree} polynomial to {} samples",
+            x.len()
+        );
+    }
+    // rescale x onto [-1, 1] to keep the normal equations well conditioned
+    let (lo, hi) = x
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+            (lo.min(v), hi.max(v))
+        });
+    let span = if hi > lo { (hi - lo) / 2. } else { 1. };
+    let xs = x.iter().map(|v| (v - lo) / span - 1.).collect_vec();
+    let n = degree + 1;
+    let mut a = vec![vec![0.; n + 1]; n];
+    for (&xi, &yi) in xs.iter().zip(y) {
+        let powers = (0..n).map(|p| xi.powi(p as i32)).collect_vec();
+        for r in 0..n {
+            for c in 0..n {
+                a[r][c] += powers[r] * powers[c];
+            }
+            a[r][n] += powers[r] * yi;
+        }
+    }
+    // gaussian elimination with partial pivoting
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+            .unwrap();
+        if a[pivot][col].abs() < f64::EPSILON {
+            bail!("Polynomial fit is singular");
+        }
+        a.swap(col, pivot);
+        let pivot_row = a[col].clone();
+        for (row, values) in a.iter_mut().enumerate() {
+            if row != col {
+                let factor = values[col] / pivot_row[col];
+                for (v, p) in values[col..].iter_mut().zip(&pivot_row[col..]) {
+                    *v -= factor * p;
+                }
+            }
+        }
+    }
+    let coeffs = (0..n).map(|i| a[i][n] / a[i][i]).collect_vec();
+    Ok(xs
+        .iter()
+        .map(|xi| coeffs.iter().rev().fold(0., |acc, c| acc * xi + c))
+        .collect())
+}
+
+// centered moving average, shrinking the window at the edges of the record
+fn moving_average(y: &[f64], window: usize) -> Result<Vec<f64>> {
+    if window == 0 {
+        bail!("Moving baseline window must be at least one sample");
+    }
+    let mut prefix = vec![0.; y.len() + 1];
+    for (i, v) in y.iter().enumerate() {
+        prefix[i + 1] = prefix[i] + v;
+    }
+    let half = window / 2;
+    Ok((0..y.len())
+        .map(|i| {
+            let start = i.saturating_sub(half);
+            let end = (i + window - half).min(y.len());
+            (prefix[end] - prefix[start]) / (end - start) as f64
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    // a minimal tab separated export with the given channel header and one row per sample
+    fn text_file<const N: usize>(header: &str, rows: &[[f64; N]]) -> String {
+        let mut text = String::new();
+        for (key, value) in [
+            (SP_PATTERN, "1"),
+            (PKPK_KEY, "200"),
+            (PERIOD_KEY, "2"),
+            (SYMMETRY_KEY, "100"),
+            (OFFSET_KEY, "0"),
+        ] {
+            text += &format!("{key}\t{value}\t\n");
+        }
+        text += &format!("\n[DATA]\n{header}\n");
+        for row in rows {
+            text += &format!("{}\n", row.iter().join("\t"));
+        }
+        text
+    }
+
+    fn settings() -> WavegenSettings {
+        WavegenSettings {
+            pkpk: 200.,
+            period: Duration::from_secs(2),
+            symmetry_p: 100.,
+            offset: 0.,
+        }
+    }
+
+    // `n` samples of a ramp on each channel, offset so the channels differ
+    fn ramp(n: usize, from: f64) -> Aquisition {
+        let signal = |k: f64| (0..n).map(|i| from + i as f64 + k).collect_vec();
+        Aquisition::new(signal(0.), signal(0.5), signal(0.25), settings(), 1.).unwrap()
+    }
+
+    #[test]
+    fn data_only_rows_concatenate_under_one_header() {
+        let (first, second) = (ramp(5, 0.), ramp(3, 5.));
+        let mut bytes = vec![];
+        first.write_to_writer(&mut bytes, true).unwrap();
+        second.write_data_only(&mut bytes).unwrap();
+        let combined = Aquisition::read_from_reader(&bytes[..]).unwrap();
+        assert_eq!(combined.probe, ramp(8, 0.).probe);
+        assert_eq!(combined.voltage, ramp(8, 0.).voltage);
+        assert_eq!(combined.wavegen_settings, settings());
+    }
+
+    #[test]
+    fn write_append_round_trips() {
+        let dir = TempDir::new("write_append");
+        let path = dir.join("append.dat");
+        ramp(5, 0.).write_as(&path, OutputFormat::Dat).unwrap();
+        ramp(3, 5.).write_append(&path).unwrap();
+        let combined = Aquisition::read_from_file(&path).unwrap();
+        assert_eq!(combined, ramp(8, 0.));
+    }
+
+    #[test]
+    fn write_append_rejects_another_layout() {
+        let dir = TempDir::new("write_append_layout");
+        let path = dir.join("append.dat");
+        ramp(5, 0.).write_as(&path, OutputFormat::Dat).unwrap();
+        let mut other = ramp(3, 5.);
+        other.labels.insert(Channel::Probe, "Z (m)".into());
+        assert!(other.write_append(&path).is_err());
+        assert!(ramp(3, 5.).write_append(dir.join("missing.dat")).is_err());
+    }
+
+    #[test]
+    fn streamed_gzip_file_reads_back() {
+        let dir = TempDir::new("streamed_gzip");
+        let path = dir.join("streamed.dat.gz");
+        let mut writer = AquisitionWriter::create(&path).unwrap();
+        writer.append(&ramp(5, 0.)).unwrap();
+        writer.append(&ramp(3, 5.)).unwrap();
+        assert_eq!(writer.rows(), 8);
+        writer.finish().unwrap().close().unwrap();
+        assert_eq!(Aquisition::read_from_file(&path).unwrap(), ramp(8, 0.));
+    }
+
+    #[test]
+    fn rewrite_header_sets_attributes_and_trims_rows() {
+        let dir = TempDir::new("rewrite_header");
+        for name in ["rewritten.dat", "rewritten.dat.gz"] {
+            let path = dir.join(name);
+            let mut writer = AquisitionWriter::create(&path).unwrap();
+            writer.append(&ramp(8, 0.)).unwrap();
+            writer.finish().unwrap().close().unwrap();
+            let attributes = BTreeMap::from([
+                (PKPK_KEY.to_string(), "100".to_string()),
+                ("windows".to_string(), "2".to_string()),
+            ]);
+            rewrite_header(&path, &attributes, 2..7).unwrap();
+            let aq = Aquisition::read_from_file(&path).unwrap();
+            assert_eq!(aq.probe, [2., 3., 4., 5., 6.], "{name}");
+            assert_eq!(aq.wavegen_settings.pkpk, 100., "{name}");
+            assert_eq!(aq.get_meta("windows"), Some("2"), "{name}");
+        }
+    }
+
+    #[test]
+    fn csv_round_trips() {
+        let mut aq = ramp(6, 0.);
+        aq.metadata.insert("note".into(), "a=b".into());
+        aq.extra.insert("Bias (V)".into(), vec![0.5; 6]);
+        let mut bytes = vec![];
+        aq.write_csv(&mut bytes).unwrap();
+        assert_eq!(Aquisition::read_csv(&bytes[..]).unwrap(), aq);
+        let dir = TempDir::new("csv_round_trip");
+        for name in ["round_trip.csv", "round_trip.csv.gz"] {
+            let path = dir.join(name);
+            aq.write_as(&path, OutputFormat::Csv).unwrap();
+            assert_eq!(Aquisition::read_from_file(&path).unwrap(), aq, "{name}");
+        }
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_schema_has_time_and_float_channels() {
+        use arrow::datatypes::DataType;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let aq = ramp(6, 0.);
+        let dir = TempDir::new("parquet_schema");
+        let path = dir.join("schema.parquet");
+        aq.write_as(&path, OutputFormat::Parquet).unwrap();
+        let builder =
+            ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap()).unwrap();
+        let fields = builder.schema().fields();
+        let names = fields.iter().map(|f| f.name().as_str()).collect_vec();
+        let expected = [TIME_PATTERN]
+            .into_iter()
+            .chain(aq.columns().iter().map(|c| c.0))
+            .collect_vec();
+        assert_eq!(names, expected);
+        assert!(fields.iter().all(|f| *f.data_type() == DataType::Float64));
+        let metadata = builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap();
+        assert!(metadata
+            .iter()
+            .any(|kv| kv.key == SP_PATTERN && kv.value.as_deref() == Some("1")));
+        assert_eq!(Aquisition::read_from_file(&path).unwrap(), aq);
+    }
+
+    // a sine of `amplitude` sampled `n` times per period, clamped to ±1 and quantized to 16 bits
+    fn quantized_sine(amplitude: f64, n: usize) -> Vec<f64> {
+        let step = 2f64.powi(-15);
+        (0..n)
+            .map(|i| amplitude * (std::f64::consts::TAU * i as f64 / n as f64).sin())
+            .map(|v| (v.clamp(-1., 1.) / step).round() * step)
+            .collect()
+    }
+
+    #[test]
+    fn oversampled_sines_are_not_clipped() {
+        let report = clip_report(
+            "sine",
+            &quantized_sine(1., 20_000),
+            None,
+            &ChannelLimits::default(),
+            1.,
+        );
+        assert_eq!(report.fraction, 0.);
+        assert!(report.ranges_s.is_empty());
+    }
+
+    #[test]
+    fn saturated_sines_are_clipped_with_or_without_rails() {
+        let signal = quantized_sine(1.5, 20_000);
+        for rail in [None, Some((-1., 1.))] {
+            let report = clip_report("sine", &signal, rail, &ChannelLimits::default(), 1.);
+            assert!(
+                (0.4..0.6).contains(&report.fraction),
+                "{rail:?}: {}",
+                report.fraction
+            );
+            assert_eq!(report.ranges_s.len(), 2, "{rail:?}");
+            let (start, end) = report.ranges_s[0];
+            assert!(start < 5. && end > 5., "{rail:?}: {start}..{end}");
+        }
+    }
+
+    #[test]
+    fn loosely_matched_channels_are_checked_for_clipping() {
+        let n = 20_000;
+        let mut aq = Aquisition::new(
+            quantized_sine(1., n),
+            quantized_sine(1.5, n),
+            quantized_sine(1., n),
+            settings(),
+            1.,
+        )
+        .unwrap();
+        aq.labels
+            .insert(Channel::Current, "Current Input (A)".into());
+        let reports = aq.detect_clipping(&ChannelLimits::default());
+        let channels = reports.iter().map(|r| r.channel.as_str()).collect_vec();
+        assert_eq!(channels, [PROBE_PATTERN, "Current Input (A)"]);
+        assert_eq!(reports[0].fraction, 0.);
+        assert!(reports[1].fraction > 0.4, "{}", reports[1].fraction);
+
+        let mut datfile = aq.to_datfile();
+        let current = datfile.signals.remove("Current Input (A)").unwrap();
+        datfile
+            .signals
+            .insert("current input (A)".into(), current.clone());
+        assert_eq!(
+            find_signal(&datfile, Channel::Current),
+            Some(("current input (A)", &current[..]))
+        );
+    }
+
+    #[test]
+    fn noisy_flat_tops_are_not_clipped() {
+        let step = 2f64.powi(-15);
+        // a trapezium whose flat tops wander over a few ADC codes
+        let signal = (0..4000)
+            .map(|i| {
+                let level = match i % 2000 {
+                    t if t < 500 => t as f64 / 500.,
+                    t if t < 1000 => 1.,
+                    t if t < 1500 => 1. - (t - 1000) as f64 / 250.,
+                    _ => -1.,
+                };
+                level + ((i * 7919) % 7) as f64 * step
+            })
+            .collect_vec();
+        let report = clip_report("trapezium", &signal, None, &ChannelLimits::default(), 1.);
+        assert_eq!(report.fraction, 0.);
+    }
+
+    // `periods` of a unit sine on the probe, 100 samples each, with `drift(t)` added
+    fn drifting_sine(periods: usize, drift: impl Fn(f64) -> f64) -> Aquisition {
+        let n = periods * 100;
+        let probe = (0..n)
+            .map(|i| {
+                let t = i as f64 / 1000.;
+                (std::f64::consts::TAU * i as f64 / 100.).sin() + drift(t)
+            })
+            .collect_vec();
+        Aquisition::new(probe, vec![0.; n], vec![0.; n], settings(), 1.).unwrap()
+    }
+
+    fn range(signal: &[f64]) -> f64 {
+        let (min, max) = signal.iter().copied().minmax().into_option().unwrap();
+        max - min
+    }
+
+    #[test]
+    fn detrend_removes_drift_but_keeps_the_sine() {
+        let cases = [
+            (DetrendMode::Linear, drifting_sine(50, |t| 3. + t)),
+            (
+                DetrendMode::Polynomial(2),
+                drifting_sine(50, |t| 1. - 2. * t + 0.4 * t * t),
+            ),
+            (
+                DetrendMode::MovingBaseline { window: 100 },
+                drifting_sine(50, |t| 3. + t),
+            ),
+        ];
+        for (mode, aq) in cases {
+            let detrended = aq.detrend(Channel::Probe, mode).unwrap();
+            // the edges of a moving baseline only see half a window
+            let middle = &detrended.probe[100..4900];
+            assert!(middle.iter().all(|v| v.is_finite()), "{mode}");
+            assert!(
+                (range(middle) - 2.).abs() < 0.05,
+                "{mode}: {}",
+                range(middle)
+            );
+            assert!(middle.iter().sum::<f64>().abs() / 4800. < 0.02, "{mode}");
+            assert!(detrended.probe.iter().all(|v| v.is_finite()), "{mode}");
+            assert_eq!(detrended.current, aq.current, "{mode}");
+            assert_eq!(
+                detrended.get_meta("Capacitive Probe (m) detrend"),
+                Some(mode.to_string().as_str())
+            );
+        }
+        assert!(drifting_sine(1, |_| 0.)
+            .detrend(Channel::Probe, DetrendMode::MovingBaseline { window: 0 })
+            .is_err());
+    }
+
+    #[test]
+    fn decimate_averages_away_what_striding_would_alias() {
+        let tau = std::f64::consts::TAU;
+        // a slow sine, plus a tone at the decimated sample rate that striding folds onto DC
+        let slow = |i: usize| (tau * i as f64 / 1000.).sin();
+        let probe = (0..2005)
+            .map(|i| slow(i) + 0.5 * (tau * i as f64 / 10. + 0.7).sin())
+            .collect_vec();
+        let n = probe.len();
+        let aq = Aquisition::new(probe.clone(), vec![1.; n], vec![0.; n], settings(), 1.).unwrap();
+        let decimated = aq.decimate(10).unwrap();
+        assert_eq!(decimated.len(), 200);
+        assert_eq!(decimated.sample_period_ms, 10.);
+        assert!(decimated.current.iter().all(|v| (v - 1.).abs() < 1e-12));
+        // each bin's average of the slow sine, which the tone must not disturb
+        let expected = (0..200)
+            .map(|bin| (0..10).map(|i| slow(bin * 10 + i)).sum::<f64>() / 10.)
+            .collect_vec();
+        let error = |signal: &[f64]| {
+            signal
+                .iter()
+                .zip(&expected)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0., f64::max)
+        };
+        let strided = probe.iter().step_by(10).take(200).copied().collect_vec();
+        assert!(error(&decimated.probe) < 1e-9);
+        assert!(error(&strided) > 0.25);
+        assert_eq!(aq.decimate(1).unwrap(), aq);
+        assert!(aq.decimate(0).is_err());
+    }
+
+    #[test]
+    fn gzip_round_trips_byte_for_byte() {
+        let aq = ramp(50, 0.);
+        let mut plain = vec![];
+        aq.write_to_writer(&mut plain, true).unwrap();
+        let dir = TempDir::new("gzip_round_trip");
+        let path = dir.join("compressed.dat.gz");
+        aq.write_gz(&path).unwrap();
+        let compressed = std::fs::read(&path).unwrap();
+        assert!(compressed.starts_with(&[0x1f, 0x8b]));
+        let mut decompressed = vec![];
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, plain);
+        assert_eq!(Aquisition::read_from_file(&path).unwrap(), aq);
+        // found by its magic bytes even without the extension
+        let misnamed = dir.join("compressed_misnamed.dat");
+        std::fs::write(&misnamed, &compressed).unwrap();
+        assert_eq!(Aquisition::read_from_file(&misnamed).unwrap(), aq);
+    }
+
+    // a sine `u` sampled every ms through a first-order low-pass, y' = (u - y) / tau, integrated
+    // with RK4 between samples. the first second is dropped so the start-up transient has died
+    fn low_pass(frequency_hz: f64, tau_s: f64, n: usize) -> Aquisition {
+        let (dt, settle) = (1e-3, 1000);
+        let u = |t: f64| (std::f64::consts::TAU * frequency_hz * t).sin();
+        let dy = |t: f64, y: f64| (u(t) - y) / tau_s;
+        let mut y = 0.;
+        let mut probe = vec![];
+        for i in 0..settle + n {
+            let t = i as f64 * dt;
+            if i >= settle {
+                probe.push(y);
+            }
+            let k1 = dy(t, y);
+            let k2 = dy(t + dt / 2., y + k1 * dt / 2.);
+            let k3 = dy(t + dt / 2., y + k2 * dt / 2.);
+            let k4 = dy(t + dt, y + k3 * dt);
+            y += (k1 + 2. * k2 + 2. * k3 + k4) * dt / 6.;
+        }
+        let voltage = (settle..settle + n).map(|i| u(i as f64 * dt)).collect_vec();
+        Aquisition::new(probe, vec![0.; n], voltage, settings(), 1.).unwrap()
+    }
+
+    #[test]
+    fn frequency_response_recovers_gain_and_phase() {
+        let tau_s = 0.05;
+        for frequency_hz in [1., 2., 5.] {
+            let aq = low_pass(frequency_hz, tau_s, 3000);
+            let response = aq.frequency_response(frequency_hz).unwrap();
+            let wt = std::f64::consts::TAU * frequency_hz * tau_s;
+            let (magnitude, phase_deg) = (1. / (1. + wt * wt).sqrt(), -wt.atan().to_degrees());
+            assert!(
+                (response.magnitude - magnitude).abs() < 1e-4,
+                "{response:?}"
+            );
+            assert!(
+                (response.phase_deg - phase_deg).abs() < 1e-2,
+                "{response:?}"
+            );
+            assert!((response.coherence - 1.).abs() < 1e-9, "{response:?}");
+        }
+        let aq = low_pass(2., tau_s, 3000);
+        assert!(aq.frequency_response(0.).is_err());
+        // longer than the record
+        assert!(aq.frequency_response(0.1).is_err());
+    }
+
+    #[test]
+    fn calibration_relabels_scales_and_applies_once() {
+        let calibration = ChannelCalibration {
+            channels: BTreeMap::from([
+                (
+                    Channel::Voltage,
+                    Calibration {
+                        multiplier: 100.,
+                        offset: 0.,
+                        label: "Drive Voltage (V)".into(),
+                    },
+                ),
+                (
+                    Channel::Probe,
+                    Calibration {
+                        multiplier: 2e3,
+                        offset: -1.,
+                        label: "Displacement (nm)".into(),
+                    },
+                ),
+            ]),
+        };
+        let mut datfile = ramp(4, 0.).to_datfile();
+        calibration.apply(&mut datfile).unwrap();
+        assert_eq!(
+            datfile.signals["Drive Voltage (V)"],
+            [25., 125., 225., 325.]
+        );
+        assert_eq!(
+            datfile.signals["Displacement (nm)"],
+            [-1., 1999., 3999., 5999.]
+        );
+        assert!(!datfile.signals.contains_key(VOLTAGE_PATTERN));
+        assert_eq!(
+            datfile.attributes["calibration_voltage_raw_label"],
+            VOLTAGE_PATTERN
+        );
+        assert_eq!(datfile.attributes["calibration_voltage_multiplier"], "100");
+        let once = datfile.clone();
+        calibration.apply(&mut datfile).unwrap();
+        assert_eq!(datfile, once);
+        let aq = Aquisition::from_datfile(&datfile).unwrap();
+        assert_eq!(aq.voltage, [25., 125., 225., 325.]);
+        assert_eq!(aq.label(Channel::Probe), "Displacement (nm)");
+        assert_eq!(aq.current, ramp(4, 0.).current);
+    }
+
+    #[test]
+    fn attributes_round_trip_and_name_bad_values() {
+        let settings = WavegenSettings {
+            pkpk: 1.5,
+            period: Duration::from_millis(250),
+            symmetry_p: 40.,
+            offset: -0.25,
+        };
+        let mut map = BTreeMap::new();
+        let mut attributes = AcqAttributes(&mut map);
+        attributes.set_settings(settings);
+        attributes.set_sample_period_ms(0.1);
+        attributes.set_gain(38.5);
+        let attributes = AcqAttributes(&map);
+        assert_eq!(attributes.settings().unwrap(), settings);
+        assert_eq!(
+            attributes.sample_period().unwrap(),
+            Duration::from_micros(100)
+        );
+        assert_eq!(attributes.gain().unwrap(), 38.5);
+        assert_eq!(attributes.get_f64_opt("missing").unwrap(), None);
+
+        map.insert(PKPK_KEY.into(), "lots".into());
+        let err = format!("{:#}", AcqAttributes(&map).settings().unwrap_err());
+        assert!(err.contains(PKPK_KEY) && err.contains("`lots`"), "{err}");
+        let err = format!("{:#}", AcqAttributes(&map).get_str("missing").unwrap_err());
+        assert!(err.contains("`missing`"), "{err}");
+
+        // a quarter second period survives a file, and whole seconds written without a point
+        // still read
+        let dir = TempDir::new("period_round_trip");
+        let path = dir.join("period.dat");
+        let aq = Aquisition::new(vec![1., 2.], vec![0.; 2], vec![3., 4.], settings, 1.).unwrap();
+        aq.write_as(&path, OutputFormat::Dat).unwrap();
+        let read = Aquisition::read_from_file(&path).unwrap();
+        assert_eq!(read.wavegen_settings.period, Duration::from_millis(250));
+        assert_eq!(read.wavegen_settings, settings);
+        let text = std::fs::read_to_string(&path).unwrap();
+        let line = text
+            .lines()
+            .find(|line| line.starts_with(PERIOD_KEY))
+            .unwrap();
+        std::fs::write(&path, text.replace(line, &format!("{PERIOD_KEY}\t3\t"))).unwrap();
+        let read = Aquisition::read_from_file(&path).unwrap();
+        assert_eq!(read.wavegen_settings.period, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn comma_decimals_are_read_where_unambiguous() {
+        assert_eq!(parse_attribute("0,5").unwrap(), 0.5);
+        assert_eq!(parse_attribute(" 12 ").unwrap(), 12.);
+        assert!(parse_attribute("1,234.5").is_err());
+        assert!(parse_attribute("1,2,3").is_err());
+
+        let mut bytes = vec![];
+        ramp(3, 0.).write_to_writer(&mut bytes, true).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+        let text = text
+            .replace(
+                &format!("{SP_PATTERN}\t1\t"),
+                &format!("{SP_PATTERN}\t0,25\t"),
+            )
+            .replace(
+                &format!("{PERIOD_KEY}\t2\t"),
+                &format!("{PERIOD_KEY}\t0,5\t"),
+            );
+        assert!(text.contains("0,25") && text.contains("0,5\t"), "{text}");
+        let aq = Aquisition::read_from_reader(text.as_bytes()).unwrap();
+        assert_eq!(aq.sample_period_ms, 0.25);
+        assert_eq!(aq.wavegen_settings.period, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn settling_stops_early_on_an_exponential_decay() {
+        // 2 s of a decay with a 100 ms time constant, sampled every ms
+        let decay = (0..2000).map(|i| (-(i as f64) / 100.).exp()).collect_vec();
+        let criterion = |test| SettleCriterion {
+            channel: Channel::Probe,
+            window: Duration::from_millis(100),
+            test,
+        };
+        // the slope at the middle of the window drops below 0.1/s at 100 ms * ln(100)
+        let slope = criterion(SettleTest::SlopeBelow(0.1));
+        let at = slope.settled_at(&decay, 1., 0).unwrap();
+        assert!((505..=525).contains(&at), "{at}");
+        assert!(!slope.passes(&decay[at - 110..at - 10], 1.));
+
+        let std = criterion(SettleTest::StdBelow(1e-3));
+        let at = std.settled_at(&decay, 1., 0).unwrap();
+        assert!(at < decay.len(), "{at}");
+        assert!(std.passes(&decay[at - 100..at], 1.));
+        assert!(!std.passes(&decay[at - 110..at - 10], 1.));
+
+        assert_eq!(slope.settled_at(&decay, 1., 800), Some(800));
+        assert_eq!(slope.settled_at(&decay[..300], 1., 0), None);
+    }
+
+    #[test]
+    fn headers_match_loosely() {
+        let text = text_file(
+            " capacitive probe [m]\tCURRENT (A) \tVoltage (V)",
+            &[[1., 2., 3.], [4., 5., 6.]],
+        );
+        let aq = Aquisition::read_from_reader(text.as_bytes()).unwrap();
+        assert_eq!(aq.probe, [1., 4.]);
+        assert_eq!(aq.current, [2., 5.]);
+        assert_eq!(aq.voltage, [3., 6.]);
+    }
+
+    #[test]
+    fn extra_and_reordered_channels_are_carried_along() {
+        let text = text_file(
+            "Bias (V)\tVoltage Monitor (V)\tCurrent (A)\tCapacitive Probe (m)",
+            &[[9., 3., 2., 1.], [8., 6., 5., 4.]],
+        );
+        let aq = Aquisition::read_from_reader(text.as_bytes()).unwrap();
+        assert_eq!(aq.probe, [1., 4.]);
+        assert_eq!(aq.current, [2., 5.]);
+        assert_eq!(aq.voltage, [3., 6.]);
+        assert_eq!(
+            aq.extra,
+            BTreeMap::from([("Bias (V)".into(), vec![9., 8.])])
+        );
+        let mut bytes = vec![];
+        aq.write_to_writer(&mut bytes, true).unwrap();
+        assert_eq!(Aquisition::read_from_reader(&bytes[..]).unwrap(), aq);
+    }
+
+    #[test]
+    fn missing_channel_lists_the_headers_found() {
+        let text = text_file(
+            "Capacitive Probe (m)\tCurrent (A)\tBias (V)",
+            &[[1., 2., 3.]],
+        );
+        let err = Aquisition::read_from_reader(text.as_bytes())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("voltage monitor"), "{err}");
+        assert!(err.contains("`Bias (V)`"), "{err}");
+    }
+}
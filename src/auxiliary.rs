@@ -0,0 +1,82 @@
+use std::{collections::BTreeMap, rc::Rc};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+
+use crate::power_automate::PowerAutomate;
+
+// readings from instruments other than the nanonis, such as a thermometer, keyed by name.
+// boxed so a sweep can hold any logger as a `dyn AuxLogger`
+pub trait AuxLogger {
+    fn sample(&self) -> LocalBoxFuture<'_, Result<BTreeMap<String, f64>>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuxStage {
+    Before,
+    During,
+    After,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuxReading {
+    pub time: DateTime<Local>,
+    pub stage: AuxStage,
+    pub values: BTreeMap<String, f64>,
+}
+
+// a value the flow scrapes from a field of another window
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxChannel {
+    pub name: String,
+    pub window: String,
+    pub field: String,
+}
+
+pub struct FlowAuxLogger {
+    pa: Rc<PowerAutomate>,
+    channels: Vec<AuxChannel>,
+}
+impl FlowAuxLogger {
+    pub(crate) fn new(pa: Rc<PowerAutomate>, channels: Vec<AuxChannel>) -> Self {
+        Self { pa, channels }
+    }
+}
+impl AuxLogger for FlowAuxLogger {
+    fn sample(&self) -> LocalBoxFuture<'_, Result<BTreeMap<String, f64>>> {
+        async move {
+            let mut values = BTreeMap::new();
+            for channel in &self.channels {
+                let value = self
+                    .pa
+                    .read_window_value(&channel.window, &channel.field)
+                    .await
+                    .with_context(|| format!("Could not read `{}`", channel.name))?;
+                values.insert(channel.name.clone(), value);
+            }
+            Ok(values)
+        }
+        .boxed_local()
+    }
+}
+
+// returns `values` on every sample, or fails every time when `fail` is set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MockAuxLogger {
+    pub values: BTreeMap<String, f64>,
+    pub fail: bool,
+}
+impl AuxLogger for MockAuxLogger {
+    fn sample(&self) -> LocalBoxFuture<'_, Result<BTreeMap<String, f64>>> {
+        async move {
+            if self.fail {
+                bail!("Mock auxiliary channel failure");
+            }
+            Ok(self.values.clone())
+        }
+        .boxed_local()
+    }
+}
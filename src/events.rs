@@ -0,0 +1,238 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliary::AuxReading,
+    power_automate::{AcquisitionInfo, CommandStats, WavegenSettings},
+};
+
+pub const EVENTS_FILE: &str = "events.jsonl";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PointStarted {
+        name: String,
+        settings: WavegenSettings,
+    },
+    PointFinished {
+        name: String,
+        report: AcquisitionInfo,
+    },
+    PointSkipped {
+        name: String,
+    },
+    PointFailed {
+        name: String,
+        error: String,
+    },
+    PointDeferred {
+        name: String,
+    },
+    HookFailed {
+        name: String,
+        hook: String,
+        error: String,
+    },
+    AuxSampled {
+        name: String,
+        reading: AuxReading,
+    },
+    SettingsApplied {
+        settings: WavegenSettings,
+    },
+    WavegenStarted,
+    WavegenStopped,
+    RecoveryStarted {
+        window: String,
+        action: String,
+    },
+    RecoveryFinished {
+        window: String,
+        error: Option<String>,
+    },
+    // flow round trip latency, logged when a sweep ends
+    BridgeStats {
+        commands: BTreeMap<String, CommandStats>,
+    },
+    Warning {
+        message: String,
+    },
+    Error {
+        message: String,
+        backtrace: String,
+    },
+}
+impl Event {
+    pub fn error(e: &anyhow::Error) -> Self {
+        Self::Error {
+            message: format!("{e:#}"),
+            backtrace: e.backtrace().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub time: DateTime<Local>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+// appends one json line per event, flushed straight to disk so a crash leaves
+// the record complete up to the failure
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    file: Rc<RefCell<File>>,
+}
+impl EventLog {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open event log `{}`", path.display()))?;
+        Ok(Self {
+            file: Rc::new(RefCell::new(file)),
+        })
+    }
+    // a failing log must not stop the experiment, so errors are only reported
+    pub fn log(&self, event: Event) {
+        let entry = LogEntry {
+            time: Local::now(),
+            event,
+        };
+        let res = serde_json::to_string(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut file = self.file.borrow_mut();
+                writeln!(file, "{line}")?;
+                file.flush()?;
+                Ok(())
+            });
+        if let Err(e) = res {
+            eprintln!("WARNING: could not write event: {e:#}");
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointSummary {
+    pub name: String,
+    pub started: DateTime<Local>,
+    pub finished: Option<DateTime<Local>>,
+    pub status: &'static str,
+    pub warnings: usize,
+}
+
+// one row per point, in the order they were started
+pub fn summarize(path: impl AsRef<Path>) -> Result<Vec<PointSummary>> {
+    let path = path.as_ref();
+    let reader = BufReader::new(
+        File::open(path).with_context(|| format!("Could not open `{}`", path.display()))?,
+    );
+    let mut points: Vec<PointSummary> = vec![];
+    let mut running = BTreeMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: LogEntry = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid event on line {}", i + 1))?;
+        let (name, status, warnings) = match entry.event {
+            Event::PointStarted { name, .. } => {
+                running.insert(name.clone(), points.len());
+                points.push(PointSummary {
+                    name,
+                    started: entry.time,
+                    finished: None,
+                    status: "running",
+                    warnings: 0,
+                });
+                continue;
+            }
+            Event::PointFinished { name, report } => (name, "complete", report.warnings.len()),
+            Event::PointFailed { name, .. } => (name, "failed", 0),
+            Event::PointDeferred { name } => (name, "deferred", 0),
+            // a point already complete on resume is skipped without being started, so only one
+            // skipped mid-run has a row to close
+            Event::PointSkipped { name } => (name, "skipped", 0),
+            _ => continue,
+        };
+        if let Some(point) = running.remove(&name).map(|i| &mut points[i]) {
+            point.finished = Some(entry.time);
+            point.status = status;
+            point.warnings = warnings;
+        }
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn started(name: &str) -> Event {
+        Event::PointStarted {
+            name: name.into(),
+            settings: WavegenSettings {
+                pkpk: 200.,
+                period: Duration::from_secs(2),
+                symmetry_p: 100.,
+                offset: 0.,
+            },
+        }
+    }
+
+    #[test]
+    fn skipped_points_are_closed_in_the_summary() {
+        let dir = TempDir::new("events_summary");
+        let path = dir.join(EVENTS_FILE);
+        let log = EventLog::open(&path).unwrap();
+        for event in [
+            Event::PointSkipped {
+                name: "done_on_resume.dat".into(),
+            },
+            started("skipped.dat"),
+            Event::PointSkipped {
+                name: "skipped.dat".into(),
+            },
+            started("failed.dat"),
+            Event::PointFailed {
+                name: "failed.dat".into(),
+                error: "gone".into(),
+            },
+            started("running.dat"),
+        ] {
+            log.log(event);
+        }
+        let statuses = summarize(&path)
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.name, p.status, p.finished.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                ("skipped.dat".to_string(), "skipped", true),
+                ("failed.dat".to_string(), "failed", true),
+                ("running.dat".to_string(), "running", false),
+            ]
+        );
+    }
+}
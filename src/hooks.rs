@@ -0,0 +1,286 @@
+use std::{fs::OpenOptions, path::Path};
+
+use anyhow::{bail, Context, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use itertools::Itertools;
+use tokio::process::Command;
+
+use crate::{
+    aquisition::{Aquisition, Channel},
+    power_automate::AcquisitionReport,
+    sweep::SweepPoint,
+};
+
+pub const SUMMARY_FILE: &str = "summary.csv";
+const SUMMARY_HEADER: [&str; 14] = [
+    "file",
+    "pkpk",
+    "period_s",
+    "symmetry_p",
+    "offset",
+    "n_waves",
+    "started_at",
+    "duration_s",
+    "samples",
+    "probe_pkpk",
+    "current_pkpk",
+    "voltage_pkpk",
+    "tracking_error",
+    "warnings",
+];
+
+// custom logic run after each sweep point's file is written, see `SweepRunner::add_hook`.
+// a failure is logged and recorded against the point, and only stops the sweep if the hook is
+// critical
+pub trait AcqHook {
+    fn name(&self) -> &str;
+    fn is_critical(&self) -> bool {
+        false
+    }
+    fn on_point_complete<'a>(
+        &'a self,
+        point: &'a SweepPoint,
+        path: &'a Path,
+        report: &'a AcquisitionReport,
+    ) -> LocalBoxFuture<'a, Result<()>>;
+}
+
+// appends a row of each point's settings and key figures to `summary.csv` in the run folder
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SummaryCsv {
+    pub critical: bool,
+}
+impl AcqHook for SummaryCsv {
+    fn name(&self) -> &str {
+        "summary_csv"
+    }
+    fn is_critical(&self) -> bool {
+        self.critical
+    }
+    fn on_point_complete<'a>(
+        &'a self,
+        point: &'a SweepPoint,
+        path: &'a Path,
+        report: &'a AcquisitionReport,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move { append_summary(point, path, report) }.boxed_local()
+    }
+}
+
+fn append_summary(point: &SweepPoint, path: &Path, report: &AcquisitionReport) -> Result<()> {
+    let folder = path.parent().context("The point's file has no folder")?;
+    let summary = folder.join(SUMMARY_FILE);
+    let aq = Aquisition::from_datfile(&report.data)?;
+    let range = |channel: Channel| {
+        aq.channel(channel)
+            .iter()
+            .minmax()
+            .into_option()
+            .map_or(0., |(min, max)| max - min)
+    };
+    let settings = point.settings;
+    let info = &report.info;
+    let duration = (info.finished_at - info.started_at).num_milliseconds() as f64 / 1000.;
+    let row = [
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into(),
+        settings.pkpk.to_string(),
+        settings.period.as_secs_f64().to_string(),
+        settings.symmetry_p.to_string(),
+        settings.offset.to_string(),
+        point.n_waves.to_string(),
+        info.started_at.to_rfc3339(),
+        duration.to_string(),
+        aq.len().to_string(),
+        range(Channel::Probe).to_string(),
+        range(Channel::Current).to_string(),
+        range(Channel::Voltage).to_string(),
+        aq.voltage_tracking_error().to_string(),
+        info.warnings.len().to_string(),
+    ];
+    let new = !summary.exists();
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&summary)
+        .with_context(|| format!("Could not open `{}`", summary.display()))?;
+    let mut writer = csv::Writer::from_writer(file);
+    if new {
+        writer.write_record(SUMMARY_HEADER)?;
+    }
+    writer.write_record(row)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// runs `command` through the shell after each point, with `{path}`, `{name}` and `{folder}`
+// replaced by the point's file, its file name and the run folder. nothing is quoted, so a
+// template for paths with spaces needs its own quotes. a non-zero exit counts as a failure
+#[derive(Debug, Clone)]
+pub struct ShellCommand {
+    pub command: String,
+    pub critical: bool,
+}
+impl ShellCommand {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            critical: false,
+        }
+    }
+    pub fn render(&self, path: &Path) -> String {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let folder = path.parent().unwrap_or(Path::new(""));
+        self.command
+            .replace("{path}", &path.display().to_string())
+            .replace("{name}", &name)
+            .replace("{folder}", &folder.display().to_string())
+    }
+}
+impl AcqHook for ShellCommand {
+    fn name(&self) -> &str {
+        &self.command
+    }
+    fn is_critical(&self) -> bool {
+        self.critical
+    }
+    fn on_point_complete<'a>(
+        &'a self,
+        _point: &'a SweepPoint,
+        path: &'a Path,
+        _report: &'a AcquisitionReport,
+    ) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            let command = self.render(path);
+            let output = shell(&command)
+                .output()
+                .await
+                .with_context(|| format!("Could not run `{command}`"))?;
+            if !output.status.success() {
+                bail!(
+                    "`{command}` exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+fn shell(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        Command::new("cmd")
+    } else {
+        Command::new("sh")
+    };
+    shell
+        .arg(if cfg!(windows) { "/C" } else { "-c" })
+        .arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Local;
+
+    use super::*;
+    use crate::{
+        power_automate::{AcquisitionInfo, WavegenSettings},
+        test_util::TempDir,
+    };
+
+    fn point() -> SweepPoint {
+        SweepPoint {
+            settings: WavegenSettings {
+                pkpk: 200.,
+                period: Duration::from_secs(2),
+                symmetry_p: 100.,
+                offset: 0.,
+            },
+            n_waves: 3,
+            warmup_periods: 1,
+            discard_first_period: false,
+            skip_amplitude_correction: false,
+            grid: None,
+        }
+    }
+
+    fn report() -> AcquisitionReport {
+        let signal = |scale: f64| (0..10).map(|i| i as f64 * scale).collect_vec();
+        let aq =
+            Aquisition::new(signal(1.), signal(0.5), signal(2.), point().settings, 1.).unwrap();
+        let now = Local::now();
+        AcquisitionReport {
+            data: aq.to_datfile(),
+            info: AcquisitionInfo {
+                windows: 1,
+                skipped_windows: 0,
+                unmatched_seams: 0,
+                gaps: 0,
+                trimmed_samples: 0,
+                started_at: now,
+                finished_at: now + chrono::Duration::seconds(6),
+                retries: 0,
+                window_intervals: vec![],
+                warnings: vec!["noisy".into()],
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn summary_csv_appends_a_row_per_point() {
+        let folder = TempDir::new("summary_hook");
+        let hook = SummaryCsv::default();
+        for name in ["point_1.dat", "point_2.dat"] {
+            let path = folder.join(name);
+            hook.on_point_complete(&point(), &path, &report())
+                .await
+                .unwrap();
+        }
+        let mut reader = csv::Reader::from_path(folder.join(SUMMARY_FILE)).unwrap();
+        assert_eq!(reader.headers().unwrap(), &SUMMARY_HEADER[..]);
+        let rows = reader.records().map(Result::unwrap).collect_vec();
+        assert_eq!(rows.len(), 2);
+        let column = |name| SUMMARY_HEADER.iter().position(|h| *h == name).unwrap();
+        assert_eq!(&rows[1][column("file")], "point_2.dat");
+        assert_eq!(&rows[0][column("pkpk")], "200");
+        assert_eq!(&rows[0][column("duration_s")], "6");
+        assert_eq!(&rows[0][column("samples")], "10");
+        assert_eq!(&rows[0][column("probe_pkpk")], "9");
+        assert_eq!(&rows[0][column("voltage_pkpk")], "18");
+        assert_eq!(&rows[0][column("warnings")], "1");
+    }
+
+    #[tokio::test]
+    async fn shell_command_runs_the_rendered_template() {
+        let folder = TempDir::new("shell_hook");
+        let path = folder.join("point_1.dat");
+        let hook = ShellCommand::new("echo {name}> {folder}/ran.txt");
+        assert_eq!(
+            hook.render(&path),
+            format!("echo point_1.dat> {}/ran.txt", folder.path().display())
+        );
+        hook.on_point_complete(&point(), &path, &report())
+            .await
+            .unwrap();
+        let ran = std::fs::read_to_string(folder.join("ran.txt")).unwrap();
+        assert_eq!(ran.trim(), "point_1.dat");
+    }
+
+    #[tokio::test]
+    async fn shell_command_fails_on_a_non_zero_exit() {
+        let folder = TempDir::new("shell_hook_fail");
+        let path = folder.join("point_1.dat");
+        let err = ShellCommand::new("exit 3")
+            .on_point_complete(&point(), &path, &report())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("`exit 3` exited with"), "{err}");
+    }
+}
@@ -0,0 +1,52 @@
+use std::io::IsTerminal;
+
+use anyhow::Result;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    terminal,
+};
+use power_automate::sweep::SweepCommand;
+use tokio::sync::mpsc::UnboundedSender;
+
+// leaves raw mode when the sweep is over
+pub struct Keyboard {
+    raw: bool,
+}
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        if self.raw {
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+}
+
+// p pauses after the current point, r resumes, s skips the current point and q aborts.
+// raw mode swallows the Ctrl+C signal, so it is read as a key and aborts the same way
+pub fn listen(commands: UnboundedSender<SweepCommand>) -> Result<Keyboard> {
+    if !std::io::stdout().is_terminal() {
+        return Ok(Keyboard { raw: false });
+    }
+    terminal::enable_raw_mode()?;
+    println!("p: pause after this point, r: resume, s: skip this point, q: abort\r");
+    std::thread::spawn(move || loop {
+        let key = match event::read() {
+            Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => key,
+            Ok(_) => continue,
+            Err(_) => return,
+        };
+        let command = match key.code {
+            KeyCode::Char('p') => SweepCommand::Pause,
+            KeyCode::Char('r') => SweepCommand::Resume,
+            KeyCode::Char('s') => SweepCommand::Skip,
+            KeyCode::Char('q') => SweepCommand::Abort,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                SweepCommand::Abort
+            }
+            _ => continue,
+        };
+        if commands.send(command).is_err() {
+            return;
+        }
+    });
+    Ok(Keyboard { raw: true })
+}
@@ -0,0 +1,54 @@
+mod aquisition;
+pub mod auxiliary;
+pub mod events;
+pub mod hooks;
+pub mod notify;
+#[cfg(feature = "plot")]
+mod plot;
+mod power_automate;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod reprocess;
+pub mod scratch;
+pub mod session;
+pub mod sweep;
+#[cfg(test)]
+mod test_util;
+
+pub mod driver {
+    pub use crate::power_automate::{
+        AcquisitionInfo, AcquisitionReport, AlreadyAcquiring, AmplitudeCorrection,
+        AquisitionDriver as AcquisitionDriver, AveragedAcquisition, DriverConfig, PhaseAlign,
+        ProgressStatus, RecoveryFailed, TrimPolicy, Waveform, WavegenGuard, WavegenSettings,
+        WindowFailure,
+    };
+
+    #[deprecated(note = "renamed to `AcquisitionDriver`")]
+    pub type AquisitionDriver = AcquisitionDriver;
+}
+
+pub mod bridge {
+    pub use crate::power_automate::{CommandStats, PowerAutomate, ServerError, Transport};
+}
+
+pub mod data {
+    pub use crate::aquisition::{
+        format_attribute, is_gzip_path, parse_attribute, AcqAttributes, Aquisition as Acquisition,
+        AquisitionWriter as AcquisitionWriter, Calibration, Channel, ChannelCalibration,
+        ChannelPatterns, DecimalSeparator, FileSink, OutputFormat, ReaderOptions,
+    };
+
+    #[deprecated(note = "renamed to `Acquisition`")]
+    pub type Aquisition = Acquisition;
+}
+
+pub mod analysis {
+    pub use crate::aquisition::{
+        average_aquisitions, boxcar_decimate, clip_report, find_extrema, rising_crossing,
+        std_label, ChannelLimits, ClipReport, DetrendMode, Extremum, FrequencyResponse,
+        SettleCriterion, SettleTest,
+    };
+}
+
+pub use data::Acquisition;
+pub use driver::{AcquisitionDriver, WavegenSettings};
@@ -1,16 +1,53 @@
-mod power_automate;
+mod keyboard;
 
-use std::{io::BufWriter, path::PathBuf, time::Duration};
+use std::time::Duration;
 
-use anyhow::Result;
-use power_automate::{AquisitionDriver, WavegenSettings};
+use anyhow::{bail, Context, Result};
+use power_automate::{
+    data::OutputFormat,
+    driver::DriverConfig,
+    events::summarize,
+    reprocess::{load_mapping, reprocess, ReprocessOptions, ReprocessStatus},
+    scratch,
+    sweep::{RunFolder, SweepCommand, SweepGrid, SweepOptions, SweepPlan, SweepPoint, SweepRunner},
+    AcquisitionDriver, WavegenSettings,
+};
+
+const DATA_DIR: &str = r#"C:\Users\Brad\Desktop\code\actuator-project\data"#;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let mut aqd = AquisitionDriver::new().await?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("reprocess") => return run_reprocess(&args[1..]),
+        Some("events") => return run_events(&args[1..]),
+        Some("clean-temp") => return run_clean_temp(&args[1..]),
+        #[cfg(feature = "remote")]
+        Some("serve") => return run_serve(&args[1..]).await,
+        _ => {}
+    }
+    // `run` is the default
+    let run_args = match args.first().map(String::as_str) {
+        Some("run") => &args[1..],
+        _ => &args[..],
+    };
+    let (mut dry_run, mut reset_session) = (false, false);
+    for flag in run_args {
+        match flag.as_str() {
+            "--dry-run" => dry_run = true,
+            "--reset-session" => reset_session = true,
+            _ => bail!("Usage: run [--dry-run] [--reset-session]"),
+        }
+    }
 
-    let folder = PathBuf::from(r#"C:\Users\Brad\Desktop\code\actuator-project\data\pzt-tile\0002"#);
+    let mut config = DriverConfig::from_env();
+    config.reset_session = reset_session;
+    let data_dir = config.output_folder.clone().unwrap_or(DATA_DIR.into());
+    let run_folder = RunFolder::new(data_dir, "pzt-tile");
+    let resume = true;
     let num_samples = 2;
+    let warmup_periods = 0;
+    let discard_first_period = true;
     let pkpk = 200.;
     let offset = 200.;
 
@@ -19,8 +56,17 @@ async fn main() -> Result<()> {
     let ramp_times = [0.5];
     // let ramp_times = [0.1, 1., 5.];
     let ramp_rest_time = 120.;
+    let options = SweepOptions {
+        format: OutputFormat::Dat,
+        compress: false,
+        plot: false,
+        rest_between_points: None,
+        start_at: None,
+        deadline: None,
+    };
 
     let mut settings = WavegenSettings::default();
+    let mut points = vec![];
 
     // Hysteresis
     settings.pkpk = pkpk;
@@ -28,15 +74,14 @@ async fn main() -> Result<()> {
     settings.offset = offset;
     for period in hyst_periods {
         settings.period = Duration::from_secs_f64(period);
-        let mut file_path = folder.clone();
-        file_path.push(filename(settings));
-        if file_path.exists() {
-            continue;
-        }
-        println!("Running {}", filename(settings));
-        let aq = aqd.aquire_n_waves(settings, num_samples).await?;
-        let writer = BufWriter::new(std::fs::File::create(file_path)?);
-        aq.write_to(writer)?;
+        points.push(SweepPoint {
+            settings,
+            n_waves: num_samples,
+            warmup_periods,
+            discard_first_period,
+            skip_amplitude_correction: false,
+            grid: None,
+        });
     }
 
     // Ramp
@@ -46,26 +91,184 @@ async fn main() -> Result<()> {
         let ramp_dur = Duration::from_secs_f64(ramp_time);
         let ramp_rest_dur = Duration::from_secs_f64(ramp_rest_time);
         settings.set_ramp_time(ramp_dur, ramp_rest_dur);
-        let mut file_path = folder.clone();
-        file_path.push(filename(settings));
-        if file_path.exists() {
-            continue;
-        }
-        println!("Running {}", filename(settings));
-        let aq = aqd.aquire_n_waves(settings, num_samples).await?;
-        let writer = BufWriter::new(std::fs::File::create(file_path)?);
-        aq.write_to(writer)?;
+        points.push(SweepPoint {
+            settings,
+            n_waves: num_samples,
+            warmup_periods,
+            discard_first_period,
+            skip_amplitude_correction: false,
+            grid: None,
+        });
+    }
+
+    // Grid
+    let grid: Option<SweepGrid> = None;
+    // let grid = Some(SweepGrid {
+    //     base: SweepPoint {
+    //         settings: WavegenSettings { symmetry_p: 100., offset, ..settings },
+    //         n_waves: num_samples,
+    //         warmup_periods,
+    //         discard_first_period,
+    //         skip_amplitude_correction: false,
+    //         grid: None,
+    //     },
+    //     outer: GridAxis { axis: SweepAxis::Pkpk, values: AxisValues::List(vec![50., 100., 200.]) },
+    //     inner: GridAxis {
+    //         axis: SweepAxis::Period,
+    //         values: AxisValues::Range { start: 0.25, stop: 20., n: 4, log: true },
+    //     },
+    //     snake: true,
+    // });
+    if let Some(grid) = grid {
+        points.extend(grid.points()?);
     }
 
+    let plan = SweepPlan { points, options };
+    let description = plan.describe(&config);
+    println!("{description}");
+    if !description.is_valid() {
+        bail!("The sweep plan is invalid");
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    let mut aqd = AcquisitionDriver::with_config(config).await?;
+    aqd.check_ready().await?;
+    let folder = if resume {
+        run_folder.open_latest()?
+    } else {
+        run_folder.create_next()?
+    };
+    println!("Writing to {}", folder.display());
+
+    let (commands, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let on_ctrl_c = commands.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = on_ctrl_c.send(SweepCommand::Abort);
+        }
+    });
+    let keyboard = keyboard::listen(commands)?;
+    let res = SweepRunner::new(&mut aqd, folder, plan.options)?
+        .with_commands(receiver)
+        .run(&plan.points)
+        .await;
+    drop(keyboard);
+    res?;
+
     aqd.stop_wavegen().await?;
     Ok(())
 }
 
-fn filename(settings: WavegenSettings) -> String {
-    format!(
-        "trap_{:.2}s_{:.2}v_{:.2}p.dat",
-        settings.period.as_secs_f64(),
-        settings.pkpk,
-        settings.symmetry_p,
-    )
+// serve [--addr 0.0.0.0:3001] [--token secret]
+#[cfg(feature = "remote")]
+async fn run_serve(args: &[String]) -> Result<()> {
+    use power_automate::remote::{serve, RemoteOptions};
+
+    let config = DriverConfig::from_env();
+    let mut options = RemoteOptions {
+        addr: "127.0.0.1:3001".parse().unwrap(),
+        token: None,
+        data_dir: config.output_folder.clone().unwrap_or(DATA_DIR.into()),
+    };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .with_context(|| format!("Missing value for `{flag}`"))?;
+        match flag.as_str() {
+            "--addr" => options.addr = value.parse().context("Invalid address")?,
+            "--token" => options.token = Some(value.clone()),
+            _ => bail!("Unknown option `{flag}`"),
+        }
+    }
+    let mut aqd = AcquisitionDriver::with_config(config).await?;
+    aqd.check_ready().await?;
+    serve(&mut aqd, options).await
+}
+
+// reprocess <src> <dst> [--mapping settings.csv] [--offset volts]
+fn run_reprocess(args: &[String]) -> Result<()> {
+    let [src, dst, rest @ ..] = args else {
+        bail!("Usage: reprocess <src> <dst> [--mapping settings.csv] [--offset volts]");
+    };
+    let mut options = ReprocessOptions::default();
+    let mut rest = rest.iter();
+    while let Some(flag) = rest.next() {
+        let value = rest
+            .next()
+            .with_context(|| format!("Missing value for `{flag}`"))?;
+        match flag.as_str() {
+            "--mapping" => options.mapping = load_mapping(value)?,
+            "--offset" => options.offset = Some(value.parse().context("Invalid offset")?),
+            _ => bail!("Unknown option `{flag}`"),
+        }
+    }
+    let summaries = reprocess(src, dst, &options)?;
+    for summary in &summaries {
+        println!("{summary}");
+    }
+    let count = |f: fn(&ReprocessStatus) -> bool| summaries.iter().filter(|s| f(&s.status)).count();
+    println!(
+        "{} rewritten, {} skipped, {} failed",
+        count(|s| *s == ReprocessStatus::Rewritten),
+        count(|s| *s == ReprocessStatus::Skipped),
+        count(|s| matches!(s, ReprocessStatus::Failed(_))),
+    );
+    Ok(())
+}
+
+// clean-temp [--max-age-h <hours>]
+fn run_clean_temp(args: &[String]) -> Result<()> {
+    let max_age = match args {
+        [] => DriverConfig::default().scratch_max_age,
+        [flag, hours] if flag == "--max-age-h" => {
+            let hours: f64 = hours
+                .parse()
+                .with_context(|| format!("`{hours}` is not a number of hours"))?;
+            Duration::try_from_secs_f64(hours * 3600.)
+                .with_context(|| format!("{hours} h is not a valid age"))?
+        }
+        _ => bail!("Usage: clean-temp [--max-age-h <hours>]"),
+    };
+    let dir = DriverConfig::from_env().scratch_dir;
+    let summary = scratch::clean(&dir, max_age)?;
+    println!("{summary} from {}", dir.display());
+    Ok(())
+}
+
+// events summarize <events.jsonl>
+fn run_events(args: &[String]) -> Result<()> {
+    let [command, path] = args else {
+        bail!("Usage: events summarize <events.jsonl>");
+    };
+    if command != "summarize" {
+        bail!("Unknown events command `{command}`");
+    }
+    let points = summarize(path)?;
+    let width = points
+        .iter()
+        .map(|p| p.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(5);
+    println!(
+        "{:width$}  {:19}  {:>10}  {:8}  warnings",
+        "point", "started", "duration", "status"
+    );
+    for p in points {
+        let duration = p.finished.map_or("-".to_string(), |f| {
+            format!("{:.1} s", (f - p.started).num_milliseconds() as f64 / 1000.)
+        });
+        println!(
+            "{:width$}  {}  {:>10}  {:8}  {}",
+            p.name,
+            p.started.format("%Y-%m-%d %H:%M:%S"),
+            duration,
+            p.status,
+            p.warnings
+        );
+    }
+    Ok(())
 }
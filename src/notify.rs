@@ -0,0 +1,97 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::Result;
+
+use crate::power_automate::AquisitionDriver;
+
+// `{"text": ...}` is understood by both Slack and Teams incoming webhooks
+const DEFAULT_TEMPLATE: &str = r#"{"text": "{title}: {message} ({folder}, {elapsed_s} s, {succeeded} succeeded, {failed} failed)"}"#;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+    // json body with `{title}`, `{message}`, `{folder}`, `{elapsed_s}`, `{succeeded}`
+    // and `{failed}` placeholders, substituted as escaped json string contents
+    pub template: String,
+}
+impl Webhook {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            template: DEFAULT_TEMPLATE.into(),
+        }
+    }
+    pub fn render(&self, status: &SweepStatus) -> String {
+        let fields = [
+            ("title", status.title.clone()),
+            ("message", status.message.clone()),
+            ("folder", status.folder.display().to_string()),
+            ("elapsed_s", format!("{:.0}", status.elapsed.as_secs_f64())),
+            ("succeeded", status.succeeded.to_string()),
+            ("failed", status.failed.to_string()),
+        ];
+        fields
+            .into_iter()
+            .fold(self.template.clone(), |body, (key, value)| {
+                body.replace(&format!("{{{key}}}"), &json_escape(&value))
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepStatus {
+    pub title: String,
+    pub message: String,
+    pub folder: PathBuf,
+    pub elapsed: Duration,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Notifier {
+    pub webhook: Option<Webhook>,
+    // a windows toast shown by the flow
+    pub toast: bool,
+}
+impl Notifier {
+    // a missed notification must not take the sweep down with it, so failures are only reported
+    pub async fn notify(&self, driver: &AquisitionDriver, status: &SweepStatus) {
+        if self.toast {
+            if let Err(e) = driver
+                .show_notification(&status.title, &status.message)
+                .await
+            {
+                eprintln!("WARNING: could not show notification: {e:#}");
+            }
+        }
+        if let Some(webhook) = &self.webhook {
+            if let Err(e) = post_webhook(webhook, status).await {
+                eprintln!("WARNING: could not post webhook: {e:#}");
+            }
+        }
+    }
+}
+
+#[cfg(feature = "webhook")]
+async fn post_webhook(webhook: &Webhook, status: &SweepStatus) -> Result<()> {
+    reqwest::Client::new()
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .body(webhook.render(status))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "webhook"))]
+async fn post_webhook(_webhook: &Webhook, _status: &SweepStatus) -> Result<()> {
+    anyhow::bail!("Webhooks require the `webhook` feature")
+}
+
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap();
+    quoted[1..quoted.len() - 1].to_string()
+}
@@ -0,0 +1,131 @@
+use std::{ops::Range, path::Path};
+
+use anyhow::Result;
+use itertools::Itertools;
+use plotters::{coord::Shift, prelude::*};
+
+use crate::aquisition::{Aquisition, Channel};
+
+const PLOT_MAX_POINTS: usize = 2000;
+
+type Area<'a> = DrawingArea<BitMapBackend<'a>, Shift>;
+
+impl Aquisition {
+    pub fn plot_summary(&self, path: impl AsRef<Path>) -> Result<()> {
+        let aq = self.for_plotting()?;
+        let root = BitMapBackend::new(path.as_ref(), (1000, 1200)).into_drawing_area();
+        root.fill(&WHITE)?;
+        let panels = root.split_evenly((4, 1));
+        let times = aq.times_s().collect_vec();
+        for (panel, channel) in panels.iter().zip(Channel::ALL) {
+            let points = times
+                .iter()
+                .copied()
+                .zip(aq.channel(channel).iter().copied());
+            draw_line(panel, None, "Time (s)", aq.label(channel), points.collect())?;
+        }
+        let loop_points = aq
+            .channel(Channel::Voltage)
+            .iter()
+            .copied()
+            .zip(aq.channel(Channel::Probe).iter().copied());
+        draw_line(
+            &panels[3],
+            None,
+            aq.label(Channel::Voltage),
+            aq.label(Channel::Probe),
+            loop_points.collect(),
+        )?;
+        root.present()?;
+        Ok(())
+    }
+    pub fn plot_hysteresis(&self, path: impl AsRef<Path>) -> Result<()> {
+        let aq = self.for_plotting()?;
+        let root = BitMapBackend::new(path.as_ref(), (1000, 800)).into_drawing_area();
+        root.fill(&WHITE)?;
+        let loop_points = aq
+            .channel(Channel::Voltage)
+            .iter()
+            .copied()
+            .zip(aq.channel(Channel::Probe).iter().copied());
+        draw_line(
+            &root,
+            Some(&aq.settings_caption()),
+            aq.label(Channel::Voltage),
+            aq.label(Channel::Probe),
+            loop_points.collect(),
+        )?;
+        root.present()?;
+        Ok(())
+    }
+    pub fn plot_timeseries(&self, path: impl AsRef<Path>) -> Result<()> {
+        let aq = self.for_plotting()?;
+        let root = BitMapBackend::new(path.as_ref(), (1000, 900)).into_drawing_area();
+        root.fill(&WHITE)?;
+        let root = root.titled(&aq.settings_caption(), ("sans-serif", 20))?;
+        let times = aq.times_s().collect_vec();
+        let panels = root.split_evenly((Channel::ALL.len(), 1));
+        for (panel, channel) in panels.iter().zip(Channel::ALL) {
+            let points = times
+                .iter()
+                .copied()
+                .zip(aq.channel(channel).iter().copied());
+            draw_line(panel, None, "Time (s)", aq.label(channel), points.collect())?;
+        }
+        root.present()?;
+        Ok(())
+    }
+    fn settings_caption(&self) -> String {
+        let settings = self.wavegen_settings;
+        format!(
+            "{:.2} s period, {:.2} V pkpk, {:.2} V offset, {:.2}% symmetry",
+            settings.period.as_secs_f64(),
+            settings.pkpk,
+            settings.offset,
+            settings.symmetry_p
+        )
+    }
+    // decimate long aquisitions so rendering stays fast
+    fn for_plotting(&self) -> Result<Aquisition> {
+        let factor = (self.len() / PLOT_MAX_POINTS).max(1);
+        self.decimate(factor)
+    }
+}
+
+fn draw_line(
+    area: &Area,
+    caption: Option<&str>,
+    x_label: &str,
+    y_label: &str,
+    points: Vec<(f64, f64)>,
+) -> Result<()> {
+    let x_range = padded_range(points.iter().map(|p| p.0));
+    let y_range = padded_range(points.iter().map(|p| p.1));
+    let mut builder = ChartBuilder::on(area);
+    if let Some(caption) = caption {
+        builder.caption(caption, ("sans-serif", 20));
+    }
+    let mut chart = builder
+        .margin(10)
+        .x_label_area_size(40)
+        .y_label_area_size(80)
+        .build_cartesian_2d(x_range, y_range)?;
+    chart
+        .configure_mesh()
+        .x_desc(x_label)
+        .y_desc(y_label)
+        .draw()?;
+    chart.draw_series(LineSeries::new(points, &BLUE))?;
+    Ok(())
+}
+
+fn padded_range(values: impl Iterator<Item = f64>) -> Range<f64> {
+    let (lo, hi) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+        (lo.min(v), hi.max(v))
+    });
+    if !lo.is_finite() || !hi.is_finite() {
+        return 0.0..1.0;
+    }
+    let pad = if hi > lo { (hi - lo) * 0.05 } else { 1. };
+    lo - pad..hi + pad
+}
@@ -1,40 +1,94 @@
 use std::{
-    collections::BTreeSet,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    env::VarError,
+    fmt::Display,
+    fs::File,
     future::{ready, Future},
-    path::Path,
+    io::BufWriter,
+    net::SocketAddr,
+    path::{Path, PathBuf},
     rc::Rc,
-    sync::{Arc, Mutex},
-    time::{Duration, SystemTime},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{bail, Context, Result};
 use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path as AxumPath, Query,
+    },
+    http::StatusCode,
     routing::{get, post},
-    Router,
+    Json, Router,
 };
-use indicatif::{ProgressBar, ProgressStyle};
+use chrono::{DateTime, Local};
+use futures::{future, stream, Stream, TryStreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use nanonis::DatFile;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
 use tokio::{
+    runtime::{Handle, RuntimeFlavor},
     sync::{
         mpsc::{self, error::TryRecvError},
-        oneshot,
+        oneshot, Mutex as AsyncMutex, OwnedMutexGuard,
+    },
+    task::{self, JoinHandle},
+};
+
+use crate::{
+    aquisition::{
+        average_aquisitions, boxcar_decimate, clip_report, find_signal, format_attribute,
+        parse_decimal, rewrite_header, rising_crossing, std_label, AcqAttributes, Aquisition,
+        AquisitionWriter, Channel, ChannelCalibration, ChannelLimits, ChannelPatterns, ClipReport,
+        SettleCriterion, OFFSET_KEY, PERIOD_KEY, PKPK_KEY, SYMMETRY_KEY,
     },
-    task::JoinHandle,
+    auxiliary::{AuxChannel, FlowAuxLogger},
+    events::{Event, EventLog},
+    scratch::{self, ScratchFile},
+    session::{self, SessionState},
+    sweep::RestPolicy,
 };
 
+const DEFAULT_BIND_ADDR: &str = "127.0.0.1:3000";
 const WAVEGEN_GAIN: f64 = 40.;
 const NANONIS_WINDOW_S: f64 = 125.;
 const NANONIS_WINDOW_BUFFER_S: f64 = 5.;
+const PING_TIMEOUT_S: f64 = 5.;
+const HISTORY_SAVE_TIMEOUT_S: f64 = 30.;
+// covers both transports, from queueing the command to its response
+const COMMAND_TIMEOUT_S: f64 = 60.;
+// flow instances polling within this long of each other are treated as running at once
+const DUPLICATE_INSTANCE_WINDOW_S: f64 = 30.;
+const SETTINGS_TOLERANCE: f64 = 1e-3;
+const WAVEFORMS_WINDOW: &str = "WaveForms (new workspace)";
+const HISTORY_WINDOW: &str = "History";
+const WINDOW_OPEN_TIMEOUT_S: f64 = 60.;
+// time between the two history snapshots compared by `check_history_recording`
+const HISTORY_CHECK_INTERVAL_S: f64 = 2.;
+const MAX_SAMPLE_PERIOD_MS: f64 = 1000.;
+// a toggle click can miss, so the running state is read back and the click retried
+const WAVEGEN_TOGGLE_ATTEMPTS: usize = 3;
+const WAVEGEN_TOGGLE_SETTLE_S: f64 = 0.5;
+// keeps each custom waveform command well inside a single GET response
+const CUSTOM_CHUNK_SAMPLES: usize = 500;
+// round trips kept per command for the latency statistics
+const COMMAND_TIMING_WINDOW: usize = 200;
 
 static mut PA_SERVER: Option<Rc<PowerAutomate>> = None;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct WavegenSettings {
     pub pkpk: f64,
     pub period: Duration,
+    // percent of each half period spent ramping, not a duty cycle
     pub symmetry_p: f64,
     pub offset: f64,
 }
@@ -43,6 +97,83 @@ impl WavegenSettings {
         self.period = (ramp_time + rest_time) * 2;
         self.symmetry_p = ramp_time.as_secs_f64() / (self.period.as_secs_f64() / 2.) * 100.;
     }
+    pub fn ramp_time(&self) -> Option<(Duration, Duration)> {
+        // a pure triangle has no rest, so it was not built by `set_ramp_time`
+        if self.symmetry_p >= 100. || self.symmetry_p <= 0. || self.period.is_zero() {
+            return None;
+        }
+        let half_period = self.period.as_secs_f64() / 2.;
+        let ramp = half_period * self.symmetry_p / 100.;
+        Some((
+            Duration::from_secs_f64(ramp),
+            Duration::from_secs_f64(half_period - ramp),
+        ))
+    }
+    // `trap_{period}s_{pkpk}v_{symmetry}p.dat`, rounded to 2 decimals
+    pub fn to_filename(&self) -> String {
+        format!("{}.dat", self.file_stem())
+    }
+    pub fn file_stem(&self) -> String {
+        format!(
+            "trap_{:.2}s_{:.2}v_{:.2}p",
+            self.period.as_secs_f64(),
+            self.pkpk,
+            self.symmetry_p,
+        )
+    }
+    // header attributes recording these settings, at full precision
+    pub fn attributes(&self) -> [(String, String); 4] {
+        [
+            (PKPK_KEY, self.pkpk),
+            (PERIOD_KEY, self.period.as_secs_f64()),
+            (SYMMETRY_KEY, self.symmetry_p),
+            (OFFSET_KEY, self.offset),
+        ]
+        .map(|(key, value)| (key.to_string(), format_attribute(value)))
+    }
+    // ideal trapezoid at the amplifier output, starting at the foot of the rising ramp
+    pub fn generate_waveform(&self, sample_period_ms: f64, n_samples: usize) -> Vec<f64> {
+        (0..n_samples)
+            .map(|i| self.value_at(i as f64 * sample_period_ms / 1000.))
+            .collect()
+    }
+    // `n_periods` of the ideal drive as the voltage channel, with the other channels zeroed
+    pub fn preview(&self, sample_period: Duration, n_periods: usize) -> Aquisition {
+        let sample_period_ms = sample_period.as_secs_f64() * 1000.;
+        let n_samples = if sample_period.is_zero() {
+            0
+        } else {
+            (self.period.as_secs_f64() * n_periods as f64 / sample_period.as_secs_f64()) as usize
+        };
+        Aquisition {
+            probe: vec![0.; n_samples],
+            current: vec![0.; n_samples],
+            voltage: self.generate_waveform(sample_period_ms, n_samples),
+            wavegen_settings: *self,
+            sample_period_ms,
+            metadata: BTreeMap::from([("preview".to_string(), "true".to_string())]),
+            patterns: ChannelPatterns::default(),
+            labels: BTreeMap::new(),
+            extra: BTreeMap::new(),
+        }
+    }
+    pub(crate) fn value_at(&self, t_s: f64) -> f64 {
+        let low = self.offset - self.pkpk / 2.;
+        let period = self.period.as_secs_f64();
+        if period <= 0. {
+            return low;
+        }
+        let half_period = period / 2.;
+        let ramp = half_period * (self.symmetry_p / 100.).clamp(0., 1.);
+        let t = t_s.rem_euclid(period);
+        let (t, rising) = if t < half_period {
+            (t, true)
+        } else {
+            (t - half_period, false)
+        };
+        let frac = if ramp > 0. { (t / ramp).min(1.) } else { 1. };
+        low + self.pkpk * if rising { frac } else { 1. - frac }
+    }
 }
 impl Default for WavegenSettings {
     fn default() -> Self {
@@ -54,328 +185,3950 @@ impl Default for WavegenSettings {
         }
     }
 }
+// `trapezium 200.0Vpkpk @ 2.00Hz (0.50s), symmetry 100%, offset 200.0V`, with the ramp and rest
+// times added when the symmetry leaves a rest
+impl Display for WavegenSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let period = self.period.as_secs_f64();
+        write!(f, "trapezium {:.1}Vpkpk", self.pkpk)?;
+        if period > 0. {
+            write!(f, " @ {:.2}Hz ({period:.2}s)", 1. / period)?;
+        } else {
+            write!(f, " with no period")?;
+        }
+        // 100% prints as such, a ramp-derived 33.333...% as 33.33%
+        write!(f, ", symmetry {}%", (self.symmetry_p * 100.).round() / 100.)?;
+        if let Some((ramp, rest)) = self.ramp_time() {
+            write!(
+                f,
+                " (ramp {:.2}s, rest {:.2}s)",
+                ramp.as_secs_f64(),
+                rest.as_secs_f64()
+            )?;
+        }
+        write!(f, ", offset {:.1}V", self.offset)
+    }
+}
 
-pub struct AquisitionDriver {
-    pa: Rc<PowerAutomate>,
-    pkpk: Option<f64>,
-    period: Option<Duration>,
-    offset: Option<f64>,
-    symmetry: Option<f64>,
+#[derive(Debug, Clone)]
+pub struct DriverConfig {
+    pub decimate: Option<usize>,
+    pub clip_limits: ChannelLimits,
+    pub max_clip_fraction: Option<f64>,
+    pub offset_settle_time: Duration,
+    // waited out after starting the wavegen, on top of the requested aquisition duration
+    pub warmup: Duration,
+    pub verify_settings: bool,
+    pub verify_retries: usize,
+    pub align_start: bool,
+    pub verify_output: bool,
+    pub output_tolerance: f64,
+    // trims the amplitude against the voltage monitor after warmup, see `AmplitudeCorrection`
+    pub amplitude_correction: Option<AmplitudeCorrection>,
+    // send independent settings changes concurrently, for flows that can run them in parallel
+    pub pipeline_settings: bool,
+    pub transport: Transport,
+    // drive volts per volt read on the voltage monitor
+    pub voltage_monitor_scale: f64,
+    // applied to every capture after clipping is checked, see `ChannelCalibration`
+    pub calibration: ChannelCalibration,
+    // longest run of repeated samples removed where two history windows are joined
+    pub max_seam_dedup: Duration,
+    // longer captures are refused before anything is driven, see `aquire_duration_uncapped`
+    pub max_duration: Option<Duration>,
+    // time spent reading each history window, only used by `estimate_duration`
+    pub window_save_cost: Duration,
+    // the wavegen's output range in volts, before the amplifier gain
+    pub max_output_v: f64,
+    // relaunched if the WaveForms window is closed during a run
+    pub waveforms_path: Option<PathBuf>,
+    // repeat the check that the nanonis is logging to its history before every sweep point
+    pub check_history_each_point: bool,
+    // reopening closed windows is given up after this many attempts within one point
+    pub max_recoveries: usize,
+    // a failed history read is retried this many times before `window_failure` decides
+    pub max_window_retries: usize,
+    pub window_failure: WindowFailure,
+    // where the flow reaches the bridge, only used by the first driver in the process
+    pub bind_addr: SocketAddr,
+    // drive volts per volt of wavegen amplitude
+    pub wavegen_gain: f64,
+    // length of the nanonis history, and how far before its end each window is read
+    pub history_window: Duration,
+    pub window_buffer: Duration,
+    // where runs are written when the caller doesn't pick a folder
+    pub output_folder: Option<PathBuf>,
+    // significant digits the wavegen fields are sent with, since WaveForms drops the rest
+    pub wavegen_digits: usize,
+    // an acquisition started while another holds the bridge fails with `AlreadyAcquiring`
+    // instead of waiting for it to finish
+    pub fail_if_acquiring: bool,
+    // history snapshots are written here before being parsed, see `scratch`
+    pub scratch_dir: PathBuf,
+    // scratch files older than this are deleted when a driver starts
+    pub scratch_max_age: Duration,
+    // where the last commanded state is kept between runs, see `SessionState`
+    pub session_file: Option<PathBuf>,
+    // starts without comparing the device against the saved session
+    pub reset_session: bool,
 }
-impl AquisitionDriver {
-    pub async fn aquire_n_waves(&mut self, settings: WavegenSettings, n: usize) -> Result<DatFile> {
-        let duration = settings.period * (n + 1) as u32;
-        self.aquire_duration(settings, duration).await
-    }
-    pub async fn aquire_duration(
-        &mut self,
-        settings: WavegenSettings,
-        duration: Duration,
-    ) -> Result<DatFile> {
-        self.apply_wavegen_settings(settings).await?;
-        self.start_wavegen().await?;
-        let num_aqs = duration.as_secs_f64() / NANONIS_WINDOW_S;
-        let window_dur = Duration::from_secs_f64(NANONIS_WINDOW_S);
-        let window_buffer_dur = Duration::from_secs_f64(NANONIS_WINDOW_BUFFER_S);
-        let total_dur = duration + window_buffer_dur;
-        let bar = ProgressBar::new(total_dur.as_millis() as u64 / 100).with_style(
-            ProgressStyle::with_template("[{eta_precise}] {bar:60.cyan/blue} {msg}")?,
-        );
-        let aq_end_time = SystemTime::now() + total_dur;
-        let mut window_end_time = SystemTime::now() + window_dur;
-        let mut acc_datfile = None;
-        for i in 1.. {
-            bar.set_message(format!("{} of {}", i, num_aqs.ceil()));
-            let aq_done = loop {
-                let aq_done = aq_end_time.elapsed().is_ok();
-                let window_done = window_end_time.elapsed().is_ok();
-                if window_done | aq_done {
-                    break aq_done;
-                }
-                let Err(remaining) = aq_end_time.elapsed() else{
-                    unreachable!()
-                };
-                bar.set_position((total_dur - remaining.duration()).as_millis() as u64 / 100);
-                tokio::time::sleep(Duration::from_millis(1000)).await;
-            };
-            window_end_time = SystemTime::now() + window_dur - window_buffer_dur;
-            let new_datfile = self.read_history().await?;
-            acc_datfile = match acc_datfile {
-                Some(df) => Some(combine_datfiles(df, new_datfile)),
-                None => Some(new_datfile),
-            };
-            if aq_done {
-                break;
+impl DriverConfig {
+    // the defaults, overridden by `PA_BIND_ADDR`, `PA_WAVEGEN_GAIN`, `PA_WINDOW_S`,
+    // `PA_BUFFER_S` and `PA_OUTPUT_FOLDER` where they are set. values that don't parse are
+    // reported and left at their defaults
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(addr) = env_var("PA_BIND_ADDR") {
+            config.bind_addr = addr;
+        }
+        match env_var::<f64>("PA_WAVEGEN_GAIN") {
+            Some(gain) if gain.is_nan() || gain <= 0. => {
+                eprintln!("WARNING: ignoring PA_WAVEGEN_GAIN={gain}, it must be positive")
             }
+            Some(gain) => config.wavegen_gain = gain,
+            None => {}
         }
-        bar.finish();
-        let mut datfile = acc_datfile.unwrap();
-        // trim extra time from the file
-        let signal_len = datfile.signals.values().next().unwrap().len();
-        let sample_period = datfile.attributes["Sample Period (ms)"]
-            .parse::<f64>()
-            .unwrap();
-        let i = signal_len - (duration.as_secs_f64() * 1000. / sample_period) as usize;
-        for sig in datfile.signals.values_mut() {
-            *sig = sig[i..].into();
+        let window = env_secs("PA_WINDOW_S").unwrap_or(config.history_window);
+        let buffer = env_secs("PA_BUFFER_S").unwrap_or(config.window_buffer);
+        if buffer < window {
+            config.history_window = window;
+            config.window_buffer = buffer;
+        } else {
+            eprintln!(
+                "WARNING: ignoring PA_WINDOW_S and PA_BUFFER_S, the buffer ({buffer:?}) must be \
+                 shorter than the window ({window:?})"
+            );
         }
-        datfile
-            .attributes
-            .insert("period_s".into(), settings.period.as_secs().to_string());
-        datfile
-            .attributes
-            .insert("symmetry_p".into(), settings.symmetry_p.to_string());
-        datfile
-            .attributes
-            .insert("pkpk".into(), settings.pkpk.to_string());
-        datfile
-            .attributes
-            .insert("offset".into(), settings.offset.to_string());
-        Ok(datfile)
+        if let Some(folder) = env_var::<PathBuf>("PA_OUTPUT_FOLDER") {
+            config.output_folder = Some(folder);
+        }
+        config
     }
-    async fn read_history(&mut self) -> Result<DatFile, anyhow::Error> {
-        let mut path = std::env::temp_dir();
-        let fname = format!(
-            "temp{}.dat",
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_secs()
-        );
-        path.push(fname);
-        self.save_dat(&path).await?;
-        while !path.exists() {
-            tokio::time::sleep(Duration::from_millis(100)).await;
+    // wall-clock time of `aquire_n_waves` without warmup periods, without touching the hardware
+    pub fn estimate_duration(&self, settings: WavegenSettings, n: usize) -> Duration {
+        let capture = self.capture_duration(settings, n);
+        let windows = u32::try_from(self.window_count(capture)).unwrap_or(u32::MAX);
+        self.warmup
+            .saturating_add(capture)
+            .saturating_add(self.window_save_cost.saturating_mul(windows))
+    }
+    // what the WaveForms field is sent for a setting, with amplitude and offset in wavegen volts
+    fn field_value(&self, field: WavegenField, value: f64) -> f64 {
+        let value = match field {
+            WavegenField::Amplitude | WavegenField::Offset => value / self.wavegen_gain / 2.,
+            WavegenField::Period | WavegenField::Symmetry => value,
+        };
+        round_significant(value, self.wavegen_digits)
+    }
+    fn setting_value(&self, field: WavegenField, field_value: f64) -> f64 {
+        match field {
+            WavegenField::Amplitude | WavegenField::Offset => field_value * self.wavegen_gain * 2.,
+            WavegenField::Period | WavegenField::Symmetry => field_value,
         }
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        let new_datfile = DatFile::read_from_file(&path)?;
-        std::fs::remove_file(path)?;
-        Ok(new_datfile)
     }
-    pub async fn start_wavegen(&self) -> Result<()> {
-        self.focus_window("WaveForms (new workspace)").await?;
-        if !self.pa.wavegen_is_running().await? {
-            self.pa.wavegen_toggle_running().await?;
+    // `value` as the wavegen holds it once sent, which is what the driver caches
+    fn sent_value(&self, field: WavegenField, value: f64) -> f64 {
+        self.setting_value(field, self.field_value(field, value))
+    }
+    pub fn sent_settings(&self, settings: WavegenSettings) -> Result<WavegenSettings> {
+        let period = self.sent_value(WavegenField::Period, settings.period.as_secs_f64());
+        Ok(WavegenSettings {
+            pkpk: self.sent_value(WavegenField::Amplitude, settings.pkpk),
+            period: Duration::try_from_secs_f64(period)
+                .with_context(|| format!("{period} s is not a valid period"))?,
+            symmetry_p: self.sent_value(WavegenField::Symmetry, settings.symmetry_p),
+            offset: self.sent_value(WavegenField::Offset, settings.offset),
+        })
+    }
+    // largest pkpk that keeps the wavegen within its output range at `offset`
+    pub fn max_pkpk(&self, offset: f64) -> f64 {
+        (2. * self.wavegen_gain * self.max_output_v - offset.abs()).max(0.)
+    }
+    // WaveForms clamps anything past its output range without complaint
+    pub fn check_output_range(&self, pkpk: f64, offset: f64) -> Result<()> {
+        let gain = self.wavegen_gain;
+        let peak = (pkpk / gain / 2.).abs() + (offset / gain / 2.).abs();
+        let max = self.max_output_v;
+        if peak > max * (1. + SETTINGS_TOLERANCE) {
+            bail!(
+                "{pkpk} V pkpk at a {offset} V offset needs the wavegen to reach {peak:.3} V, \
+                 beyond its ±{max} V output"
+            );
         }
         Ok(())
     }
-    pub async fn stop_wavegen(&self) -> Result<()> {
-        self.focus_window("WaveForms (new workspace)").await?;
-        if self.pa.wavegen_is_running().await? {
-            self.pa.wavegen_toggle_running().await?;
+    // history windows read by `aquire_n_waves`
+    pub fn history_windows(&self, settings: WavegenSettings, n: usize) -> usize {
+        self.window_count(self.capture_duration(settings, n))
+    }
+    // `n` periods plus the one trimmed off, and the buffer read past the end
+    fn capture_duration(&self, settings: WavegenSettings, n: usize) -> Duration {
+        periods(settings.period, n + 1)
+            .unwrap_or(Duration::MAX)
+            .saturating_add(self.window_buffer)
+    }
+    // the first read waits out a full history window, later reads come a buffer early so the
+    // windows overlap, and the last read is at the end of the buffered capture
+    fn window_count(&self, total: Duration) -> usize {
+        let window = self.history_window.as_secs_f64();
+        let after_first = (total.as_secs_f64() - window).max(0.);
+        1 + (after_first / (window - self.window_buffer.as_secs_f64())).ceil() as usize
+    }
+    // time between window reads, see `window_count`
+    fn window_stride(&self) -> Duration {
+        self.history_window.saturating_sub(self.window_buffer)
+    }
+}
+
+fn env_var<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    let value = match std::env::var(name) {
+        Ok(value) => value,
+        Err(VarError::NotPresent) => return None,
+        Err(e) => {
+            eprintln!("WARNING: ignoring {name}: {e}");
+            return None;
+        }
+    };
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            eprintln!("WARNING: ignoring {name}=`{value}`: {e}");
+            None
         }
-        Ok(())
     }
-    pub async fn set_wavegen_pkpk(&mut self, pkpk: f64) -> Result<()> {
-        if self.pkpk != Some(pkpk) {
-            self.pa
-                .wavegen_set_amplitude(pkpk / WAVEGEN_GAIN / 2.)
-                .await?;
-            self.pkpk = Some(pkpk);
+}
+
+fn env_secs(name: &str) -> Option<Duration> {
+    let secs = env_var::<f64>(name)?;
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if !duration.is_zero() => Some(duration),
+        _ => {
+            eprintln!("WARNING: ignoring {name}={secs}, it must be a positive number of seconds");
+            None
         }
-        Ok(())
     }
-    pub async fn set_wavegen_period(&mut self, period: Duration) -> Result<()> {
-        if self.period != Some(period) {
-            self.pa.wavegen_set_period(period.as_secs_f64()).await?;
-            self.period = Some(period);
+}
+impl Default for DriverConfig {
+    fn default() -> Self {
+        Self {
+            decimate: None,
+            clip_limits: Default::default(),
+            max_clip_fraction: None,
+            offset_settle_time: Duration::ZERO,
+            warmup: Duration::ZERO,
+            verify_settings: false,
+            verify_retries: 0,
+            align_start: false,
+            verify_output: false,
+            output_tolerance: 0.1,
+            amplitude_correction: None,
+            pipeline_settings: true,
+            transport: Transport::Polling,
+            voltage_monitor_scale: 1.,
+            calibration: ChannelCalibration::default(),
+            max_seam_dedup: Duration::from_secs_f64(NANONIS_WINDOW_BUFFER_S),
+            max_duration: Some(Duration::from_secs(2 * 60 * 60)),
+            window_save_cost: Duration::from_secs(2),
+            max_output_v: 5.,
+            waveforms_path: Some(r"C:\Program Files\Digilent\WaveForms3\WaveForms.exe".into()),
+            check_history_each_point: false,
+            max_recoveries: 2,
+            max_window_retries: 2,
+            window_failure: WindowFailure::Abort,
+            bind_addr: DEFAULT_BIND_ADDR.parse().unwrap(),
+            wavegen_gain: WAVEGEN_GAIN,
+            history_window: Duration::from_secs_f64(NANONIS_WINDOW_S),
+            window_buffer: Duration::from_secs_f64(NANONIS_WINDOW_BUFFER_S),
+            output_folder: None,
+            wavegen_digits: 6,
+            fail_if_acquiring: false,
+            scratch_dir: scratch::default_dir(),
+            scratch_max_age: scratch::DEFAULT_MAX_AGE,
+            session_file: Some(session::default_path()),
+            reset_session: false,
         }
-        Ok(())
     }
-    pub async fn set_wavegen_offset(&mut self, offset: f64) -> Result<()> {
-        if self.offset != Some(offset) {
-            self.pa
-                .wavegen_set_offset(offset / WAVEGEN_GAIN / 2.)
-                .await?;
-            self.offset = Some(offset);
+}
+
+// the amplifier's gain is never exactly `wavegen_gain` and droops under load, so the pkpk on the
+// voltage monitor is measured and the wavegen amplitude scaled by the error until the two agree
+// within `tolerance`, a fraction of the requested pkpk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmplitudeCorrection {
+    pub max_iterations: usize,
+    pub tolerance: f64,
+}
+impl Default for AmplitudeCorrection {
+    fn default() -> Self {
+        Self {
+            max_iterations: 3,
+            tolerance: 0.01,
         }
-        Ok(())
     }
-    pub async fn set_wavegen_symmetry(&mut self, symmetry: f64) -> Result<()> {
-        if self.symmetry != Some(symmetry) {
-            self.pa.wavegen_set_symmetry(symmetry).await?;
-            self.symmetry = Some(symmetry);
+}
+impl AmplitudeCorrection {
+    // what follows a measurement of `corrected`: scaling the command by the error, capped at
+    // `max_pkpk`, until it is within tolerance or can't get any closer
+    fn step(&self, target: f64, max_pkpk: f64, corrected: CorrectedAmplitude) -> CorrectionStep {
+        let CorrectedAmplitude {
+            commanded,
+            achieved,
+            iterations,
+        } = corrected;
+        if (achieved - target).abs() <= target.abs() * self.tolerance {
+            return CorrectionStep::Done;
+        }
+        if iterations == self.max_iterations || achieved <= 0. {
+            return CorrectionStep::Stop(format!(
+                "amplitude correction reached {achieved:.3} V pkpk of {target:.3} V \
+                 after {iterations} iterations"
+            ));
+        }
+        let next = commanded * target / achieved;
+        if next <= max_pkpk {
+            CorrectionStep::Command(next)
+        } else if commanded < max_pkpk {
+            CorrectionStep::Command(max_pkpk)
+        } else {
+            CorrectionStep::Stop(format!(
+                "amplitude correction stopped at the {max_pkpk:.3} V pkpk output limit, \
+                 reaching {achieved:.3} V of {target:.3} V"
+            ))
         }
-        Ok(())
     }
-    pub async fn apply_wavegen_settings(&mut self, settings: WavegenSettings) -> Result<()> {
-        self.set_wavegen_pkpk(settings.pkpk).await?;
-        self.set_wavegen_period(settings.period).await?;
-        self.set_wavegen_offset(settings.offset).await?;
-        self.set_wavegen_symmetry(settings.symmetry_p).await?;
-        Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum CorrectionStep {
+    Done,
+    // gave up short of the tolerance, with the warning to raise
+    Stop(String),
+    Command(f64),
+}
+
+// the outcome of an amplitude correction, with `commanded` in drive volts at the nominal gain
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CorrectedAmplitude {
+    commanded: f64,
+    achieved: f64,
+    iterations: usize,
+}
+impl CorrectedAmplitude {
+    fn record(&self, attributes: &mut BTreeMap<String, String>) {
+        let mut attrs = AcqAttributes(attributes);
+        attrs.set_f64("commanded_pkpk", self.commanded);
+        attrs.set_f64("achieved_pkpk", self.achieved);
+        attrs.set_str("amplitude_iterations", self.iterations.to_string());
     }
-    pub async fn save_dat(&self, path: impl AsRef<Path>) -> Result<()> {
-        let fname = path.as_ref().file_name().unwrap().to_str().unwrap();
-        let folder = path.as_ref().parent().unwrap().to_str().unwrap();
-        self.with_window("History", self.pa.nanonis_save_history(folder, fname))
-            .await?;
-        Ok(())
+}
+
+// what a multi-window acquisition does with a history window that can't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WindowFailure {
+    // fails the whole acquisition
+    #[default]
+    Abort,
+    // leaves a gap in the record and carries on with the next window
+    Skip,
+}
+
+// which of the samples recorded past the requested duration are removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrimPolicy {
+    // keeps the end of the record
+    #[default]
+    LeadingExcess,
+    // keeps the start of the record, for transients right after the wavegen starts
+    TrailingExcess,
+    // trims the leading excess, then cuts the record down to whole periods from `align`
+    WholePeriods {
+        align: PhaseAlign,
+    },
+    // keeps everything, including the buffer read past the end
+    None,
+}
+impl Display for TrimPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrimPolicy::LeadingExcess => write!(f, "leading_excess"),
+            TrimPolicy::TrailingExcess => write!(f, "trailing_excess"),
+            TrimPolicy::WholePeriods { align } => write!(f, "whole_periods({align})"),
+            TrimPolicy::None => write!(f, "none"),
+        }
     }
-    pub async fn focus_window(&self, window: &str) -> Result<()> {
-        let focused = self.pa.get_open_window().await?;
-        if focused != window {
-            self.pa.focus_window(window, "").await?;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PhaseAlign {
+    // periods are counted from wherever the record starts
+    #[default]
+    Start,
+    // the record starts on a rising crossing of the voltage monitor, as with `align_start`
+    RisingCrossing,
+}
+impl Display for PhaseAlign {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhaseAlign::Start => write!(f, "start"),
+            PhaseAlign::RisingCrossing => write!(f, "rising_crossing"),
         }
-        Ok(())
     }
-    pub async fn with_window<T>(
-        &self,
-        window: &str,
-        f: impl Future<Output = Result<T>>,
-    ) -> Result<T> {
-        let open_window = self.pa.get_open_window().await?;
-        self.focus_window(window).await?;
-        let res = f.await;
-        self.focus_window(&open_window).await?;
-        res
+}
+
+// normalized to -1..1, the custom samples are scaled by the amplitude and offset like the trapezium
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Waveform {
+    #[default]
+    Trapezium,
+    Custom(Vec<f64>),
+}
+impl Waveform {
+    fn id(&self) -> WaveformId {
+        match self {
+            Waveform::Trapezium => WaveformId::Trapezium,
+            Waveform::Custom(samples) => WaveformId::Custom {
+                len: samples.len(),
+                checksum: crc32fast::hash(
+                    &samples.iter().flat_map(|s| s.to_le_bytes()).collect_vec(),
+                ),
+            },
+        }
     }
-    pub async fn new() -> Result<Self> {
-        unsafe {
-            if PA_SERVER.is_none() {
-                PA_SERVER = Some(Rc::new(PowerAutomate::new()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WaveformId {
+    Trapezium,
+    Custom { len: usize, checksum: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WavegenField {
+    Amplitude,
+    Period,
+    Offset,
+    Symmetry,
+}
+
+// `value` with only `digits` significant digits, e.g. 1.54320000000000001 to 1.5432
+fn round_significant(value: f64, digits: usize) -> f64 {
+    if value == 0. || !value.is_finite() {
+        return value;
+    }
+    format!("{:.*e}", digits.saturating_sub(1), value)
+        .parse()
+        .unwrap_or(value)
+}
+
+// the WaveForms fields round what they're given, so allow for the lost digits
+fn settings_match(commanded: f64, observed: f64) -> bool {
+    (commanded - observed).abs() <= commanded.abs() * SETTINGS_TOLERANCE + 1e-6
+}
+
+// where the device no longer matches what the last session commanded
+fn session_discrepancies(
+    saved: &SessionState,
+    device: WavegenSettings,
+    running: bool,
+) -> Vec<String> {
+    let mut messages = vec![];
+    if let Some(settings) = saved.settings {
+        let fields = [
+            ("pkpk", settings.pkpk, device.pkpk),
+            (
+                "period",
+                settings.period.as_secs_f64(),
+                device.period.as_secs_f64(),
+            ),
+            ("symmetry", settings.symmetry_p, device.symmetry_p),
+            ("offset", settings.offset, device.offset),
+        ];
+        for (name, commanded, observed) in fields {
+            if !settings_match(commanded, observed) {
+                messages.push(format!(
+                    "the last session set the {name} to {commanded}, but WaveForms reads {observed}"
+                ));
             }
         }
-        let self_ = Self {
-            pa: unsafe { PA_SERVER.as_ref() }.unwrap().clone(),
-            pkpk: None,
-            period: None,
-            offset: None,
-            symmetry: None,
-        };
-        if !self_
-            .pa
-            .is_window_open("WaveForms (new workspace)", "")
-            .await?
-        {
-            bail!("Waveforms is not open")
-        };
-        self_.pa.wavegen_set_trapezium().await?;
-        Ok(self_)
     }
+    if let Some(was_running) = saved.wavegen_running.filter(|r| *r != running) {
+        let state = |running| if running { "running" } else { "stopped" };
+        messages.push(format!(
+            "the last session left the wavegen {}, but it is {}",
+            state(was_running),
+            state(running)
+        ));
+    }
+    messages
 }
 
-fn combine_datfiles(mut a: DatFile, b: DatFile) -> DatFile {
-    assert_eq!(
-        a.signals.keys().collect_vec(),
-        b.signals.keys().collect_vec()
-    );
-    let index = a
-        .signals
-        .values()
-        .map(|s| *s.last().unwrap())
-        .zip(b.signals.values())
-        .map(|(m, v)| {
-            v.iter()
-                .positions(|f| *f == m)
-                .flat_map(|p| [p, p + 1, p + 2])
-                .collect::<BTreeSet<_>>()
-        })
-        .reduce(|a, b| BTreeSet::intersection(&a, &b).cloned().collect())
-        .unwrap()
-        .into_iter()
-        .next()
-        .unwrap();
-    for (key, sig) in a.signals.iter_mut() {
-        sig.extend(b.signals[key].iter().skip(index + 1));
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AcquisitionInfo {
+    // history windows read into the record, not counting skipped ones
+    pub windows: usize,
+    pub skipped_windows: usize,
+    // windows whose overlap with the one before couldn't be found, joined on their read times
+    pub unmatched_seams: usize,
+    // windows after a skipped one that had no overlap left to join on
+    pub gaps: usize,
+    pub trimmed_samples: usize,
+    pub started_at: DateTime<Local>,
+    pub finished_at: DateTime<Local>,
+    pub retries: usize,
+    pub window_intervals: Vec<Duration>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AcquisitionReport {
+    pub data: DatFile,
+    pub info: AcquisitionInfo,
+}
+
+#[derive(Debug, Clone)]
+pub struct AveragedAcquisition {
+    // every repetition as recorded, including excluded ones
+    pub repeats: Vec<Aquisition>,
+    // mean of the repetitions that were kept, with a standard deviation channel for each
+    pub average: DatFile,
+    pub warnings: Vec<String>,
+}
+
+// extra state shown next to the capture progress bar, shared with whoever is controlling the sweep
+#[derive(Debug, Clone, Default)]
+pub struct ProgressStatus(Rc<RefCell<Option<String>>>);
+impl ProgressStatus {
+    pub fn set(&self, status: Option<String>) {
+        *self.0.borrow_mut() = status;
+    }
+    pub fn get(&self) -> Option<String> {
+        self.0.borrow().clone()
     }
-    a
 }
 
-struct PowerAutomate {
-    _handle: JoinHandle<Result<(), hyper::Error>>,
-    channel_send: mpsc::Sender<(String, oneshot::Sender<String>)>,
+// keeps an error or panic between starting and stopping the wavegen from leaving the actuator driven
+pub struct WavegenGuard {
+    pa: Rc<PowerAutomate>,
+    armed: bool,
 }
-macro_rules! pa_fn {
-    ($name:ident($($arg:ident: $typ:ty),*) -> $res:ty) => {
-        async fn $name(&self, $($arg: $typ),*) -> $res{
-            let command = json!({
-                "command": stringify!($name),
-                $(stringify!($arg): $arg),*
-            });
-            self.execute(&command).await
-        }
-    };
+impl WavegenGuard {
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
 }
-impl PowerAutomate {
-    pa_fn!(wavegen_is_running() -> Result<bool>);
-    pa_fn!(wavegen_toggle_running() -> Result<()>);
-    pa_fn!(wavegen_set_trapezium() -> Result<()>);
-    pa_fn!(wavegen_set_period(period: f64) -> Result<()>);
-    pa_fn!(wavegen_set_amplitude(amplitude: f64) -> Result<()>);
-    pa_fn!(wavegen_set_offset(offset: f64) -> Result<()>);
-    pa_fn!(wavegen_set_symmetry(symmetry: f64) -> Result<()>);
-    pa_fn!(nanonis_save_history(folder: &str, filename: &str) -> Result<()>);
-    pa_fn!(nanonis_open_history() -> Result<()>);
-    pa_fn!(is_window_open(title: &str, class: &str) -> Result<bool>);
-    pa_fn!(get_open_window() -> Result<String>);
-    pa_fn!(focus_window(title: &str, class: &str) -> Result<()>);
-    fn new() -> Self {
-        type ChannelData = (String, oneshot::Sender<String>);
-        struct ServerState {
-            channel_recv: mpsc::Receiver<ChannelData>,
-            oneshot: Option<oneshot::Sender<String>>,
+impl Drop for WavegenGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
         }
-        let (channel_send, channel_recv) = mpsc::channel(1);
-        let shared = Arc::new(Mutex::new(ServerState {
-            channel_recv,
-            oneshot: None,
-        }));
-        let shared_clone = shared.clone();
-        let app = Router::new()
-            .route(
-                "/",
-                get(move || {
-                    let mut state = shared.lock().unwrap();
-                    let a = match state.channel_recv.try_recv() {
-                        Ok((command, oneshot)) => {
-                            state.oneshot = Some(oneshot);
-                            command
-                        }
-                        Err(TryRecvError::Empty) => "".to_string(),
-                        e => unimplemented!("{e:?}"),
-                    };
-                    ready(a)
-                }),
-            )
-            .route(
-                "/",
-                post(move |body: String| {
-                    shared_clone
-                        .lock()
-                        .unwrap()
-                        .oneshot
-                        .take()
-                        .unwrap()
-                        .send(body)
-                        .unwrap();
-                    ready("")
-                }),
-            );
-        let _handle = tokio::spawn(
-            axum::Server::bind(&"127.0.0.1:3000".parse().unwrap()).serve(app.into_make_service()),
-        );
-        Self {
-            _handle,
-            channel_send,
+        // drop can't await, so the stop has to block a worker thread
+        let handle = match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => handle,
+            _ => {
+                eprintln!("WARNING: cannot stop the wavegen outside a multi-threaded runtime");
+                return;
+            }
+        };
+        let pa = self.pa.clone();
+        if let Err(e) = task::block_in_place(|| handle.block_on(pa.stop_wavegen())) {
+            eprintln!("WARNING: failed to stop the wavegen: {e:#}");
         }
     }
-    async fn execute<R: DeserializeOwned>(&self, command: &impl Serialize) -> Result<R> {
-        let command_str = serde_json::to_string(command).unwrap();
-        let (send, recv) = oneshot::channel();
-        self.channel_send.send((command_str, send)).await.unwrap();
-        let resp = recv.await.unwrap();
-        let patched = url_escape::decode(&resp)
-            .replace("+", " ")
-            .replace("\r\n", "\\n")
-            .replace("False", "false")
-            .replace("True", "true");
-        // println!("{}: {patched:?}", serde_json::to_string(command).unwrap());
-        serde_json::from_str::<Result<_, ServerError>>(&patched)
-            .unwrap()
-            .context("Power automate returned an error")
-    }
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize, thiserror::Error)]
-#[error("{0}")]
-pub struct ServerError(String);
+// a window stayed closed after every recovery attempt allowed for the point
+#[derive(Debug, thiserror::Error)]
+#[error("`{window}` could not be reopened after {attempts} attempts")]
+pub struct RecoveryFailed {
+    pub window: String,
+    pub attempts: usize,
+}
+
+// another acquisition held the bridge, with `DriverConfig::fail_if_acquiring` set
+#[derive(Debug, thiserror::Error)]
+#[error("another acquisition is already in progress")]
+pub struct AlreadyAcquiring;
+
+// kept until a streamed capture is dropped, so a failure on the way stops the wavegen
+struct Held {
+    _lock: OwnedMutexGuard<()>,
+    guard: WavegenGuard,
+}
+
+// what `start_capture` did before the first window was read
+struct Capture {
+    started_at: DateTime<Local>,
+    corrected: Option<CorrectedAmplitude>,
+    retries: usize,
+    warnings: Vec<String>,
+}
+
+struct Recording {
+    datfile: DatFile,
+    stats: WindowStats,
+    last_read: Instant,
+    stepped_at: Option<Instant>,
+    // samples into the record at which the settle criterion first passed
+    settled_at: Option<usize>,
+}
+
+pub struct AquisitionDriver {
+    pub config: DriverConfig,
+    pa: Rc<PowerAutomate>,
+    pkpk: Option<f64>,
+    period: Option<Duration>,
+    offset: Option<f64>,
+    symmetry: Option<f64>,
+    waveform: Option<WaveformId>,
+    custom_max_samples: Option<usize>,
+    events: Option<EventLog>,
+    progress: MultiProgress,
+    progress_status: ProgressStatus,
+    recoveries: Cell<usize>,
+    session: RefCell<SessionState>,
+}
+impl AquisitionDriver {
+    pub async fn aquire_n_waves(
+        &mut self,
+        settings: WavegenSettings,
+        n: usize,
+        warmup_periods: usize,
+        discard_first_period: bool,
+    ) -> Result<DatFile> {
+        Ok(self
+            .aquire_n_waves_report(settings, n, warmup_periods, discard_first_period)
+            .await?
+            .data)
+    }
+    // repetitions that are flat, clipped or can't be aligned to the first good one are left
+    // out of the average with a warning
+    pub async fn aquire_n_waves_averaged(
+        &mut self,
+        settings: WavegenSettings,
+        n_waves: usize,
+        repeats: usize,
+        rest: Option<RestPolicy>,
+    ) -> Result<AveragedAcquisition> {
+        // held across the rests too, so another driver can't move the offset in between
+        let lock = self.lock_acquisition().await?;
+        let mut warnings = vec![];
+        let mut recorded = vec![];
+        let mut candidates = vec![];
+        for i in 0..repeats {
+            if let Some(rest) = rest.filter(|rest| i > 0 || rest.before_first) {
+                self.rest(rest.hold_voltage, rest.duration).await?;
+            }
+            let datfile = self
+                .capture_n_waves(&lock, settings, n_waves, 0, false)
+                .await?
+                .data;
+            let aq = Aquisition::from_datfile(&datfile)?;
+            recorded.push(aq.clone());
+            if rising_crossing(aq.channel(Channel::Voltage)).is_none() {
+                warn(
+                    &mut warnings,
+                    format!("Excluding repetition {}: voltage is flat", i + 1),
+                );
+            } else if let Some(r) = aq
+                .detect_clipping(&self.config.clip_limits)
+                .into_iter()
+                .find(|r| r.fraction > 0.)
+            {
+                let message = format!("Excluding repetition {}: `{}` clipped", i + 1, r.channel);
+                warn(&mut warnings, message);
+            } else {
+                candidates.push((i, datfile, aq));
+            }
+        }
+        let len = candidates.iter().map(|(_, _, aq)| aq.len()).min();
+        let (Some(len), Some((_, reference_file, _))) = (len, candidates.first()) else {
+            bail!("All {repeats} repetitions were excluded");
+        };
+        let mut average = reference_file.clone();
+        let truncate = |mut aq: Aquisition| {
+            for channel in Channel::ALL {
+                aq.channel_mut(channel).truncate(len);
+            }
+            aq
+        };
+        let reference = truncate(candidates[0].2.clone());
+        let mut aligned = vec![];
+        for (i, _, aq) in candidates {
+            match truncate(aq).align_phase(&reference) {
+                Ok(aq) => aligned.push(aq),
+                Err(e) => warn(
+                    &mut warnings,
+                    format!("Excluding repetition {}: {e:#}", i + 1),
+                ),
+            }
+        }
+        let (mean, std) = average_aquisitions(&aligned)?;
+        average.signals.clear();
+        for channel in Channel::ALL {
+            let label = mean.label(channel);
+            average
+                .signals
+                .insert(label.to_string(), mean.channel(channel).to_vec());
+            average
+                .signals
+                .insert(std_label(label), std[&channel].clone());
+        }
+        average
+            .attributes
+            .insert("repeats".into(), aligned.len().to_string());
+        average
+            .attributes
+            .insert("repeats_requested".into(), repeats.to_string());
+        Ok(AveragedAcquisition {
+            repeats: recorded,
+            average,
+            warnings,
+        })
+    }
+    // skips the capture when the file already exists, returning the written path otherwise
+    pub async fn aquire_and_save(
+        &mut self,
+        settings: WavegenSettings,
+        n: usize,
+        folder: &Path,
+    ) -> Result<Option<PathBuf>> {
+        let path = folder.join(settings.to_filename());
+        if path.exists() {
+            return Ok(None);
+        }
+        let lock = self.lock_acquisition().await?;
+        let datfile = self
+            .capture_n_waves(&lock, settings, n, 0, false)
+            .await?
+            .data;
+        let file = File::create(&path)
+            .with_context(|| format!("Could not create `{}`", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        datfile.write_to(&mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        Aquisition::from_datfile(&datfile)?
+            .verify_file(&path)
+            .context("The saved file did not read back")?;
+        Ok(Some(path))
+    }
+    // `n + 1` periods are captured so the first can be thrown away as the transient,
+    // which `discard_first_period` does before returning
+    pub async fn aquire_n_waves_report(
+        &mut self,
+        settings: WavegenSettings,
+        n: usize,
+        warmup_periods: usize,
+        discard_first_period: bool,
+    ) -> Result<AcquisitionReport> {
+        let lock = self.lock_acquisition().await?;
+        self.capture_n_waves(&lock, settings, n, warmup_periods, discard_first_period)
+            .await
+    }
+    // the warm-up runs under `lock` as well, so nothing else can drive the wavegen between it
+    // and the capture
+    async fn capture_n_waves(
+        &mut self,
+        lock: &OwnedMutexGuard<()>,
+        settings: WavegenSettings,
+        n: usize,
+        warmup_periods: usize,
+        discard_first_period: bool,
+    ) -> Result<AcquisitionReport> {
+        let duration = periods(settings.period, n + 1)?;
+        self.check_duration(duration)?;
+        let guard = self.wavegen_guard();
+        if warmup_periods > 0 {
+            self.warm_up(settings, warmup_periods).await?;
+        }
+        let mut report = self
+            .capture_duration(lock, settings, duration, TrimPolicy::LeadingExcess)
+            .await?;
+        report
+            .data
+            .attributes
+            .insert("warmup_periods".into(), warmup_periods.to_string());
+        let discarded = if discard_first_period {
+            discard_leading(&mut report.data, settings.period)?;
+            1
+        } else {
+            0
+        };
+        report
+            .data
+            .attributes
+            .insert("discarded_periods".into(), discarded.to_string());
+        guard.disarm();
+        Ok(report)
+    }
+    pub fn estimate_duration(&self, settings: WavegenSettings, n: usize) -> Duration {
+        self.config.estimate_duration(settings, n)
+    }
+    // drives `settings` for `n` periods, as `aquire_n_waves` does before its capture
+    pub async fn warm_up(&mut self, settings: WavegenSettings, n: usize) -> Result<()> {
+        let duration = periods(settings.period, n)?;
+        self.drive(settings).await?;
+        wait_with_progress(&self.progress, duration, "warming up".into()).await
+    }
+    // leaves the wavegen running at `settings` without capturing anything
+    pub async fn drive(&mut self, settings: WavegenSettings) -> Result<()> {
+        self.apply_wavegen_settings(settings).await?;
+        self.start_wavegen().await
+    }
+    pub async fn hold_for(&mut self, settings: WavegenSettings, duration: Duration) -> Result<()> {
+        self.drive(settings).await?;
+        wait_with_progress(&self.progress, duration, "holding".into()).await
+    }
+    pub async fn rest(&mut self, hold_voltage: f64, duration: Duration) -> Result<()> {
+        self.stop_wavegen().await?;
+        self.set_wavegen_offset(hold_voltage).await?;
+        let message = format!("resting at {hold_voltage} V");
+        wait_with_progress(&self.progress, duration, message).await
+    }
+    pub async fn aquire_offset_sweep(
+        &mut self,
+        base: WavegenSettings,
+        offsets: &[f64],
+        samples: usize,
+    ) -> Result<Vec<(f64, DatFile)>> {
+        let lock = self.lock_acquisition().await?;
+        let mut results = vec![];
+        let guard = self.wavegen_guard();
+        for &offset in offsets {
+            let settings = WavegenSettings { offset, ..base };
+            if self.offset != Some(offset) {
+                self.set_wavegen_offset(offset).await?;
+                tokio::time::sleep(self.config.offset_settle_time).await;
+            }
+            let report = self
+                .capture_n_waves(&lock, settings, samples, 0, false)
+                .await?;
+            results.push((offset, report.data));
+        }
+        guard.disarm();
+        Ok(results)
+    }
+    // holds `from_v`, steps straight to `to_v` after `pre_duration` and keeps recording for
+    // `post_duration`. where the step was commanded and observed, and how long the rise took, are
+    // in the `step_*` attributes
+    pub async fn aquire_step(
+        &mut self,
+        from_v: f64,
+        to_v: f64,
+        pre_duration: Duration,
+        post_duration: Duration,
+    ) -> Result<AcquisitionReport> {
+        self.check_duration(pre_duration + post_duration)?;
+        let _lock = self.lock_acquisition().await?;
+        let started_at = Local::now();
+        let mut warnings = vec![];
+        let guard = self.wavegen_guard();
+        self.set_wavegen_pkpk(0.).await?;
+        self.set_wavegen_offset(from_v).await?;
+        self.start_wavegen().await?;
+        tokio::time::sleep(self.config.offset_settle_time).await;
+        let windows = self.windows(
+            pre_duration + post_duration,
+            Some((pre_duration, to_v)),
+            None,
+        )?;
+        let recording = stitch_windows(windows, None).await?;
+        let mut datfile = recording.datfile;
+        record_window_gaps(
+            &self.config,
+            &mut datfile.attributes,
+            &recording.stats,
+            &mut warnings,
+        );
+        let stepped_at = recording
+            .stepped_at
+            .context("Recording ended before the step was applied")?;
+
+        let signal_len = datfile
+            .signals
+            .values()
+            .next()
+            .context("No channel was recorded in every history window")?
+            .len();
+        let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+        let to_samples = |d: Duration| (d.as_secs_f64() * 1000. / sample_period) as usize;
+        let since_step = recording.last_read.saturating_duration_since(stepped_at);
+        let step_abs = signal_len.saturating_sub(to_samples(since_step));
+        let start = step_abs.saturating_sub(to_samples(pre_duration));
+        for sig in datfile.signals.values_mut() {
+            *sig = sig[start..].into();
+        }
+        let step_index = step_abs - start;
+
+        let mut attrs = AcqAttributes(&mut datfile.attributes);
+        attrs.set_f64("step_from_v", from_v);
+        attrs.set_f64("step_to_v", to_v);
+        attrs.set_str("step_index", step_index.to_string());
+        match voltage_signal(&datfile).and_then(|v| step_timing(v, step_index)) {
+            Some((observed, rise_start, rise_end)) => {
+                let delay_s = (observed as f64 - step_index as f64) * sample_period / 1000.;
+                let transition_s = (rise_end - rise_start) as f64 * sample_period / 1000.;
+                let mut attrs = AcqAttributes(&mut datfile.attributes);
+                attrs.set_str("step_observed_index", observed.to_string());
+                attrs.set_f64("step_delay_s", delay_s);
+                attrs.set_f64("step_transition_s", transition_s);
+            }
+            None => warn(
+                &mut warnings,
+                "the step was not visible on the voltage monitor",
+            ),
+        }
+        self.check_clipping(&mut datfile, &mut warnings)?;
+        if let Some(factor) = self.config.decimate {
+            decimate_datfile(&mut datfile, factor)?;
+        }
+        self.config.calibration.apply(&mut datfile)?;
+        guard.disarm();
+        Ok(AcquisitionReport {
+            data: datfile,
+            info: recording.stats.info(started_at, start, 0, warnings),
+        })
+    }
+    pub async fn aquire_duration(
+        &mut self,
+        settings: WavegenSettings,
+        duration: Duration,
+        trim: TrimPolicy,
+    ) -> Result<DatFile> {
+        Ok(self
+            .aquire_duration_report(settings, duration, trim)
+            .await?
+            .data)
+    }
+    // for analysis that works on channels rather than the raw signals
+    pub async fn aquire_duration_as_aquisition(
+        &mut self,
+        settings: WavegenSettings,
+        duration: Duration,
+        trim: TrimPolicy,
+    ) -> Result<Aquisition> {
+        let datfile = self.aquire_duration(settings, duration, trim).await?;
+        Aquisition::from_datfile(&datfile)
+    }
+    pub async fn aquire_duration_report(
+        &mut self,
+        settings: WavegenSettings,
+        duration: Duration,
+        trim: TrimPolicy,
+    ) -> Result<AcquisitionReport> {
+        self.check_duration(duration)?;
+        let lock = self.lock_acquisition().await?;
+        self.capture_duration(&lock, settings, duration, trim).await
+    }
+    // every capture except `aquire_duration_uncapped` goes through here first
+    fn check_duration(&self, duration: Duration) -> Result<()> {
+        match self.config.max_duration {
+            Some(max) if duration > max => bail!(
+                "requested duration {} exceeds configured maximum {}",
+                format_duration(duration),
+                format_duration(max)
+            ),
+            _ => Ok(()),
+        }
+    }
+    // for legitimately long runs, ignoring `DriverConfig::max_duration`
+    pub async fn aquire_duration_uncapped(
+        &mut self,
+        settings: WavegenSettings,
+        duration: Duration,
+        trim: TrimPolicy,
+    ) -> Result<AcquisitionReport> {
+        let lock = self.lock_acquisition().await?;
+        self.capture_duration(&lock, settings, duration, trim).await
+    }
+    async fn capture_duration(
+        &mut self,
+        lock: &OwnedMutexGuard<()>,
+        settings: WavegenSettings,
+        duration: Duration,
+        trim: TrimPolicy,
+    ) -> Result<AcquisitionReport> {
+        let (guard, capture) = self.start_capture(lock, settings).await?;
+        let Capture {
+            started_at,
+            corrected,
+            retries,
+            mut warnings,
+        } = capture;
+        // the same windows `aquire_stream` yields, stitched into one record
+        let recording = stitch_windows(self.windows(duration, None, None)?, None).await?;
+        let mut datfile = recording.datfile;
+        record_window_gaps(
+            &self.config,
+            &mut datfile.attributes,
+            &recording.stats,
+            &mut warnings,
+        );
+        let trimmed_samples = trim_recording(
+            &mut datfile,
+            trim,
+            duration,
+            settings.period,
+            self.config.align_start,
+            &mut warnings,
+        )?;
+        datfile
+            .attributes
+            .extend(self.capture_attributes(settings, corrected.as_ref()));
+        self.check_clipping(&mut datfile, &mut warnings)?;
+        if let Some(factor) = self.config.decimate {
+            decimate_datfile(&mut datfile, factor)?;
+        }
+        self.config.calibration.apply(&mut datfile)?;
+        guard.disarm();
+        Ok(AcquisitionReport {
+            data: datfile,
+            info: recording
+                .stats
+                .info(started_at, trimmed_samples, retries, warnings),
+        })
+    }
+    // records until `criterion` passes on the stitched data, checked after every history window,
+    // or until `max_duration` plus a buffer has been recorded. nothing is trimmed, so the record
+    // runs to the end of the window the response settled in
+    pub async fn aquire_until_settled(
+        &mut self,
+        settings: WavegenSettings,
+        max_duration: Duration,
+        criterion: SettleCriterion,
+    ) -> Result<AcquisitionReport> {
+        self.check_duration(max_duration)?;
+        let lock = self.lock_acquisition().await?;
+        let (guard, capture) = self.start_capture(&lock, settings).await?;
+        let Capture {
+            started_at,
+            corrected,
+            retries,
+            mut warnings,
+        } = capture;
+        let calibration = self.config.calibration.clone();
+        let windows = self.windows(max_duration, None, None)?;
+        let recording = stitch_windows(windows, Some((&criterion, &calibration))).await?;
+        let mut datfile = recording.datfile;
+        record_window_gaps(
+            &self.config,
+            &mut datfile.attributes,
+            &recording.stats,
+            &mut warnings,
+        );
+        let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+        datfile
+            .attributes
+            .extend(self.capture_attributes(settings, corrected.as_ref()));
+        let mut attrs = AcqAttributes(&mut datfile.attributes);
+        attrs.set_str("settle_criterion", criterion.to_string());
+        attrs.set_str("settled", recording.settled_at.is_some().to_string());
+        match recording.settled_at {
+            Some(at) => {
+                let settled_s = at as f64 * sample_period / 1000.;
+                println!("Settled after {settled_s:.1} s");
+                attrs.set_f64("settled_at_s", settled_s);
+            }
+            None => warn(
+                &mut warnings,
+                format!(
+                    "the response had not settled ({criterion}) after {}",
+                    format_duration(max_duration)
+                ),
+            ),
+        }
+        self.check_clipping(&mut datfile, &mut warnings)?;
+        if let Some(factor) = self.config.decimate {
+            decimate_datfile(&mut datfile, factor)?;
+        }
+        self.config.calibration.apply(&mut datfile)?;
+        guard.disarm();
+        Ok(AcquisitionReport {
+            data: datfile,
+            info: recording.stats.info(started_at, 0, retries, warnings),
+        })
+    }
+    // the setup shared by every capture of a wavegen setting: drive it, wait out the warmup, then
+    // correct the amplitude and check the output where configured. the caller takes the lock at
+    // its entry point, before anything it does to the wavegen, and keeps it past the record
+    async fn start_capture(
+        &mut self,
+        _lock: &OwnedMutexGuard<()>,
+        settings: WavegenSettings,
+    ) -> Result<(WavegenGuard, Capture)> {
+        let started_at = Local::now();
+        let mut warnings = vec![];
+        let guard = self.wavegen_guard();
+        self.apply_wavegen_settings(settings).await?;
+        self.start_wavegen().await?;
+        self.wait_warmup().await?;
+        let corrected = self.correct_amplitude(settings, &mut warnings).await?;
+        let mut retries = 0;
+        if self.config.verify_output {
+            retries += self.verify_output(settings, &mut warnings).await?;
+        }
+        let capture = Capture {
+            started_at,
+            corrected,
+            retries,
+            warnings,
+        };
+        Ok((guard, capture))
+    }
+    // what every capture of `settings` is recorded with, whether or not it is trimmed
+    fn capture_attributes(
+        &self,
+        settings: WavegenSettings,
+        corrected: Option<&CorrectedAmplitude>,
+    ) -> BTreeMap<String, String> {
+        let mut attributes = BTreeMap::new();
+        let mut attrs = AcqAttributes(&mut attributes);
+        attrs.set_settings(settings);
+        attrs.set_gain(self.config.wavegen_gain);
+        attrs.set_f64("warmup_s", self.config.warmup.as_secs_f64());
+        if let Some(WaveformId::Custom { len, checksum }) = self.waveform {
+            attrs.set_str("custom_waveform_len", len.to_string());
+            attrs.set_str("custom_waveform_crc32", format!("{checksum:08x}"));
+        }
+        if let Some(corrected) = corrected {
+            corrected.record(&mut attributes);
+        }
+        attributes
+    }
+    // history windows until `duration` (plus a buffer) has been recorded, optionally moving the
+    // offset to a new voltage part way through. `held` is kept until the stream is dropped, with
+    // its guard disarmed once the last window has been read
+    fn windows(
+        &mut self,
+        duration: Duration,
+        step: Option<(Duration, f64)>,
+        held: Option<Held>,
+    ) -> Result<impl Stream<Item = Result<Window>> + '_> {
+        let reader = WindowReader::new(self, duration, step)?;
+        Ok(stream::try_unfold(
+            (reader, held),
+            |(mut reader, held)| async move {
+                match reader.next_window().await? {
+                    Some(window) => Ok(Some((window, (reader, held)))),
+                    None => {
+                        if let Some(held) = held {
+                            held.guard.disarm();
+                        }
+                        Ok(None)
+                    }
+                }
+            },
+        ))
+    }
+    // yields each history window as it is read, with the overlap onto the previous one removed.
+    // the stream holds the bridge and the wavegen guard, so an error or a stream abandoned part
+    // way stops the wavegen, while one read to the end leaves it running
+    pub async fn aquire_stream(
+        &mut self,
+        settings: WavegenSettings,
+        duration: Duration,
+    ) -> Result<impl Stream<Item = Result<DatFile>> + '_> {
+        self.check_duration(duration)?;
+        let lock = self.lock_acquisition().await?;
+        let (guard, _) = self.start_capture(&lock, settings).await?;
+        let held = Held { _lock: lock, guard };
+        Ok(self
+            .windows(duration, None, Some(held))?
+            .try_filter_map(|window| future::ready(Ok(window.datfile))))
+    }
+    // streams each window to `path` as it is read, gzipped if the name ends in `.gz`, so a crash
+    // loses at most the window in flight. each window is checked for clipping and calibrated on
+    // the way in, and once the last is written the header is rewritten with the record's
+    // attributes and the rows cut as `trim` says. whole periods need the whole record, so only
+    // the excess can be trimmed, and nothing is decimated since windows don't split into bins
+    pub async fn aquire_duration_to_file(
+        &mut self,
+        settings: WavegenSettings,
+        duration: Duration,
+        trim: TrimPolicy,
+        path: impl AsRef<Path>,
+    ) -> Result<AcquisitionInfo> {
+        let path = path.as_ref();
+        if let TrimPolicy::WholePeriods { .. } = trim {
+            bail!("Whole periods can't be trimmed while streaming to a file");
+        }
+        self.check_duration(duration)?;
+        let lock = self.lock_acquisition().await?;
+        let mut writer = AquisitionWriter::create(path)?;
+        let (guard, capture) = self.start_capture(&lock, settings).await?;
+        let Capture {
+            started_at,
+            corrected,
+            retries,
+            mut warnings,
+        } = capture;
+        let mut attributes = self.capture_attributes(settings, corrected.as_ref());
+        attributes.insert("trim_policy".into(), trim.to_string());
+        let calibration = self.config.calibration.clone();
+        let limits = self.config.clip_limits.clone();
+        // the clipped samples and ranges of each channel over the whole record
+        let mut clipped = BTreeMap::<String, ClipReport>::new();
+        let mut stats = WindowStats::default();
+        let mut sample_period = None;
+        {
+            let windows = self.windows(duration, None, None)?;
+            tokio::pin!(windows);
+            while let Some(window) = windows.try_next().await? {
+                let Some(mut datfile) = stats.add(window) else {
+                    continue;
+                };
+                let period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+                sample_period = Some(period);
+                let start_s = writer.rows() as f64 * period / 1000.;
+                let len = datfile.signals.values().next().map_or(0, Vec::len);
+                for report in clip_reports(&datfile, &limits, period) {
+                    let total =
+                        clipped
+                            .entry(report.channel.clone())
+                            .or_insert_with(|| ClipReport {
+                                channel: report.channel.clone(),
+                                fraction: 0.,
+                                ranges_s: vec![],
+                            });
+                    // a count of clipped samples until every window is in
+                    total.fraction += report.fraction * len as f64;
+                    let ranges = report.ranges_s.iter();
+                    total
+                        .ranges_s
+                        .extend(ranges.map(|(from, to)| (from + start_s, to + start_s)));
+                }
+                datfile.attributes.extend(attributes.clone());
+                calibration.apply(&mut datfile)?;
+                writer.append(&Aquisition::from_datfile(&datfile)?)?;
+            }
+        }
+        let rows = writer.rows();
+        writer.finish()?.close()?;
+        if stats.read == 0 {
+            bail!("No history window could be read");
+        }
+        record_window_gaps(&self.config, &mut attributes, &stats, &mut warnings);
+        let sample_period = sample_period.unwrap();
+        let excess = rows.saturating_sub((duration.as_secs_f64() * 1000. / sample_period) as usize);
+        let kept = match trim {
+            TrimPolicy::LeadingExcess => excess..rows,
+            TrimPolicy::TrailingExcess => 0..rows - excess,
+            TrimPolicy::None | TrimPolicy::WholePeriods { .. } => 0..rows,
+        };
+        let trimmed_samples = rows - kept.len();
+        attributes.insert("trimmed_samples".into(), trimmed_samples.to_string());
+        let reports = clipped
+            .into_values()
+            .map(|mut r| {
+                r.fraction /= rows.max(1) as f64;
+                r
+            })
+            .collect_vec();
+        self.flag_clipping(&mut attributes, &reports, &mut warnings)?;
+        rewrite_header(path, &attributes, kept)?;
+        guard.disarm();
+        Ok(stats.info(started_at, trimmed_samples, retries, warnings))
+    }
+    async fn verify_output(
+        &mut self,
+        settings: WavegenSettings,
+        warnings: &mut Vec<String>,
+    ) -> Result<usize> {
+        let tolerance = settings.pkpk.abs() * self.config.output_tolerance;
+        let within = |measured: f64| (measured - settings.pkpk).abs() <= tolerance;
+        let measured = self.measure_output_pkpk(settings).await?;
+        if within(measured) {
+            return Ok(0);
+        }
+        warn(
+            warnings,
+            format!(
+                "wavegen output measured {measured:.3} V pkpk, expected {:.3} V, retrying",
+                settings.pkpk
+            ),
+        );
+        self.stop_wavegen().await?;
+        self.start_wavegen().await?;
+        let measured = self.measure_output_pkpk(settings).await?;
+        if !within(measured) {
+            self.stop_wavegen().await?;
+            bail!(
+                "Wavegen output measured {measured:.3} V pkpk, expected {:.3} V",
+                settings.pkpk
+            );
+        }
+        Ok(1)
+    }
+    // `None` if `DriverConfig::amplitude_correction` is off. the correction never commands more
+    // than `DriverConfig::max_pkpk`, and falling short of the tolerance is only a warning
+    async fn correct_amplitude(
+        &mut self,
+        settings: WavegenSettings,
+        warnings: &mut Vec<String>,
+    ) -> Result<Option<CorrectedAmplitude>> {
+        let Some(correction) = self.config.amplitude_correction else {
+            return Ok(None);
+        };
+        let target = settings.pkpk;
+        let max_pkpk = self.config.max_pkpk(settings.offset);
+        let mut commanded = target;
+        let mut iterations = 0;
+        loop {
+            let achieved = self.measure_output_pkpk(settings).await?;
+            let corrected = CorrectedAmplitude {
+                commanded,
+                achieved,
+                iterations,
+            };
+            match correction.step(target, max_pkpk, corrected) {
+                CorrectionStep::Done => return Ok(Some(corrected)),
+                CorrectionStep::Stop(message) => {
+                    warn(warnings, message);
+                    return Ok(Some(corrected));
+                }
+                CorrectionStep::Command(next) => commanded = next,
+            }
+            iterations += 1;
+            self.set_wavegen_pkpk(commanded).await?;
+        }
+    }
+    async fn measure_output_pkpk(&mut self, settings: WavegenSettings) -> Result<f64> {
+        let max_wait = self.config.window_stride();
+        let wait = settings.period.min(max_wait);
+        tokio::time::sleep(wait).await;
+        let datfile = self.read_history().await?;
+        let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+        let voltage = voltage_signal(&datfile).context("History has no voltage channel")?;
+        let n = (wait.as_secs_f64() * 1000. / sample_period) as usize;
+        let (lo, hi) = voltage[voltage.len().saturating_sub(n)..]
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            });
+        Ok((hi - lo).max(0.) * self.config.voltage_monitor_scale)
+    }
+    fn check_clipping(&self, datfile: &mut DatFile, warnings: &mut Vec<String>) -> Result<()> {
+        let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+        let reports = clip_reports(datfile, &self.config.clip_limits, sample_period);
+        self.flag_clipping(&mut datfile.attributes, &reports, warnings)
+    }
+    // warns about and marks the channels that clipped, failing past `max_clip_fraction`
+    fn flag_clipping(
+        &self,
+        attributes: &mut BTreeMap<String, String>,
+        reports: &[ClipReport],
+        warnings: &mut Vec<String>,
+    ) -> Result<()> {
+        let reports = reports.iter().filter(|r| r.fraction > 0.).collect_vec();
+        if reports.is_empty() {
+            return Ok(());
+        }
+        for r in &reports {
+            warn(
+                warnings,
+                format!(
+                    "`{}` clipped for {:.2}% of the aquisition",
+                    r.channel,
+                    r.fraction * 100.
+                ),
+            );
+        }
+        attributes.insert(
+            "clipped".into(),
+            reports.iter().map(|r| &r.channel).join(","),
+        );
+        if let Some(max) = self.config.max_clip_fraction {
+            if let Some(r) = reports.iter().find(|r| r.fraction > max) {
+                bail!(
+                    "`{}` clipped for {:.2}% of the aquisition, over the {:.2}% limit",
+                    r.channel,
+                    r.fraction * 100.,
+                    max * 100.
+                );
+            }
+        }
+        Ok(())
+    }
+    // two history snapshots a moment apart are the same if the nanonis isn't logging, and saving
+    // would then keep returning the stale buffer
+    pub async fn check_history_recording(&mut self) -> Result<()> {
+        let first = self.read_history().await?;
+        let sample_period = AcqAttributes(&first.attributes)
+            .sample_period_ms()
+            .context("The history has no usable sample period")?;
+        if sample_period.is_nan() || sample_period <= 0. || sample_period >= MAX_SAMPLE_PERIOD_MS {
+            bail!("The history sample period of {sample_period} ms is implausible");
+        }
+        if first.signals.values().all(Vec::is_empty) {
+            bail!("The history is empty, start the Nanonis data acquisition");
+        }
+        tokio::time::sleep(Duration::from_secs_f64(HISTORY_CHECK_INTERVAL_S)).await;
+        let second = self.read_history().await?;
+        if first.signals == second.signals {
+            bail!(
+                "The history did not change in {HISTORY_CHECK_INTERVAL_S} s, start the Nanonis \
+                 data acquisition"
+            );
+        }
+        Ok(())
+    }
+    // snapshots whatever is in the nanonis history buffer, without touching the wavegen
+    pub async fn capture_history(&mut self) -> Result<DatFile> {
+        self.read_history().await
+    }
+    async fn read_history(&mut self) -> Result<DatFile, anyhow::Error> {
+        let file = ScratchFile::new(&self.config.scratch_dir, "dat")?;
+        self.save_dat(file.path()).await?;
+        read_when_written(file.path()).await
+    }
+    pub async fn start_wavegen(&self) -> Result<()> {
+        self.focus_window(WAVEFORMS_WINDOW).await?;
+        if self.pa.set_wavegen_running(true).await? {
+            self.log(Event::WavegenStarted);
+        }
+        self.update_session(|s| s.set_running(true));
+        Ok(())
+    }
+    // the saved session is compared with what WaveForms reports and any difference logged, then
+    // the settings cache starts from the device's values rather than empty
+    // returns where the device disagrees with the saved session. the device reads are best
+    // effort, a failed one leaves the settings cache empty so the first apply sends everything
+    async fn reconcile_session(&mut self) -> Vec<String> {
+        let Some(path) = self.config.session_file.clone() else {
+            return vec![];
+        };
+        if self.config.reset_session {
+            self.update_session(|s| *s = SessionState::default());
+            return vec![];
+        }
+        let saved = SessionState::load(&path).unwrap_or_else(|e| {
+            eprintln!("WARNING: ignoring the saved session: {e:#}");
+            None
+        });
+        let running = match self.pa.wavegen_is_running().await {
+            Ok(running) => Some(running),
+            Err(e) => {
+                eprintln!("WARNING: could not read whether the wavegen is running: {e:#}");
+                None
+            }
+        };
+        let device = match self.sync_from_device().await {
+            Ok(device) => Some(device),
+            Err(e) => {
+                eprintln!("WARNING: could not read the wavegen settings: {e:#}");
+                self.invalidate_cache();
+                None
+            }
+        };
+        // a failed read keeps what the last session recorded rather than forgetting it
+        *self.session.borrow_mut() = saved.clone().unwrap_or_default();
+        let discrepancies = match (&saved, device, running) {
+            (Some(saved), Some(device), Some(running)) => {
+                session_discrepancies(saved, device, running)
+            }
+            _ => vec![],
+        };
+        self.update_session(|s| {
+            if let Some(device) = device {
+                s.set_settings(device);
+            }
+            if let Some(running) = running {
+                s.set_running(running);
+            }
+        });
+        discrepancies
+    }
+    // saving is best effort, a failure only costs the next run its reconcile
+    fn update_session(&self, update: impl FnOnce(&mut SessionState)) {
+        let mut session = self.session.borrow_mut();
+        update(&mut session);
+        if let Some(path) = &self.config.session_file {
+            if let Err(e) = session.save(path) {
+                eprintln!("WARNING: could not save the session: {e:#}");
+            }
+        }
+    }
+    async fn lock_acquisition(&self) -> Result<OwnedMutexGuard<()>> {
+        self.pa
+            .lock_acquisition(!self.config.fail_if_acquiring)
+            .await
+    }
+    async fn wait_warmup(&self) -> Result<()> {
+        if self.config.warmup.is_zero() {
+            return Ok(());
+        }
+        let message = "settling after start".into();
+        wait_with_progress(&self.progress, self.config.warmup, message).await
+    }
+    pub async fn stop_wavegen(&self) -> Result<()> {
+        self.pa.stop_wavegen().await?;
+        self.log(Event::WavegenStopped);
+        self.update_session(|s| s.set_running(false));
+        Ok(())
+    }
+    pub async fn show_notification(&self, title: &str, message: &str) -> Result<()> {
+        self.pa.show_notification(title, message).await
+    }
+    pub fn set_event_log(&mut self, events: Option<EventLog>) {
+        self.events = events;
+    }
+    pub fn bridge_stats(&self) -> BTreeMap<String, CommandStats> {
+        self.pa.stats()
+    }
+    pub fn progress_status(&self) -> ProgressStatus {
+        self.progress_status.clone()
+    }
+    // reads each channel through the flow, e.g. a temperature shown in a controller's window
+    pub fn flow_aux_logger(&self, channels: Vec<AuxChannel>) -> FlowAuxLogger {
+        FlowAuxLogger::new(self.pa.clone(), channels)
+    }
+    // bars added here are drawn together with the driver's own
+    pub fn progress(&self) -> MultiProgress {
+        self.progress.clone()
+    }
+    // the last settings sent to the wavegen
+    fn settings_label(&self) -> String {
+        let parts = [
+            self.pkpk.map(|v| format!("{v} V pkpk")),
+            self.period.map(|p| format!("{} s", p.as_secs_f64())),
+            self.symmetry.map(|s| format!("{s}% symmetry")),
+        ];
+        parts.into_iter().flatten().join(", ")
+    }
+    fn log(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.log(event);
+        }
+    }
+    // stops the wavegen when dropped unless disarmed first
+    pub fn wavegen_guard(&self) -> WavegenGuard {
+        WavegenGuard {
+            pa: self.pa.clone(),
+            armed: true,
+        }
+    }
+    pub async fn set_wavegen_waveform(&mut self, waveform: &Waveform) -> Result<()> {
+        let id = waveform.id();
+        if self.waveform == Some(id) {
+            return Ok(());
+        }
+        match waveform {
+            Waveform::Trapezium => self.pa.wavegen_set_trapezium().await?,
+            Waveform::Custom(samples) => self.upload_custom(samples).await?,
+        }
+        self.waveform = Some(id);
+        Ok(())
+    }
+    async fn upload_custom(&mut self, samples: &[f64]) -> Result<()> {
+        if samples.is_empty() {
+            bail!("Custom waveform has no samples");
+        }
+        if let Some(s) = samples.iter().find(|s| !(-1. ..=1.).contains(*s)) {
+            bail!("Custom waveform sample {s} is outside -1..1");
+        }
+        let max = match self.custom_max_samples {
+            Some(max) => max,
+            None => *self
+                .custom_max_samples
+                .insert(self.pa.wavegen_custom_max_samples().await?),
+        };
+        if samples.len() > max {
+            bail!(
+                "Custom waveform has {} samples but the wavegen accepts at most {max}",
+                samples.len()
+            );
+        }
+        for (i, chunk) in samples.chunks(CUSTOM_CHUNK_SAMPLES).enumerate() {
+            let csv = chunk.iter().map(|s| format!("{s:.6}")).join(",");
+            self.pa.wavegen_set_custom(&csv, i > 0).await?;
+        }
+        Ok(())
+    }
+    pub async fn set_wavegen_pkpk(&mut self, pkpk: f64) -> Result<()> {
+        self.config
+            .check_output_range(pkpk, self.offset.unwrap_or_default())?;
+        self.send_pkpk(pkpk).await
+    }
+    async fn send_pkpk(&mut self, pkpk: f64) -> Result<()> {
+        let pkpk = self.config.sent_value(WavegenField::Amplitude, pkpk);
+        if self.pkpk != Some(pkpk) {
+            self.set_field(WavegenField::Amplitude, pkpk).await?;
+            self.pkpk = Some(pkpk);
+        }
+        Ok(())
+    }
+    pub async fn set_wavegen_period(&mut self, period: Duration) -> Result<()> {
+        let secs = self
+            .config
+            .sent_value(WavegenField::Period, period.as_secs_f64());
+        let period = Duration::try_from_secs_f64(secs)
+            .with_context(|| format!("{secs} s is not a valid period"))?;
+        if self.period != Some(period) {
+            self.set_field(WavegenField::Period, secs).await?;
+            self.period = Some(period);
+        }
+        Ok(())
+    }
+    pub async fn set_wavegen_offset(&mut self, offset: f64) -> Result<()> {
+        self.config
+            .check_output_range(self.pkpk.unwrap_or_default(), offset)?;
+        self.send_offset(offset).await
+    }
+    async fn send_offset(&mut self, offset: f64) -> Result<()> {
+        let offset = self.config.sent_value(WavegenField::Offset, offset);
+        if self.offset != Some(offset) {
+            self.set_field(WavegenField::Offset, offset).await?;
+            self.offset = Some(offset);
+        }
+        Ok(())
+    }
+    pub async fn set_wavegen_symmetry(&mut self, symmetry: f64) -> Result<()> {
+        let symmetry = self.config.sent_value(WavegenField::Symmetry, symmetry);
+        if self.symmetry != Some(symmetry) {
+            self.set_field(WavegenField::Symmetry, symmetry).await?;
+            self.symmetry = Some(symmetry);
+        }
+        Ok(())
+    }
+    // `value` is the setting, which is sent as the rounded field value and verified against it
+    async fn set_field(&self, field: WavegenField, value: f64) -> Result<()> {
+        let value = self.config.field_value(field, value);
+        self.send_field(field, value).await?;
+        if !self.config.verify_settings {
+            return Ok(());
+        }
+        let mut observed = self.read_field(field).await?;
+        for _ in 0..self.config.verify_retries {
+            if settings_match(value, observed) {
+                return Ok(());
+            }
+            self.send_field(field, value).await?;
+            observed = self.read_field(field).await?;
+        }
+        if !settings_match(value, observed) {
+            bail!("Wavegen {field:?} was set to {value} but reads back {observed}");
+        }
+        Ok(())
+    }
+    async fn send_field(&self, field: WavegenField, value: f64) -> Result<()> {
+        match field {
+            WavegenField::Amplitude => self.pa.wavegen_set_amplitude(value).await,
+            WavegenField::Period => self.pa.wavegen_set_period(value).await,
+            WavegenField::Offset => self.pa.wavegen_set_offset(value).await,
+            WavegenField::Symmetry => self.pa.wavegen_set_symmetry(value).await,
+        }
+    }
+    async fn read_field(&self, field: WavegenField) -> Result<f64> {
+        match field {
+            WavegenField::Amplitude => self.pa.wavegen_get_amplitude().await,
+            WavegenField::Period => self.pa.wavegen_get_period().await,
+            WavegenField::Offset => self.pa.wavegen_get_offset().await,
+            WavegenField::Symmetry => self.pa.wavegen_get_symmetry().await,
+        }
+    }
+    // forgets what was sent, so the next apply resends every setting, e.g. after the settings
+    // were changed by hand in WaveForms. the waveform shape is kept
+    pub fn invalidate_cache(&mut self) {
+        self.pkpk = None;
+        self.period = None;
+        self.offset = None;
+        self.symmetry = None;
+    }
+    // what the driver believes is applied, if every setting has been sent since the last
+    // invalidation
+    pub fn current_settings(&self) -> Option<WavegenSettings> {
+        Some(WavegenSettings {
+            pkpk: self.pkpk?,
+            period: self.period?,
+            symmetry_p: self.symmetry?,
+            offset: self.offset?,
+        })
+    }
+    // replaces the cache with the values WaveForms reads back
+    pub async fn sync_from_device(&mut self) -> Result<WavegenSettings> {
+        let amplitude = self.read_field(WavegenField::Amplitude).await?;
+        let period = self.read_field(WavegenField::Period).await?;
+        let offset = self.read_field(WavegenField::Offset).await?;
+        let symmetry = self.read_field(WavegenField::Symmetry).await?;
+        let settings = WavegenSettings {
+            pkpk: self
+                .config
+                .setting_value(WavegenField::Amplitude, amplitude),
+            period: Duration::try_from_secs_f64(period)
+                .with_context(|| format!("WaveForms reports an invalid period of {period} s"))?,
+            symmetry_p: symmetry,
+            offset: self.config.setting_value(WavegenField::Offset, offset),
+        };
+        self.pkpk = Some(settings.pkpk);
+        self.period = Some(settings.period);
+        self.offset = Some(settings.offset);
+        self.symmetry = Some(settings.symmetry_p);
+        Ok(settings)
+    }
+    pub async fn apply_wavegen_settings(&mut self, settings: WavegenSettings) -> Result<()> {
+        self.config
+            .check_output_range(settings.pkpk, settings.offset)?;
+        let start = Instant::now();
+        let sent = self.config.sent_settings(settings)?;
+        let changed = [
+            self.pkpk != Some(sent.pkpk),
+            self.period != Some(sent.period),
+            self.offset != Some(sent.offset),
+            self.symmetry != Some(sent.symmetry_p),
+        ];
+        if self.config.pipeline_settings {
+            // each field is its own control in WaveForms, so the commands can be in flight together
+            let fields = [
+                (WavegenField::Amplitude, sent.pkpk),
+                (WavegenField::Period, sent.period.as_secs_f64()),
+                (WavegenField::Offset, sent.offset),
+                (WavegenField::Symmetry, sent.symmetry_p),
+            ];
+            let setters = fields
+                .into_iter()
+                .zip(changed)
+                .filter(|(_, changed)| *changed)
+                .map(|((field, value), _)| self.set_field(field, value));
+            future::try_join_all(setters).await?;
+            self.pkpk = Some(sent.pkpk);
+            self.period = Some(sent.period);
+            self.offset = Some(sent.offset);
+            self.symmetry = Some(sent.symmetry_p);
+        } else {
+            self.send_pkpk(settings.pkpk).await?;
+            self.set_wavegen_period(settings.period).await?;
+            self.send_offset(settings.offset).await?;
+            self.set_wavegen_symmetry(settings.symmetry_p).await?;
+        }
+        if let Some(current) = self.current_settings() {
+            self.update_session(|s| s.set_settings(current));
+        }
+        let n_changed = changed.iter().filter(|c| **c).count();
+        if n_changed > 0 {
+            self.log(Event::SettingsApplied { settings });
+            println!(
+                "Changed {n_changed} wavegen settings in {:.2} s",
+                start.elapsed().as_secs_f64()
+            );
+        }
+        Ok(())
+    }
+    pub async fn save_dat(&self, path: impl AsRef<Path>) -> Result<()> {
+        let fname = path.as_ref().file_name().unwrap().to_str().unwrap();
+        let folder = path.as_ref().parent().unwrap().to_str().unwrap();
+        self.with_window(HISTORY_WINDOW, self.pa.nanonis_save_history(folder, fname))
+            .await?;
+        Ok(())
+    }
+    pub async fn check_ready(&self) -> Result<()> {
+        self.pa.ping().await?;
+        self.ensure_waveforms_open().await
+    }
+    async fn ensure_waveforms_open(&self) -> Result<()> {
+        if !self.pa.is_window_open(WAVEFORMS_WINDOW, "").await? {
+            let windows = self.list_windows().await?;
+            bail!(
+                "Waveforms is not open, found windows: {}",
+                windows.iter().map(|w| format!("`{w}`")).join(", ")
+            )
+        };
+        Ok(())
+    }
+    pub async fn list_windows(&self) -> Result<Vec<String>> {
+        self.pa.list_open_windows().await
+    }
+    // reopens the window first if it has been closed, see `recover`
+    pub async fn focus_window(&self, window: &str) -> Result<()> {
+        let focused = self.pa.get_open_window().await?;
+        if focused == window {
+            return Ok(());
+        }
+        if let Err(e) = self.pa.focus_window(window, "").await {
+            if self.pa.is_window_open(window, "").await? {
+                return Err(e);
+            }
+            self.recover(window).await?;
+            self.pa.focus_window(window, "").await?;
+        }
+        Ok(())
+    }
+    // attempts are shared by the whole point, see `reset_recoveries`
+    async fn recover(&self, window: &str) -> Result<()> {
+        while self.recoveries.get() < self.config.max_recoveries {
+            self.recoveries.set(self.recoveries.get() + 1);
+            let action = match window {
+                WAVEFORMS_WINDOW => "relaunching WaveForms",
+                HISTORY_WINDOW => "reopening the history",
+                _ => bail!("`{window}` was closed and can't be reopened"),
+            };
+            eprintln!("WARNING: `{window}` was closed, {action}");
+            self.log(Event::RecoveryStarted {
+                window: window.into(),
+                action: action.into(),
+            });
+            let res = match window {
+                WAVEFORMS_WINDOW => self.relaunch_waveforms().await,
+                _ => self.reopen_history().await,
+            };
+            self.log(Event::RecoveryFinished {
+                window: window.into(),
+                error: res.as_ref().err().map(|e| format!("{e:#}")),
+            });
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) => eprintln!("WARNING: could not reopen `{window}`: {e:#}"),
+            }
+        }
+        Err(RecoveryFailed {
+            window: window.into(),
+            attempts: self.recoveries.get(),
+        }
+        .into())
+    }
+    pub(crate) fn reset_recoveries(&self) {
+        self.recoveries.set(0);
+    }
+    pub(crate) fn recovery_attempts(&self) -> usize {
+        self.recoveries.get()
+    }
+    // a fresh WaveForms has default settings, so the last ones sent are applied again
+    async fn relaunch_waveforms(&self) -> Result<()> {
+        let path = self
+            .config
+            .waveforms_path
+            .as_ref()
+            .context("No WaveForms path is configured")?;
+        let path = path
+            .to_str()
+            .context("The WaveForms path is not valid unicode")?;
+        self.pa.launch_application(path).await?;
+        self.wait_for_window(WAVEFORMS_WINDOW).await?;
+        self.pa.focus_window(WAVEFORMS_WINDOW, "").await?;
+        match self.waveform {
+            Some(WaveformId::Custom { .. }) => {
+                bail!("The custom waveform can't be restored, upload it again")
+            }
+            _ => self.pa.wavegen_set_trapezium().await?,
+        }
+        let fields = [
+            (WavegenField::Amplitude, self.pkpk),
+            (WavegenField::Period, self.period.map(|p| p.as_secs_f64())),
+            (WavegenField::Offset, self.offset),
+            (WavegenField::Symmetry, self.symmetry),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                self.set_field(field, value).await?;
+            }
+        }
+        Ok(())
+    }
+    async fn reopen_history(&self) -> Result<()> {
+        self.pa.nanonis_open_history().await?;
+        self.wait_for_window(HISTORY_WINDOW).await
+    }
+    async fn wait_for_window(&self, window: &str) -> Result<()> {
+        let start = Instant::now();
+        while !self.pa.is_window_open(window, "").await? {
+            if start.elapsed().as_secs_f64() > WINDOW_OPEN_TIMEOUT_S {
+                bail!("`{window}` did not open within {WINDOW_OPEN_TIMEOUT_S} s");
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        Ok(())
+    }
+    pub async fn with_window<T>(
+        &self,
+        window: &str,
+        f: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let open_window = self.pa.get_open_window().await?;
+        self.focus_window(window).await?;
+        let res = f.await;
+        self.focus_window(&open_window).await?;
+        res
+    }
+    pub async fn new() -> Result<Self> {
+        Self::with_config(DriverConfig::default()).await
+    }
+    pub async fn from_env() -> Result<Self> {
+        Self::with_config(DriverConfig::from_env()).await
+    }
+    pub async fn with_config(config: DriverConfig) -> Result<Self> {
+        match scratch::clean(&config.scratch_dir, config.scratch_max_age) {
+            Ok(summary) if summary.removed + summary.failed > 0 => {
+                eprintln!("{summary} from {}", config.scratch_dir.display())
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("WARNING: could not clean scratch files: {e:#}"),
+        }
+        unsafe {
+            if PA_SERVER.is_none() {
+                PA_SERVER = Some(Rc::new(PowerAutomate::bind(config.bind_addr)))
+            }
+        }
+        let pa = unsafe { PA_SERVER.as_ref() }.unwrap().clone();
+        if let Transport::WebSocket { grace } = config.transport {
+            if !pa.wait_for_websocket(grace).await {
+                eprintln!(
+                    "WARNING: no WebSocket client connected within {grace:?}, polling instead"
+                );
+            }
+        }
+        let mut self_ = Self::from_parts(config, pa);
+        self_.ensure_waveforms_open().await?;
+        self_.check_history_recording().await?;
+        for message in self_.reconcile_session().await {
+            eprintln!("WARNING: {message}");
+        }
+        self_.set_wavegen_waveform(&Waveform::Trapezium).await?;
+        Ok(self_)
+    }
+    fn from_parts(config: DriverConfig, pa: Rc<PowerAutomate>) -> Self {
+        Self {
+            config,
+            pa,
+            pkpk: None,
+            period: None,
+            offset: None,
+            symmetry: None,
+            waveform: None,
+            custom_max_samples: None,
+            events: None,
+            progress: MultiProgress::new(),
+            progress_status: ProgressStatus::default(),
+            recoveries: Cell::new(0),
+            session: RefCell::new(SessionState::default()),
+        }
+    }
+}
+
+// `8h`, `2h 30m`, `45.5s`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (h, m) = (secs / 3600, secs / 60 % 60);
+    let s = duration.as_secs_f64() % 60.;
+    let parts = [
+        (h > 0).then(|| format!("{h}h")),
+        (m > 0).then(|| format!("{m}m")),
+        (s > 0. || secs == 0).then(|| format!("{s}s")),
+    ];
+    parts.into_iter().flatten().join(" ")
+}
+
+fn periods(period: Duration, n: usize) -> Result<Duration> {
+    u32::try_from(n)
+        .ok()
+        .and_then(|n| period.checked_mul(n))
+        .with_context(|| format!("{n} periods of {period:?} overflows a duration"))
+}
+
+pub(crate) async fn wait_with_progress(
+    progress: &MultiProgress,
+    duration: Duration,
+    message: String,
+) -> Result<()> {
+    let bar = progress.add(
+        ProgressBar::new(progress_position(duration, duration)).with_style(
+            ProgressStyle::with_template("[{eta_precise}] {bar:60.yellow/red} {msg}")?,
+        ),
+    );
+    bar.set_message(message);
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        bar.set_position(progress_position(start.elapsed(), duration));
+        let remaining = duration.saturating_sub(start.elapsed());
+        tokio::time::sleep(remaining.min(Duration::from_millis(1000))).await;
+    }
+    bar.finish();
+    Ok(())
+}
+
+// bar position in tenths of a second, held at the end once the time is up
+fn progress_position(elapsed: Duration, total: Duration) -> u64 {
+    (elapsed.min(total).as_millis() / 100) as u64
+}
+
+// waits for nanonis to finish writing the file, judged by its size holding steady
+async fn read_when_written(path: &Path) -> Result<DatFile> {
+    let timeout = Duration::from_secs_f64(HISTORY_SAVE_TIMEOUT_S);
+    let start = Instant::now();
+    let mut last_len = None;
+    loop {
+        let len = std::fs::metadata(path).map(|m| m.len()).ok();
+        if len.is_some() && len == last_len {
+            break;
+        }
+        if start.elapsed() >= timeout {
+            bail!(
+                "Nanonis did not finish saving `{}` within {timeout:?}",
+                path.display()
+            );
+        }
+        last_len = len;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    DatFile::read_from_file(path).with_context(|| format!("Could not read `{}`", path.display()))
+}
+
+// indices where the voltage passes 50%, 10% and 90% of the way from the level before
+// `commanded` to the level at the end of the record
+fn step_timing(voltage: &[f64], commanded: usize) -> Option<(usize, usize, usize)> {
+    let tail = voltage.len() / 10;
+    if commanded == 0 || tail == 0 || commanded >= voltage.len() - tail {
+        return None;
+    }
+    let mean = |s: &[f64]| s.iter().sum::<f64>() / s.len() as f64;
+    let before = mean(&voltage[..commanded]);
+    let after = mean(&voltage[voltage.len() - tail..]);
+    let span = after - before;
+    if span == 0. {
+        return None;
+    }
+    let reached = |fraction: f64| voltage.iter().position(|v| (v - before) / span >= fraction);
+    Some((reached(0.5)?, reached(0.1)?, reached(0.9)?))
+}
+
+struct Window {
+    // `None` for a window that could not be read and was skipped, see `WindowFailure::Skip`
+    datfile: Option<DatFile>,
+    read_at: Instant,
+    seam: Seam,
+    // repeated samples dropped from the start of this window, see `dedup_seam`
+    seam_duplicates: usize,
+    // set on the window during which the offset was stepped
+    stepped_at: Option<Instant>,
+}
+
+// how a window was joined onto the record before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Seam {
+    // matched on the overlap, or the first window
+    Joined,
+    // the overlap couldn't be found, so only the samples since the last read were kept
+    Unmatched,
+    // the window before was skipped and left no overlap, so the record has a gap here
+    Gap,
+}
+
+// how the windows of a capture were read and joined
+#[derive(Debug, Default)]
+struct WindowStats {
+    read: usize,
+    skipped: usize,
+    // between consecutive windows that were read
+    intervals: Vec<Duration>,
+    last_read: Option<Instant>,
+    seam_duplicates: usize,
+    unmatched_seams: usize,
+    gaps: usize,
+}
+impl WindowStats {
+    // the window's data, `None` if it was skipped
+    fn add(&mut self, window: Window) -> Option<DatFile> {
+        let Some(datfile) = window.datfile else {
+            self.skipped += 1;
+            return None;
+        };
+        self.read += 1;
+        if let Some(last) = self.last_read {
+            self.intervals.push(window.read_at - last);
+        }
+        self.last_read = Some(window.read_at);
+        self.seam_duplicates += window.seam_duplicates;
+        match window.seam {
+            Seam::Joined => {}
+            Seam::Unmatched => self.unmatched_seams += 1,
+            Seam::Gap => self.gaps += 1,
+        }
+        Some(datfile)
+    }
+    fn info(
+        self,
+        started_at: DateTime<Local>,
+        trimmed_samples: usize,
+        retries: usize,
+        warnings: Vec<String>,
+    ) -> AcquisitionInfo {
+        AcquisitionInfo {
+            windows: self.read,
+            skipped_windows: self.skipped,
+            unmatched_seams: self.unmatched_seams,
+            gaps: self.gaps,
+            trimmed_samples,
+            started_at,
+            finished_at: Local::now(),
+            retries,
+            window_intervals: self.intervals,
+            warnings,
+        }
+    }
+}
+
+struct WindowReader<'a> {
+    driver: &'a mut AquisitionDriver,
+    step: Option<(Duration, f64)>,
+    stepped_at: Option<Instant>,
+    bar: ProgressBar,
+    num_windows: usize,
+    total_dur: Duration,
+    start_time: Instant,
+    // measured from `start_time`
+    window_end: Duration,
+    // the last window read, as read, and when
+    previous: Option<(DatFile, Instant)>,
+    // whether the window since `previous` was skipped
+    after_skip: bool,
+    count: usize,
+    done: bool,
+}
+impl<'a> WindowReader<'a> {
+    fn new(
+        driver: &'a mut AquisitionDriver,
+        duration: Duration,
+        step: Option<(Duration, f64)>,
+    ) -> Result<Self> {
+        let total_dur = duration + driver.config.window_buffer;
+        let num_windows = driver.config.window_count(total_dur);
+        let window_end = driver.config.history_window;
+        let bar = driver.progress.add(
+            ProgressBar::new(progress_position(total_dur, total_dur)).with_style(
+                ProgressStyle::with_template("[{eta_precise}] {bar:60.cyan/blue} {msg}")?,
+            ),
+        );
+        Ok(Self {
+            driver,
+            step,
+            stepped_at: None,
+            bar,
+            num_windows,
+            total_dur,
+            start_time: Instant::now(),
+            window_end,
+            previous: None,
+            after_skip: false,
+            count: 0,
+            done: false,
+        })
+    }
+    fn max_seam_dedup_samples(&self, datfile: &DatFile) -> Result<usize> {
+        let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+        let max_ms = self.driver.config.max_seam_dedup.as_secs_f64() * 1000.;
+        Ok((max_ms / sample_period) as usize)
+    }
+    async fn next_window(&mut self) -> Result<Option<Window>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.count += 1;
+        let aq_done = self.wait_for_window_end().await?;
+        self.window_end = self.start_time.elapsed() + self.driver.config.window_stride();
+        let read_at = Instant::now();
+        let raw = self.read_window().await?;
+        if aq_done {
+            self.bar.finish();
+            self.done = true;
+        }
+        let mut window = Window {
+            datfile: None,
+            read_at,
+            seam: Seam::Joined,
+            seam_duplicates: 0,
+            stepped_at: self.stepped_at.take(),
+        };
+        let Some(raw) = raw else {
+            self.after_skip = true;
+            return Ok(Some(window));
+        };
+        let datfile = match &self.previous {
+            Some((previous, previous_read_at)) => match strip_overlap(previous, raw.clone()) {
+                Some(mut datfile) => {
+                    let max_samples = self.max_seam_dedup_samples(&raw)?;
+                    window.seam_duplicates = dedup_seam(previous, &mut datfile, max_samples);
+                    datfile
+                }
+                // a skipped window can leave nothing to stitch on
+                None if self.after_skip => {
+                    window.seam = Seam::Gap;
+                    raw.clone()
+                }
+                None => {
+                    eprintln!(
+                        "WARNING: history window {} does not overlap the one before it, \
+                         joining them on the time between reads",
+                        self.count
+                    );
+                    window.seam = Seam::Unmatched;
+                    keep_latest(raw.clone(), read_at - *previous_read_at)?
+                }
+            },
+            None => raw.clone(),
+        };
+        self.previous = Some((raw, read_at));
+        self.after_skip = false;
+        window.datfile = Some(datfile);
+        Ok(Some(window))
+    }
+    // retries a failed read, then fails the acquisition or gives the window up
+    async fn read_window(&mut self) -> Result<Option<DatFile>> {
+        let mut retries = 0;
+        loop {
+            let e = match self.driver.read_history().await {
+                Ok(raw) => return Ok(Some(raw)),
+                Err(e) => e,
+            };
+            let config = &self.driver.config;
+            if retries < config.max_window_retries && is_retryable(&e) {
+                retries += 1;
+                eprintln!(
+                    "WARNING: reading history window {} failed, retrying ({retries}/{}): {e:#}",
+                    self.count, config.max_window_retries
+                );
+                continue;
+            }
+            match config.window_failure {
+                WindowFailure::Abort => {
+                    return Err(e.context(format!("Could not read history window {}", self.count)))
+                }
+                WindowFailure::Skip => {
+                    eprintln!("WARNING: skipping history window {}: {e:#}", self.count);
+                    return Ok(None);
+                }
+            }
+        }
+    }
+    // returns whether the whole duration has now been recorded
+    async fn wait_for_window_end(&mut self) -> Result<bool> {
+        let aq_done = loop {
+            let elapsed = self.start_time.elapsed();
+            let mut message = format!(
+                "window {} of {}, {:.0}/{:.0} s, {}",
+                self.count,
+                self.num_windows,
+                elapsed.min(self.total_dur).as_secs_f64(),
+                self.total_dur.as_secs_f64(),
+                self.driver.settings_label(),
+            );
+            if let Some(status) = self.driver.progress_status.get() {
+                message += &format!(" [{status}]");
+            }
+            self.bar.set_message(message);
+            self.bar
+                .set_position(progress_position(elapsed, self.total_dur));
+            if let Some((at, voltage)) = self.step {
+                if self.start_time.elapsed() >= at {
+                    self.driver.set_wavegen_offset(voltage).await?;
+                    self.stepped_at = Some(Instant::now());
+                    self.step = None;
+                }
+            }
+            let aq_done = elapsed >= self.total_dur;
+            let window_done = elapsed >= self.window_end;
+            if window_done | aq_done {
+                break aq_done;
+            }
+            let mut tick = Duration::from_millis(1000);
+            if let Some((at, _)) = self.step {
+                tick = tick.min(at.saturating_sub(self.start_time.elapsed()));
+            }
+            tokio::time::sleep(tick).await;
+        };
+        Ok(aq_done)
+    }
+}
+
+// an abandoned capture leaves its bar where it stopped
+impl Drop for WindowReader<'_> {
+    fn drop(&mut self) {
+        if !self.bar.is_finished() {
+            self.bar.abandon();
+        }
+    }
+}
+
+// joins the windows of a capture into one record, stopping early once `settle` passes on it
+async fn stitch_windows(
+    windows: impl Stream<Item = Result<Window>>,
+    settle: Option<(&SettleCriterion, &ChannelCalibration)>,
+) -> Result<Recording> {
+    tokio::pin!(windows);
+    let mut datfile: Option<DatFile> = None;
+    let mut dropped = BTreeSet::new();
+    let mut stats = WindowStats::default();
+    let mut stepped_at = None;
+    let mut settled_at = None;
+    while let Some(window) = windows.try_next().await? {
+        stepped_at = stepped_at.or(window.stepped_at);
+        let Some(next) = stats.add(window) else {
+            continue;
+        };
+        let checked = datfile
+            .as_ref()
+            .and_then(|df| df.signals.values().next())
+            .map_or(0, Vec::len);
+        let stitched = match datfile {
+            Some(df) => append_datfile(df, next, &mut dropped),
+            None => next,
+        };
+        if let Some((criterion, calibration)) = settle {
+            settled_at = criterion.settled_in(&stitched, calibration, checked)?;
+        }
+        datfile = Some(stitched);
+        if settled_at.is_some() {
+            break;
+        }
+    }
+    let mut datfile = datfile.context("No history window could be read")?;
+    if !dropped.is_empty() {
+        let dropped = dropped.into_iter().join(", ");
+        eprintln!("WARNING: dropped channels missing from some history windows: {dropped}");
+        datfile
+            .attributes
+            .insert("dropped_channels".into(), dropped);
+    }
+    Ok(Recording {
+        datfile,
+        last_read: stats.last_read.unwrap(),
+        stats,
+        stepped_at,
+        settled_at,
+    })
+}
+
+// a report for each channel with rails in `limits`
+fn clip_reports(
+    datfile: &DatFile,
+    limits: &ChannelLimits,
+    sample_period_ms: f64,
+) -> Vec<ClipReport> {
+    limits
+        .rails
+        .iter()
+        .filter_map(|(&channel, rail)| {
+            let (name, sig) = find_signal(datfile, channel)?;
+            Some(clip_report(name, sig, *rail, limits, sample_period_ms))
+        })
+        .collect()
+}
+
+fn record_window_gaps(
+    config: &DriverConfig,
+    attributes: &mut BTreeMap<String, String>,
+    stats: &WindowStats,
+    warnings: &mut Vec<String>,
+) {
+    if stats.skipped > 0 {
+        warn(
+            warnings,
+            format!(
+                "{} history windows could not be read and were skipped",
+                stats.skipped
+            ),
+        );
+    }
+    if stats.gaps > 0 {
+        warn(
+            warnings,
+            format!("the record has {} gaps left by skipped windows", stats.gaps),
+        );
+    }
+    if stats.unmatched_seams > 0 {
+        warn(
+            warnings,
+            format!(
+                "{} history windows were joined on their read times, their overlap wasn't found",
+                stats.unmatched_seams
+            ),
+        );
+    }
+    // a late read eats into the overlap that the windows are stitched on
+    let gap_threshold = config
+        .history_window
+        .saturating_sub(config.window_buffer / 5);
+    let window_gaps = stats
+        .intervals
+        .iter()
+        .filter(|&&interval| interval > gap_threshold)
+        .count();
+    if window_gaps > 0 {
+        warn(
+            warnings,
+            format!("{window_gaps} history windows were read too late to overlap safely"),
+        );
+    }
+    let counts = [
+        ("windows", stats.read),
+        ("skipped_windows", stats.skipped),
+        ("record_gaps", stats.gaps),
+        ("unmatched_seams", stats.unmatched_seams),
+        ("seam_duplicates", stats.seam_duplicates),
+        ("window_gaps", window_gaps),
+    ];
+    for (key, count) in counts {
+        attributes.insert(key.into(), count.to_string());
+    }
+    attributes.insert(
+        "window_intervals_s".into(),
+        stats
+            .intervals
+            .iter()
+            .map(|d| format!("{:.3}", d.as_secs_f64()))
+            .join(","),
+    );
+}
+
+// a server error is only worth retrying when it says so, anything else, such as a save that
+// timed out or a file that couldn't be read yet, may clear up on its own
+fn is_retryable(e: &anyhow::Error) -> bool {
+    e.chain()
+        .find_map(|e| e.downcast_ref::<ServerError>())
+        .is_none_or(ServerError::is_transient)
+}
+
+// the samples of `datfile` recorded in the last `elapsed`, for joining windows on their read
+// times when their overlap can't be matched
+fn keep_latest(mut datfile: DatFile, elapsed: Duration) -> Result<DatFile> {
+    let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+    let keep = (elapsed.as_secs_f64() * 1000. / sample_period).round() as usize;
+    for sig in datfile.signals.values_mut() {
+        sig.drain(..sig.len().saturating_sub(keep));
+    }
+    Ok(datfile)
+}
+
+// drops the start of `b` that repeats the end of `a`, matched on the channels both have.
+// `None` if they don't overlap
+fn strip_overlap(a: &DatFile, mut b: DatFile) -> Option<DatFile> {
+    let index = a
+        .signals
+        .iter()
+        .filter_map(|(key, s)| Some((*s.last()?, b.signals.get(key)?)))
+        .map(|(m, v)| {
+            v.iter()
+                .positions(|f| *f == m)
+                .flat_map(|p| [p, p + 1, p + 2])
+                .collect::<BTreeSet<_>>()
+        })
+        .reduce(|a, b| BTreeSet::intersection(&a, &b).cloned().collect())?
+        .into_iter()
+        .next()?;
+    for sig in b.signals.values_mut() {
+        sig.drain(..(index + 1).min(sig.len()));
+    }
+    Some(b)
+}
+
+// drops the head of `b` where it repeats the tail of `a` on every channel, left behind when
+// the overlap match lands a sample or two early
+fn dedup_seam(a: &DatFile, b: &mut DatFile, max_samples: usize) -> usize {
+    let len = |d: &DatFile| d.signals.values().map(Vec::len).min().unwrap_or(0);
+    let max_samples = max_samples.min(len(a)).min(len(b));
+    let repeated = (1..=max_samples)
+        .rev()
+        .find(|&n| {
+            a.signals.iter().all(|(key, sig)| {
+                b.signals
+                    .get(key)
+                    .is_some_and(|other| sig[sig.len() - n..] == other[..n])
+            })
+        })
+        .unwrap_or(0);
+    for sig in b.signals.values_mut() {
+        sig.drain(..repeated);
+    }
+    repeated
+}
+
+// keeps only the channels both have, e.g. when one is added in Nanonis part way through a run,
+// adding the others to `dropped`
+fn append_datfile(mut a: DatFile, b: DatFile, dropped: &mut BTreeSet<String>) -> DatFile {
+    for key in a.signals.keys().chain(b.signals.keys()) {
+        if !(a.signals.contains_key(key) && b.signals.contains_key(key)) {
+            dropped.insert(key.clone());
+        }
+    }
+    a.signals.retain(|key, _| b.signals.contains_key(key));
+    for (key, sig) in a.signals.iter_mut() {
+        sig.extend(&b.signals[key]);
+    }
+    a
+}
+
+fn voltage_signal(datfile: &DatFile) -> Option<&Vec<f64>> {
+    datfile
+        .signals
+        .iter()
+        .find(|(name, _)| name.to_lowercase().contains("voltage"))
+        .map(|(_, sig)| sig)
+}
+
+// trims the record to start on a rising crossing of the voltage monitor
+fn align_start(datfile: &mut DatFile) -> Result<usize, String> {
+    let voltage =
+        voltage_signal(datfile).ok_or("no voltage channel to align the aquisition start to")?;
+    let shift = rising_crossing(voltage)
+        .ok_or("voltage channel is flat, the aquisition start was not aligned")?;
+    for sig in datfile.signals.values_mut() {
+        *sig = sig[shift..].into();
+    }
+    datfile
+        .attributes
+        .insert("start_shift_samples".into(), shift.to_string());
+    Ok(shift)
+}
+
+// trims the extra time read past `duration` as `trim` asks, returning the samples removed
+fn trim_recording(
+    datfile: &mut DatFile,
+    trim: TrimPolicy,
+    duration: Duration,
+    period: Duration,
+    align_start_always: bool,
+    warnings: &mut Vec<String>,
+) -> Result<usize> {
+    let signal_len = datfile.signals.values().next().map_or(0, Vec::len);
+    let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+    let excess =
+        signal_len.saturating_sub((duration.as_secs_f64() * 1000. / sample_period) as usize);
+    let mut trimmed_samples = match trim {
+        TrimPolicy::LeadingExcess | TrimPolicy::WholePeriods { .. } => {
+            for sig in datfile.signals.values_mut() {
+                *sig = sig[excess..].into();
+            }
+            excess
+        }
+        TrimPolicy::TrailingExcess => {
+            for sig in datfile.signals.values_mut() {
+                sig.truncate(signal_len - excess);
+            }
+            excess
+        }
+        TrimPolicy::None => 0,
+    };
+    let align = matches!(
+        trim,
+        TrimPolicy::WholePeriods {
+            align: PhaseAlign::RisingCrossing
+        }
+    );
+    if align_start_always || align {
+        match align_start(datfile) {
+            Ok(shift) => trimmed_samples += shift,
+            Err(warning) => warn(warnings, warning),
+        }
+    }
+    if let TrimPolicy::WholePeriods { .. } = trim {
+        trimmed_samples += trim_whole_periods(datfile, period, sample_period);
+    }
+    datfile
+        .attributes
+        .insert("trim_policy".into(), trim.to_string());
+    datfile
+        .attributes
+        .insert("trimmed_samples".into(), trimmed_samples.to_string());
+    Ok(trimmed_samples)
+}
+
+fn trim_whole_periods(datfile: &mut DatFile, period: Duration, sample_period_ms: f64) -> usize {
+    let period_samples = period.as_secs_f64() * 1000. / sample_period_ms;
+    let len = datfile.signals.values().next().map_or(0, Vec::len);
+    if period_samples < 1. {
+        return 0;
+    }
+    let n_periods = (len as f64 / period_samples).floor();
+    let keep = ((n_periods * period_samples).round() as usize).min(len);
+    for sig in datfile.signals.values_mut() {
+        sig.truncate(keep);
+    }
+    len - keep
+}
+
+fn warn(warnings: &mut Vec<String>, warning: impl Into<String>) {
+    let warning = warning.into();
+    eprintln!("WARNING: {warning}");
+    warnings.push(warning);
+}
+
+fn discard_leading(datfile: &mut DatFile, duration: Duration) -> Result<()> {
+    let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+    let n = (duration.as_secs_f64() * 1000. / sample_period) as usize;
+    for sig in datfile.signals.values_mut() {
+        if n >= sig.len() {
+            bail!("The recording is shorter than the period being discarded");
+        }
+        *sig = sig[n..].into();
+    }
+    Ok(())
+}
+
+fn decimate_datfile(datfile: &mut DatFile, factor: usize) -> Result<()> {
+    if factor == 0 {
+        bail!("Decimation factor must be at least 1");
+    }
+    let sample_period = AcqAttributes(&datfile.attributes).sample_period_ms()?;
+    for sig in datfile.signals.values_mut() {
+        *sig = boxcar_decimate(sig, factor);
+    }
+    AcqAttributes(&mut datfile.attributes).set_sample_period_ms(sample_period * factor as f64);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Transport {
+    // the flow polls for commands with GET and answers with POST
+    #[default]
+    Polling,
+    // the flow holds a WebSocket open on `/ws`, falling back to polling if it hasn't
+    // connected within the grace period
+    WebSocket {
+        grace: Duration,
+    },
+}
+
+type ChannelData = (String, oneshot::Sender<String>);
+
+// flows identify themselves with `?instance=<id>`, so a second copy of the flow
+// can't answer commands the first one picked up
+#[derive(Deserialize)]
+struct InstanceQuery {
+    instance: Option<String>,
+}
+
+struct PendingCommand {
+    id: u64,
+    // the flow instance that fetched the command, if it identified itself
+    instance: Option<String>,
+    response: oneshot::Sender<String>,
+}
+
+struct WsClient {
+    connection: u64,
+    commands: mpsc::UnboundedSender<String>,
+}
+
+// latency of one command name over its recent round trips
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommandStats {
+    pub count: usize,
+    pub failures: usize,
+    // abandoned by the caller before the flow picked the command up
+    pub queue_timeouts: usize,
+    // abandoned by the caller while the flow was working on it
+    pub flow_timeouts: usize,
+    pub mean_queue_ms: f64,
+    pub mean_turnaround_ms: f64,
+    pub mean_ms: f64,
+    pub p95_ms: f64,
+}
+
+#[derive(Default)]
+struct CommandTimings {
+    count: usize,
+    failures: usize,
+    queue_timeouts: usize,
+    flow_timeouts: usize,
+    // (queue wait, flow turnaround) of the latest answered commands
+    recent: VecDeque<(Duration, Duration)>,
+}
+impl CommandTimings {
+    fn stats(&self) -> CommandStats {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.;
+        let n = self.recent.len().max(1) as f64;
+        let totals = self
+            .recent
+            .iter()
+            .map(|(queue, flow)| ms(*queue + *flow))
+            .sorted_by(f64::total_cmp)
+            .collect_vec();
+        let p95 = match totals.len() {
+            0 => 0.,
+            len => totals[((len as f64 * 0.95).ceil() as usize).clamp(1, len) - 1],
+        };
+        CommandStats {
+            count: self.count,
+            failures: self.failures,
+            queue_timeouts: self.queue_timeouts,
+            flow_timeouts: self.flow_timeouts,
+            mean_queue_ms: self.recent.iter().map(|(q, _)| ms(*q)).sum::<f64>() / n,
+            mean_turnaround_ms: self.recent.iter().map(|(_, f)| ms(*f)).sum::<f64>() / n,
+            mean_ms: totals.iter().sum::<f64>() / n,
+            p95_ms: p95,
+        }
+    }
+}
+
+// times a command from `execute` until it is answered. a caller's timeout drops it unfinished,
+// which is counted against whichever side was holding the command
+struct CommandTimer<'a> {
+    shared: &'a Mutex<ServerState>,
+    name: String,
+    id: u64,
+    start: Instant,
+    succeeded: Option<bool>,
+}
+impl Drop for CommandTimer<'_> {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.shared.lock() else {
+            return;
+        };
+        let picked_up = state.picked_up.remove(&self.id);
+        let timings = state.timings.entry(self.name.clone()).or_default();
+        timings.count += 1;
+        match (self.succeeded, picked_up) {
+            (None, None) => timings.queue_timeouts += 1,
+            (None, Some(_)) => timings.flow_timeouts += 1,
+            (Some(succeeded), picked_up) => {
+                timings.failures += usize::from(!succeeded);
+                let now = Instant::now();
+                let picked_up = picked_up.unwrap_or(now).clamp(self.start, now);
+                timings
+                    .recent
+                    .push_back((picked_up - self.start, now - picked_up));
+                if timings.recent.len() > COMMAND_TIMING_WINDOW {
+                    timings.recent.pop_front();
+                }
+            }
+        }
+    }
+}
+
+struct ServerState {
+    channel_recv: mpsc::Receiver<ChannelData>,
+    // commands handed to the flow, in the order it picked them up
+    pending: VecDeque<PendingCommand>,
+    // commands sent over a WebSocket, tagged with the connection that carried them
+    ws_pending: Vec<(u64, PendingCommand)>,
+    ws_client: Option<WsClient>,
+    ws_connections: u64,
+    // when each flow instance last polled
+    instances: BTreeMap<String, Instant>,
+    // when the flow took each command still being timed
+    picked_up: BTreeMap<u64, Instant>,
+    timings: BTreeMap<String, CommandTimings>,
+}
+impl ServerState {
+    fn stats(&self) -> BTreeMap<String, CommandStats> {
+        self.timings
+            .iter()
+            .map(|(name, timings)| (name.clone(), timings.stats()))
+            .collect()
+    }
+    // the caller may have timed out and dropped its receiver, so send failures are ignored
+    fn respond(&mut self, id: u64, instance: Option<String>, response: String) -> StatusCode {
+        let Some(i) = self.pending.iter().position(|p| p.id == id) else {
+            return StatusCode::OK;
+        };
+        if self.pending[i].instance.is_some() && self.pending[i].instance != instance {
+            return StatusCode::CONFLICT;
+        }
+        let pending = self.pending.remove(i).unwrap();
+        pending.response.send(response).ok();
+        StatusCode::OK
+    }
+    // flows that don't echo the request id answer their own commands in order
+    fn respond_next(&mut self, instance: Option<String>, response: String) -> StatusCode {
+        match self.pending.iter().position(|p| p.instance == instance) {
+            Some(i) => {
+                let pending = self.pending.remove(i).unwrap();
+                pending.response.send(response).ok();
+                StatusCode::OK
+            }
+            None if self.pending.is_empty() => StatusCode::OK,
+            None => StatusCode::CONFLICT,
+        }
+    }
+    fn respond_ws(&mut self, connection: u64, id: u64, response: String) {
+        let Some(i) = self
+            .ws_pending
+            .iter()
+            .position(|(c, p)| *c == connection && p.id == id)
+        else {
+            return;
+        };
+        let (_, pending) = self.ws_pending.remove(i);
+        pending.response.send(response).ok();
+    }
+    // dropping the senders wakes the commands still waiting on this connection
+    fn disconnect_ws(&mut self, connection: u64) {
+        self.ws_pending.retain(|(c, _)| *c != connection);
+        if self
+            .ws_client
+            .as_ref()
+            .is_some_and(|client| client.connection == connection)
+        {
+            self.ws_client = None;
+        }
+    }
+    fn forget(&mut self, id: u64) {
+        self.pending.retain(|p| p.id != id);
+        self.ws_pending.retain(|(_, p)| p.id != id);
+    }
+    fn seen(&mut self, instance: &Option<String>) {
+        let Some(instance) = instance else {
+            return;
+        };
+        let window = Duration::from_secs_f64(DUPLICATE_INSTANCE_WINDOW_S);
+        self.instances.retain(|_, at| at.elapsed() < window);
+        let is_new = self
+            .instances
+            .insert(instance.clone(), Instant::now())
+            .is_none();
+        if is_new && self.instances.len() > 1 {
+            eprintln!(
+                "WARNING: {} flow instances are polling the bridge ({}), stop all but one",
+                self.instances.len(),
+                self.instances.keys().join(", ")
+            );
+        }
+    }
+}
+
+pub struct PowerAutomate {
+    _handle: JoinHandle<Result<(), hyper::Error>>,
+    local_addr: SocketAddr,
+    command_timeout: Duration,
+    channel_send: mpsc::Sender<ChannelData>,
+    shared: Arc<Mutex<ServerState>>,
+    next_id: AtomicU64,
+    // held for the length of an acquisition, so two drivers sharing the bridge can't interleave
+    // window reads and wavegen toggles
+    acquisition: Arc<AsyncMutex<()>>,
+}
+macro_rules! pa_fn {
+    ($name:ident($($arg:ident: $typ:ty),*) -> $res:ty) => {
+        pub async fn $name(&self, $($arg: $typ),*) -> $res{
+            let command = json!({
+                "command": stringify!($name),
+                $(stringify!($arg): $arg),*
+            });
+            self.execute(command).await
+        }
+    };
+}
+impl PowerAutomate {
+    pa_fn!(wavegen_is_running() -> Result<bool>);
+    pa_fn!(wavegen_toggle_running() -> Result<()>);
+    pa_fn!(wavegen_set_trapezium() -> Result<()>);
+    pa_fn!(wavegen_set_custom(samples_csv: &str, append: bool) -> Result<()>);
+    pa_fn!(wavegen_custom_max_samples() -> Result<usize>);
+    pa_fn!(wavegen_set_period(period: f64) -> Result<()>);
+    pa_fn!(wavegen_set_amplitude(amplitude: f64) -> Result<()>);
+    pa_fn!(wavegen_set_offset(offset: f64) -> Result<()>);
+    pa_fn!(wavegen_set_symmetry(symmetry: f64) -> Result<()>);
+    pa_fn!(wavegen_get_period() -> Result<f64>);
+    pa_fn!(wavegen_get_amplitude() -> Result<f64>);
+    pa_fn!(wavegen_get_offset() -> Result<f64>);
+    pa_fn!(wavegen_get_symmetry() -> Result<f64>);
+    pa_fn!(nanonis_save_history(folder: &str, filename: &str) -> Result<()>);
+    pa_fn!(nanonis_open_history() -> Result<()>);
+    pa_fn!(launch_application(path: &str) -> Result<()>);
+    pa_fn!(is_window_open(title: &str, class: &str) -> Result<bool>);
+    pa_fn!(get_open_window() -> Result<String>);
+    pa_fn!(list_open_windows() -> Result<Vec<String>>);
+    pa_fn!(focus_window(title: &str, class: &str) -> Result<()>);
+    pa_fn!(echo(message: &str) -> Result<String>);
+    pa_fn!(show_notification(title: &str, message: &str) -> Result<()>);
+    pa_fn!(read_window_value(window: &str, field: &str) -> Result<f64>);
+    async fn stop_wavegen(&self) -> Result<()> {
+        if self.get_open_window().await? != WAVEFORMS_WINDOW {
+            self.focus_window(WAVEFORMS_WINDOW, "").await?;
+        }
+        self.set_wavegen_running(false).await?;
+        Ok(())
+    }
+    // returns whether the wavegen had to be toggled
+    async fn set_wavegen_running(&self, running: bool) -> Result<bool> {
+        if self.wavegen_is_running().await? == running {
+            return Ok(false);
+        }
+        let mut observed = !running;
+        for _ in 0..WAVEGEN_TOGGLE_ATTEMPTS {
+            self.wavegen_toggle_running().await?;
+            tokio::time::sleep(Duration::from_secs_f64(WAVEGEN_TOGGLE_SETTLE_S)).await;
+            observed = self.wavegen_is_running().await?;
+            if observed == running {
+                return Ok(true);
+            }
+        }
+        bail!(
+            "Wavegen running state is {observed} after {WAVEGEN_TOGGLE_ATTEMPTS} toggles, wanted {running}"
+        );
+    }
+    pub async fn ping(&self) -> Result<()> {
+        let message = format!(
+            "ping{}",
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_millis()
+        );
+        let timeout = Duration::from_secs_f64(PING_TIMEOUT_S);
+        let resp = tokio::time::timeout(timeout, self.echo(&message))
+            .await
+            .context("Power automate flow did not respond to ping")??;
+        if resp != message {
+            bail!("Power automate flow echoed `{resp}` instead of `{message}`");
+        }
+        Ok(())
+    }
+    pub fn new() -> Self {
+        Self::bind(DEFAULT_BIND_ADDR.parse().unwrap())
+    }
+    pub fn bind(addr: SocketAddr) -> Self {
+        let (channel_send, channel_recv) = mpsc::channel(1);
+        let shared = Arc::new(Mutex::new(ServerState {
+            channel_recv,
+            pending: VecDeque::new(),
+            ws_pending: Vec::new(),
+            ws_client: None,
+            ws_connections: 0,
+            instances: BTreeMap::new(),
+            picked_up: BTreeMap::new(),
+            timings: BTreeMap::new(),
+        }));
+        let (shared_get, shared_post, shared_post_id, shared_ws, shared_stats) = (
+            shared.clone(),
+            shared.clone(),
+            shared.clone(),
+            shared.clone(),
+            shared.clone(),
+        );
+        let app = Router::new()
+            .route(
+                "/",
+                get(move |Query(query): Query<InstanceQuery>| {
+                    let mut state = shared_get.lock().unwrap();
+                    state.seen(&query.instance);
+                    // commands whose caller timed out while they were queued are dropped
+                    let mut next = state.channel_recv.try_recv();
+                    while matches!(&next, Ok((_, response)) if response.is_closed()) {
+                        next = state.channel_recv.try_recv();
+                    }
+                    let a = match next {
+                        Ok((command, response)) => {
+                            let id = serde_json::from_str::<serde_json::Value>(&command)
+                                .ok()
+                                .and_then(|c| c["id"].as_u64())
+                                .unwrap_or_default();
+                            state.picked_up.insert(id, Instant::now());
+                            state.pending.push_back(PendingCommand {
+                                id,
+                                instance: query.instance,
+                                response,
+                            });
+                            command
+                        }
+                        Err(TryRecvError::Empty) => "".to_string(),
+                        e => unimplemented!("{e:?}"),
+                    };
+                    ready(a)
+                }),
+            )
+            .route(
+                "/",
+                post(move |Query(query): Query<InstanceQuery>, body: String| {
+                    let mut state = shared_post.lock().unwrap();
+                    state.seen(&query.instance);
+                    ready(state.respond_next(query.instance, body))
+                }),
+            )
+            .route(
+                "/:id",
+                post(
+                    move |AxumPath(id): AxumPath<u64>,
+                          Query(query): Query<InstanceQuery>,
+                          body: String| {
+                        let mut state = shared_post_id.lock().unwrap();
+                        state.seen(&query.instance);
+                        ready(state.respond(id, query.instance, body))
+                    },
+                ),
+            )
+            .route(
+                "/stats",
+                get(move || ready(Json(shared_stats.lock().unwrap().stats()))),
+            )
+            .route(
+                "/ws",
+                get(move |ws: WebSocketUpgrade| {
+                    let shared = shared_ws.clone();
+                    ready(ws.on_upgrade(move |socket| serve_websocket(socket, shared)))
+                }),
+            );
+        let server = axum::Server::bind(&addr).serve(app.into_make_service());
+        let local_addr = server.local_addr();
+        let _handle = tokio::spawn(server);
+        Self {
+            _handle,
+            local_addr,
+            command_timeout: Duration::from_secs_f64(COMMAND_TIMEOUT_S),
+            channel_send,
+            shared,
+            next_id: AtomicU64::new(1),
+            acquisition: Arc::new(AsyncMutex::new(())),
+        }
+    }
+    // waits for any acquisition in progress to finish, or fails straight away if `wait` is unset
+    pub async fn lock_acquisition(&self, wait: bool) -> Result<OwnedMutexGuard<()>> {
+        if let Ok(lock) = self.acquisition.clone().try_lock_owned() {
+            return Ok(lock);
+        }
+        if !wait {
+            bail!(AlreadyAcquiring);
+        }
+        println!("Waiting for another acquisition to finish");
+        Ok(self.acquisition.clone().lock_owned().await)
+    }
+    // the address the bridge is listening on, useful when bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+    pub fn websocket_connected(&self) -> bool {
+        self.shared.lock().unwrap().ws_client.is_some()
+    }
+    pub async fn wait_for_websocket(&self, grace: Duration) -> bool {
+        let start = Instant::now();
+        while !self.websocket_connected() {
+            if start.elapsed() >= grace {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        true
+    }
+    // round trip statistics of every command sent so far, by command name
+    pub fn stats(&self) -> BTreeMap<String, CommandStats> {
+        self.shared.lock().unwrap().stats()
+    }
+    async fn execute<R: DeserializeOwned>(&self, mut command: serde_json::Value) -> Result<R> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        command["id"] = id.into();
+        let mut timer = CommandTimer {
+            shared: &self.shared,
+            name: command["command"].as_str().unwrap_or_default().to_string(),
+            id,
+            start: Instant::now(),
+            succeeded: None,
+        };
+        // a timed out command is left unresolved so the timer counts it as a timeout
+        let Ok(res) =
+            tokio::time::timeout(self.command_timeout, self.send_command(id, command)).await
+        else {
+            self.shared.lock().unwrap().forget(id);
+            bail!(
+                "Power automate flow did not answer `{}` within {:?}",
+                timer.name,
+                self.command_timeout
+            );
+        };
+        timer.succeeded = Some(res.is_ok());
+        res
+    }
+    async fn send_command<R: DeserializeOwned>(
+        &self,
+        id: u64,
+        command: serde_json::Value,
+    ) -> Result<R> {
+        let command_str = serde_json::to_string(&command).unwrap();
+        let resp = self.exchange(id, command_str).await?;
+        // println!("{command}: {resp:?}");
+        let value = serde_json::from_str::<serde_json::Value>(&resp)
+            .with_context(|| format!("Power automate returned invalid json: {resp}"))?;
+        let res = match serde_json::from_value::<Result<R, ServerError>>(value.clone()) {
+            Ok(res) => res,
+            Err(e) => serde_json::from_value(locale_numbers(value))
+                .map_err(|_| e)
+                .with_context(|| format!("Unexpected power automate response: {resp}"))?,
+        };
+        res.context("Power automate returned an error")
+    }
+    async fn exchange(&self, id: u64, command: String) -> Result<String> {
+        if let Some(recv) = self.send_websocket(id, &command) {
+            match recv.await {
+                Ok(resp) => return Ok(resp),
+                Err(_) => eprintln!(
+                    "WARNING: WebSocket client disconnected before answering command {id}, resending it over polling"
+                ),
+            }
+        }
+        let (send, recv) = oneshot::channel();
+        self.channel_send
+            .send((command, send))
+            .await
+            .ok()
+            .context("Bridge server has stopped")?;
+        let resp = recv
+            .await
+            .ok()
+            .context("Bridge dropped the command before the flow answered it")?;
+        // polling responses come back form encoded with python-style booleans
+        Ok(url_escape::decode(&resp)
+            .replace("+", " ")
+            .replace("\r\n", "\\n")
+            .replace("False", "false")
+            .replace("True", "true"))
+    }
+    // the pending entry is queued under the same lock as the send, so the answer can't beat it
+    fn send_websocket(&self, id: u64, command: &str) -> Option<oneshot::Receiver<String>> {
+        let mut state = self.shared.lock().unwrap();
+        let client = state.ws_client.as_ref()?;
+        let connection = client.connection;
+        client.commands.send(command.to_string()).ok()?;
+        let (send, recv) = oneshot::channel();
+        state.ws_pending.push((
+            connection,
+            PendingCommand {
+                id,
+                instance: None,
+                response: send,
+            },
+        ));
+        state.picked_up.insert(id, Instant::now());
+        Some(recv)
+    }
+}
+// flows can hand numbers back as text formatted for the PC's locale, e.g. "0,5"
+fn locale_numbers(value: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        Value::String(s) => match (s.trim().parse::<i64>(), parse_decimal(s.trim())) {
+            (Ok(n), _) => n.into(),
+            (_, Some(v)) if v.is_finite() => v.into(),
+            _ => Value::String(s),
+        },
+        Value::Array(values) => values.into_iter().map(locale_numbers).collect(),
+        Value::Object(map) => map
+            .into_iter()
+            .map(|(k, v)| (k, locale_numbers(v)))
+            .collect(),
+        value => value,
+    }
+}
+// commands go out as text frames, and come back as `{"id": .., "response": ..}` frames
+async fn serve_websocket(mut socket: WebSocket, shared: Arc<Mutex<ServerState>>) {
+    let (client, mut commands) = mpsc::unbounded_channel();
+    let connection = {
+        let mut state = shared.lock().unwrap();
+        state.ws_connections += 1;
+        let connection = state.ws_connections;
+        state.ws_client = Some(WsClient {
+            connection,
+            commands: client,
+        });
+        connection
+    };
+    loop {
+        tokio::select! {
+            Some(command) = commands.recv() => {
+                if socket.send(Message::Text(command)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let Ok(frame) = serde_json::from_str::<serde_json::Value>(&text) else {
+                        continue;
+                    };
+                    if let Some(id) = frame["id"].as_u64() {
+                        let response = frame["response"].to_string();
+                        shared.lock().unwrap().respond_ws(connection, id, response);
+                    }
+                }
+                Some(Ok(_)) => {}
+                _ => break,
+            },
+        }
+    }
+    shared.lock().unwrap().disconnect_ws(connection);
+}
+
+impl Default for PowerAutomate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize, thiserror::Error)]
+#[serde(from = "RawServerError", into = "RawServerError")]
+pub enum ServerError {
+    #[error("{message}")]
+    WindowNotFound { title: String, message: String },
+    #[error("{message}")]
+    ControlNotReady { message: String },
+    #[error("{message}")]
+    CommandUnknown { command: String, message: String },
+    #[error("{0}")]
+    Other(String),
+}
+impl ServerError {
+    // errors that may clear up if the same command is sent again
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::ControlNotReady { .. })
+    }
+}
+
+// the flow reports `{code, message}` objects, older flows a bare message string
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+enum RawServerError {
+    Coded {
+        code: String,
+        #[serde(default)]
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        command: Option<String>,
+    },
+    Message(String),
+}
+impl From<RawServerError> for ServerError {
+    fn from(raw: RawServerError) -> Self {
+        let (code, message, title, command) = match raw {
+            RawServerError::Coded {
+                code,
+                message,
+                title,
+                command,
+            } => (code, message, title, command),
+            RawServerError::Message(message) => return Self::Other(message),
+        };
+        match code.as_str() {
+            "window_not_found" => Self::WindowNotFound {
+                title: title.unwrap_or_default(),
+                message,
+            },
+            "control_not_ready" => Self::ControlNotReady { message },
+            "command_unknown" => Self::CommandUnknown {
+                command: command.unwrap_or_default(),
+                message,
+            },
+            _ => Self::Other(message),
+        }
+    }
+}
+impl From<ServerError> for RawServerError {
+    fn from(err: ServerError) -> Self {
+        let coded = |code: &str, message, title, command| Self::Coded {
+            code: code.into(),
+            message,
+            title,
+            command,
+        };
+        match err {
+            ServerError::WindowNotFound { title, message } => {
+                coded("window_not_found", message, Some(title), None)
+            }
+            ServerError::ControlNotReady { message } => {
+                coded("control_not_ready", message, None, None)
+            }
+            ServerError::CommandUnknown { command, message } => {
+                coded("command_unknown", message, None, Some(command))
+            }
+            ServerError::Other(message) => Self::Message(message),
+        }
+    }
+}
+
+// stands in for the Power Automate flow, for tests here and in the modules built on the driver
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+
+    pub(crate) type Answer = Box<dyn Fn(&serde_json::Value) -> Option<serde_json::Value> + Send>;
+
+    // answers commands straight off the bridge's queue, standing in for the flow, and records the
+    // name of each one. an unanswered command is dropped
+    pub(crate) fn fake_flow(
+        driver: &AquisitionDriver,
+        answer: Answer,
+    ) -> (JoinHandle<()>, Arc<Mutex<Vec<String>>>) {
+        fake_flow_after(driver, answer, Duration::ZERO)
+    }
+
+    // `fake_flow` taking `delay` to answer each command, working on any number at once
+    pub(crate) fn fake_flow_after(
+        driver: &AquisitionDriver,
+        answer: Answer,
+        delay: Duration,
+    ) -> (JoinHandle<()>, Arc<Mutex<Vec<String>>>) {
+        let shared = driver.pa.shared.clone();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let recorded = seen.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                let next = shared.lock().unwrap().channel_recv.try_recv();
+                let Ok((command, response)) = next else {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    continue;
+                };
+                let command = serde_json::from_str::<serde_json::Value>(&command).unwrap();
+                let name = command["command"].as_str().unwrap_or_default().to_string();
+                recorded.lock().unwrap().push(name);
+                let Some(answer) = answer(&command) else {
+                    continue;
+                };
+                if delay.is_zero() {
+                    response.send(answer.to_string()).ok();
+                    continue;
+                }
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    response.send(answer.to_string()).ok();
+                });
+            }
+        });
+        (handle, seen)
+    }
+
+    // a driver on its own bridge
+    pub(crate) fn bridged_driver(config: DriverConfig) -> AquisitionDriver {
+        let pa = PowerAutomate::bind("127.0.0.1:0".parse().unwrap())
+            .with_command_timeout(Duration::from_secs(2));
+        AquisitionDriver::from_parts(config, Rc::new(pa))
+    }
+
+    pub(crate) fn acknowledge() -> Answer {
+        Box::new(|_| Some(json!({ "Ok": null })))
+    }
+
+    // WaveForms focused with the wavegen already running, so starting it doesn't toggle anything
+    // and stopping it takes one toggle
+    pub(crate) fn running_waveforms() -> Answer {
+        let running = AtomicBool::new(true);
+        Box::new(move |command| {
+            Some(match command["command"].as_str()? {
+                "get_open_window" => json!({ "Ok": WAVEFORMS_WINDOW }),
+                "wavegen_is_running" => json!({ "Ok": running.load(Ordering::Relaxed) }),
+                "wavegen_toggle_running" => {
+                    running.fetch_xor(true, Ordering::Relaxed);
+                    json!({ "Ok": null })
+                }
+                _ => json!({ "Ok": null }),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fake::*, *};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn locale_numbers_are_read_from_text() {
+        let response = json!({
+            "Ok": {"pkpk": "0,5", "period": "2", "name": "Ch 1", "values": ["1,25", 3]}
+        });
+        assert_eq!(
+            locale_numbers(response),
+            json!({
+                "Ok": {"pkpk": 0.5, "period": 2, "name": "Ch 1", "values": [1.25, 3]}
+            })
+        );
+        assert_eq!(locale_numbers("1,234.5".into()), "1,234.5");
+    }
+
+    #[test]
+    fn values_are_rounded_to_significant_digits() {
+        assert_eq!(round_significant(1.5432 + 1e-12, 6), 1.5432);
+        assert_eq!(round_significant(0.1 + 0.2, 6), 0.3);
+        assert_eq!(round_significant(123456.7, 3), 123000.);
+        assert_eq!(round_significant(-0.000123456, 2), -0.00012);
+        assert_eq!(round_significant(0., 3), 0.);
+        assert!(round_significant(f64::NAN, 3).is_nan());
+    }
+
+    #[test]
+    fn sent_settings_are_what_the_fields_hold() {
+        let config = DriverConfig {
+            wavegen_gain: 38.5,
+            wavegen_digits: 4,
+            ..Default::default()
+        };
+        let amplitude = config.field_value(WavegenField::Amplitude, 100.);
+        assert_eq!(format_attribute(amplitude), "1.299");
+        let settings = WavegenSettings {
+            pkpk: 100.,
+            period: Duration::from_secs_f64(0.123456789),
+            symmetry_p: 33.33333,
+            offset: 0.,
+        };
+        let sent = config.sent_settings(settings).unwrap();
+        assert_eq!(sent.pkpk, 1.299 * 38.5 * 2.);
+        assert_eq!(sent.period, Duration::from_secs_f64(0.1235));
+        assert_eq!(sent.symmetry_p, 33.33);
+        assert_eq!(sent.offset, 0.);
+        assert!(settings_match(settings.pkpk, sent.pkpk));
+    }
+
+    // runs the correction against an amplifier with `gain` instead of the nominal one, sending
+    // the pkpk through the wavegen's rounding, and returns where it stopped
+    fn correct_against(gain: f64, target: f64) -> (CorrectedAmplitude, CorrectionStep) {
+        let config = DriverConfig::default();
+        let correction = AmplitudeCorrection::default();
+        let amplifier =
+            |pkpk| config.sent_value(WavegenField::Amplitude, pkpk) * gain / WAVEGEN_GAIN;
+        let mut commanded = target;
+        for iterations in 0.. {
+            let corrected = CorrectedAmplitude {
+                commanded,
+                achieved: amplifier(commanded),
+                iterations,
+            };
+            match correction.step(target, config.max_pkpk(0.), corrected) {
+                CorrectionStep::Command(next) => commanded = next,
+                step => return (corrected, step),
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn low_amplifier_gain_is_corrected_within_two_iterations() {
+        let (corrected, step) = correct_against(38.5, 200.);
+        assert_eq!(step, CorrectionStep::Done);
+        assert!(corrected.iterations <= 2, "{corrected:?}");
+        assert!((corrected.achieved - 200.).abs() <= 2., "{corrected:?}");
+        assert!(
+            (corrected.commanded - 200. * 40. / 38.5).abs() < 0.1,
+            "{corrected:?}"
+        );
+    }
+
+    #[test]
+    fn correction_stops_at_the_output_limit() {
+        let max = DriverConfig::default().max_pkpk(0.);
+        let (corrected, step) = correct_against(38.5, max);
+        assert_eq!(corrected.commanded, max);
+        assert!(matches!(step, CorrectionStep::Stop(ref m) if m.contains("output limit")));
+    }
+
+    #[test]
+    fn ramp_time_inverts_set_ramp_time() {
+        let mut settings = WavegenSettings::default();
+        for (ramp, rest) in [(1., 4.), (2.5, 0.5), (120., 240.)] {
+            let (ramp, rest) = (Duration::from_secs_f64(ramp), Duration::from_secs_f64(rest));
+            settings.set_ramp_time(ramp, rest);
+            let (found_ramp, found_rest) = settings.ramp_time().unwrap();
+            assert!((found_ramp.as_secs_f64() - ramp.as_secs_f64()).abs() < 1e-9);
+            assert!((found_rest.as_secs_f64() - rest.as_secs_f64()).abs() < 1e-9);
+        }
+        settings.symmetry_p = 100.;
+        assert_eq!(settings.ramp_time(), None);
+        settings.set_ramp_time(Duration::from_secs(1), Duration::ZERO);
+        assert_eq!(settings.ramp_time(), None);
+    }
+
+    #[test]
+    fn long_captures_error_instead_of_overflowing() {
+        let ramp = Duration::from_secs(480);
+        assert_eq!(periods(ramp, 1000).unwrap(), Duration::from_secs(480_000));
+        assert!(periods(Duration::MAX / 2, 3).is_err());
+        assert!(periods(Duration::from_secs(1), usize::MAX).is_err());
+        let config = DriverConfig::default();
+        let settings = WavegenSettings {
+            period: Duration::MAX / 4,
+            ..Default::default()
+        };
+        assert!(config.history_windows(settings, usize::MAX - 1) > 1);
+    }
+
+    #[test]
+    fn progress_position_is_clamped_to_the_total() {
+        let total = Duration::from_secs(130);
+        assert_eq!(progress_position(Duration::ZERO, total), 0);
+        assert_eq!(progress_position(Duration::from_millis(65_050), total), 650);
+        assert_eq!(progress_position(total, total), 1300);
+        assert_eq!(progress_position(total * 3, total), 1300);
+    }
+
+    #[test]
+    fn windows_cover_the_buffered_capture() {
+        let config = DriverConfig {
+            history_window: Duration::from_secs(125),
+            window_buffer: Duration::from_secs(5),
+            ..Default::default()
+        };
+        assert_eq!(config.window_stride(), Duration::from_secs(120));
+        for (total_s, windows) in [(0, 1), (125, 1), (126, 2), (245, 2), (246, 3)] {
+            let total = Duration::from_secs(total_s);
+            assert_eq!(config.window_count(total), windows, "{total_s} s");
+        }
+    }
+
+    #[tokio::test]
+    async fn progress_shows_the_applied_settings() {
+        let pa = PowerAutomate::bind("127.0.0.1:0".parse().unwrap());
+        let mut driver = AquisitionDriver::from_parts(DriverConfig::default(), Rc::new(pa));
+        assert_eq!(driver.settings_label(), "");
+        driver.pkpk = Some(200.);
+        driver.period = Some(Duration::from_millis(2500));
+        driver.symmetry = Some(50.);
+        assert_eq!(driver.settings_label(), "200 V pkpk, 2.5 s, 50% symmetry");
+    }
+
+    // a history window with one channel, sampled every millisecond
+    fn history(signal: impl IntoIterator<Item = f64>) -> DatFile {
+        DatFile {
+            attributes: BTreeMap::from([("Sample Period (ms)".into(), "1".into())]),
+            signals: BTreeMap::from([("Current (A)".into(), signal.into_iter().collect())]),
+        }
+    }
+
+    fn window(datfile: Option<DatFile>, read_at: Instant, seam: Seam) -> Window {
+        Window {
+            datfile,
+            read_at,
+            seam,
+            seam_duplicates: 0,
+            stepped_at: None,
+        }
+    }
+
+    // current counting up from `from`, and the voltage at twice that
+    fn two_channels(from: i32, to: i32) -> DatFile {
+        let mut datfile = history((from..to).map(f64::from));
+        let voltage = (from..to).map(|i| f64::from(i) * 2.).collect();
+        datfile.signals.insert("Voltage (V)".into(), voltage);
+        datfile
+    }
+
+    #[test]
+    fn appending_keeps_only_the_shared_channels() {
+        let mut first = two_channels(0, 3);
+        first.signals.insert("Bias (V)".into(), vec![1.; 3]);
+        let mut next = two_channels(3, 5);
+        next.signals.insert("Z (m)".into(), vec![2.; 2]);
+        let mut dropped = BTreeSet::new();
+        let joined = append_datfile(first, next, &mut dropped);
+        assert_eq!(
+            joined.signals.keys().collect_vec(),
+            ["Current (A)", "Voltage (V)"]
+        );
+        assert_eq!(joined.signals["Current (A)"], [0., 1., 2., 3., 4.]);
+        assert_eq!(dropped, BTreeSet::from(["Bias (V)".into(), "Z (m)".into()]));
+    }
+
+    #[test]
+    fn recordings_are_checked_for_clipping_by_channel() {
+        let mut datfile = two_channels(0, 100);
+        let current = datfile.signals.remove("Current (A)").unwrap();
+        let pinned = current.iter().map(|v| v.min(50.)).collect_vec();
+        datfile.signals.insert("Current Input (A)".into(), pinned);
+        let limits = ChannelLimits {
+            rails: BTreeMap::from([(Channel::Current, Some((0., 50.))), (Channel::Probe, None)]),
+            ..Default::default()
+        };
+        // there is no probe channel to check
+        let reports = clip_reports(&datfile, &limits, 1.);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].channel, "Current Input (A)");
+        assert_eq!(reports[0].fraction, 0.5);
+    }
+
+    #[test]
+    fn duplicated_seam_is_removed_on_every_channel() {
+        let first = two_channels(0, 10);
+        // the overlap match landed two samples early
+        let mut next = two_channels(8, 15);
+        assert_eq!(dedup_seam(&first, &mut next, 5), 2);
+        assert_eq!(next.signals["Current (A)"], [10., 11., 12., 13., 14.]);
+        assert_eq!(next.signals["Voltage (V)"], [20., 22., 24., 26., 28.]);
+
+        // longer than the limit is left alone
+        let mut next = two_channels(8, 15);
+        assert_eq!(dedup_seam(&first, &mut next, 1), 0);
+        assert_eq!(next.signals["Current (A)"].len(), 7);
+
+        // a repeat on one channel only is real data
+        let mut next = two_channels(8, 15);
+        next.signals.get_mut("Voltage (V)").unwrap()[0] = 0.;
+        assert_eq!(dedup_seam(&first, &mut next, 5), 0);
+
+        let mut clean = two_channels(10, 15);
+        assert_eq!(dedup_seam(&first, &mut clean, 5), 0);
+    }
+
+    // 1000 ms of a counting current, and a 100 ms voltage sine rising through zero at 30.5 ms
+    fn untrimmed() -> DatFile {
+        let mut datfile = history((0..1000).map(f64::from));
+        let tau = std::f64::consts::TAU;
+        let voltage = (0..1000)
+            .map(|i| (tau * (i as f64 - 30.5) / 100.).sin())
+            .collect();
+        datfile.signals.insert("Voltage (V)".into(), voltage);
+        datfile
+    }
+
+    #[test]
+    fn each_trim_policy_keeps_its_samples() {
+        let duration = Duration::from_millis(800);
+        let cases = [
+            (TrimPolicy::None, 300, 0, 1000, 0),
+            (TrimPolicy::LeadingExcess, 300, 200, 800, 200),
+            (TrimPolicy::TrailingExcess, 300, 0, 800, 200),
+            (
+                TrimPolicy::WholePeriods {
+                    align: PhaseAlign::Start,
+                },
+                300,
+                200,
+                600,
+                400,
+            ),
+            (
+                TrimPolicy::WholePeriods {
+                    align: PhaseAlign::RisingCrossing,
+                },
+                100,
+                231,
+                700,
+                300,
+            ),
+        ];
+        for (trim, period_ms, first, kept, trimmed) in cases {
+            let mut datfile = untrimmed();
+            let mut warnings = vec![];
+            let period = Duration::from_millis(period_ms);
+            let removed =
+                trim_recording(&mut datfile, trim, duration, period, false, &mut warnings).unwrap();
+            let current = &datfile.signals["Current (A)"];
+            assert_eq!(
+                (current[0], current.len(), removed),
+                (first as f64, kept, trimmed),
+                "{trim}"
+            );
+            assert_eq!(datfile.signals["Voltage (V)"].len(), kept, "{trim}");
+            assert_eq!(datfile.attributes["trim_policy"], trim.to_string());
+            assert_eq!(datfile.attributes["trimmed_samples"], trimmed.to_string());
+            assert!(warnings.is_empty(), "{trim}: {warnings:?}");
+        }
+    }
+
+    #[test]
+    fn overlap_is_stripped_or_reported_missing() {
+        let first = history((0..10).map(f64::from));
+        let next = strip_overlap(&first, history((5..15).map(f64::from))).unwrap();
+        assert_eq!(
+            next.signals["Current (A)"],
+            (10..15).map(f64::from).collect_vec()
+        );
+        assert!(strip_overlap(&first, history((20..30).map(f64::from))).is_none());
+    }
+
+    #[test]
+    fn unmatched_windows_keep_the_samples_since_the_last_read() {
+        let kept = keep_latest(history((0..10).map(f64::from)), Duration::from_millis(3)).unwrap();
+        assert_eq!(kept.signals["Current (A)"], [7., 8., 9.]);
+        let kept = keep_latest(history([1., 2.]), Duration::from_secs(1)).unwrap();
+        assert_eq!(kept.signals["Current (A)"], [1., 2.]);
+    }
+
+    #[test]
+    fn only_transient_server_errors_are_retried() {
+        let not_ready = ServerError::ControlNotReady {
+            message: "busy".into(),
+        };
+        let missing = ServerError::WindowNotFound {
+            title: "History".into(),
+            message: "no such window".into(),
+        };
+        assert!(is_retryable(&anyhow::Error::new(not_ready)));
+        assert!(!is_retryable(&anyhow::Error::new(missing.clone())));
+        assert!(!is_retryable(
+            &anyhow::Error::new(missing).context("Could not save the history")
+        ));
+        assert!(is_retryable(&anyhow::anyhow!("save timed out")));
+    }
+
+    #[test]
+    fn window_stats_count_skips_gaps_and_unmatched_seams() {
+        let start = Instant::now();
+        let at = |s: u64| start + Duration::from_secs(s);
+        let mut stats = WindowStats::default();
+        let windows = [
+            window(Some(history([0.])), at(0), Seam::Joined),
+            window(None, at(100), Seam::Joined),
+            window(Some(history([1.])), at(200), Seam::Gap),
+            window(Some(history([2.])), at(300), Seam::Unmatched),
+        ];
+        let read = windows.into_iter().filter_map(|w| stats.add(w)).count();
+        assert_eq!(read, 3);
+        let info = stats.info(Local::now(), 0, 0, vec![]);
+        assert_eq!(
+            (
+                info.windows,
+                info.skipped_windows,
+                info.gaps,
+                info.unmatched_seams
+            ),
+            (3, 1, 1, 1)
+        );
+        assert_eq!(
+            info.window_intervals,
+            [Duration::from_secs(200), Duration::from_secs(100)]
+        );
+    }
+
+    #[test]
+    fn window_gaps_are_recorded_in_the_attributes() {
+        let mut stats = WindowStats::default();
+        let now = Instant::now();
+        stats.add(window(Some(history([0.])), now, Seam::Joined));
+        stats.add(window(None, now, Seam::Joined));
+        stats.add(window(Some(history([1.])), now, Seam::Gap));
+        let mut attributes = BTreeMap::new();
+        let mut warnings = vec![];
+        record_window_gaps(
+            &DriverConfig::default(),
+            &mut attributes,
+            &stats,
+            &mut warnings,
+        );
+        assert_eq!(attributes["windows"], "2");
+        assert_eq!(attributes["skipped_windows"], "1");
+        assert_eq!(attributes["record_gaps"], "1");
+        assert_eq!(attributes["unmatched_seams"], "0");
+        assert_eq!(warnings.len(), 2);
+    }
+
+    // a wavegen holding `settings` as WaveForms would show them
+    fn device(config: &DriverConfig, settings: WavegenSettings, running: bool) -> Answer {
+        let fields = [
+            (
+                "wavegen_get_amplitude",
+                config.field_value(WavegenField::Amplitude, settings.pkpk),
+            ),
+            ("wavegen_get_period", settings.period.as_secs_f64()),
+            (
+                "wavegen_get_offset",
+                config.field_value(WavegenField::Offset, settings.offset),
+            ),
+            ("wavegen_get_symmetry", settings.symmetry_p),
+        ];
+        Box::new(move |command| {
+            let name = command["command"].as_str()?;
+            if name == "wavegen_is_running" {
+                return Some(json!({ "Ok": running }));
+            }
+            let (_, value) = fields.iter().find(|(field, _)| *field == name)?;
+            Some(json!({ "Ok": value }))
+        })
+    }
+
+    fn session_settings() -> WavegenSettings {
+        WavegenSettings {
+            pkpk: 200.,
+            period: Duration::from_secs(2),
+            symmetry_p: 100.,
+            offset: 0.,
+        }
+    }
+
+    // a driver with `saved` as the previous session's file in `dir`
+    fn reconciling_driver(dir: &TempDir, saved: &SessionState, reset: bool) -> AquisitionDriver {
+        let path = dir.join("session.json");
+        saved.save(&path).unwrap();
+        bridged_driver(DriverConfig {
+            session_file: Some(path),
+            reset_session: reset,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn invalidated_settings_are_sent_again() {
+        let mut driver = bridged_driver(DriverConfig {
+            session_file: None,
+            ..Default::default()
+        });
+        let (flow, seen) = fake_flow(&driver, acknowledge());
+        let setters = || {
+            let mut setters = seen
+                .lock()
+                .unwrap()
+                .drain(..)
+                .filter(|name| name.starts_with("wavegen_set_"))
+                .collect_vec();
+            setters.sort();
+            setters
+        };
+        driver
+            .apply_wavegen_settings(session_settings())
+            .await
+            .unwrap();
+        assert_eq!(setters().len(), 4);
+        driver
+            .apply_wavegen_settings(session_settings())
+            .await
+            .unwrap();
+        assert_eq!(setters(), Vec::<String>::new());
+        driver.invalidate_cache();
+        assert_eq!(driver.current_settings(), None);
+        driver
+            .apply_wavegen_settings(session_settings())
+            .await
+            .unwrap();
+        assert_eq!(
+            setters(),
+            [
+                "wavegen_set_amplitude",
+                "wavegen_set_offset",
+                "wavegen_set_period",
+                "wavegen_set_symmetry"
+            ]
+        );
+        flow.abort();
+    }
+
+    #[tokio::test]
+    async fn pipelined_settings_take_one_round_trip() {
+        let delay = Duration::from_millis(150);
+        for (pipeline_settings, round_trips) in [(true, 1), (false, 4)] {
+            let mut driver = bridged_driver(DriverConfig {
+                pipeline_settings,
+                session_file: None,
+                ..Default::default()
+            });
+            let (flow, seen) = fake_flow_after(&driver, acknowledge(), delay);
+            let start = Instant::now();
+            driver
+                .apply_wavegen_settings(session_settings())
+                .await
+                .unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(seen.lock().unwrap().len(), 4, "{pipeline_settings}");
+            assert!(
+                elapsed >= delay * round_trips && elapsed < delay * round_trips + delay / 2,
+                "{pipeline_settings}: {elapsed:?}"
+            );
+            flow.abort();
+        }
+    }
+
+    // asks for the bridge `after` a capture has started, while it is still warming up or settling
+    async fn bridge_is_held(
+        pa: Rc<PowerAutomate>,
+        capture: impl std::future::Future<Output = Result<()>>,
+        after: Duration,
+    ) -> bool {
+        let probe = async {
+            tokio::time::sleep(after).await;
+            pa.lock_acquisition(false).await
+        };
+        tokio::select! {
+            result = capture => panic!("the capture finished first: {result:?}"),
+            lock = probe => lock.is_err_and(|e| e.is::<AlreadyAcquiring>()),
+        }
+    }
+
+    #[tokio::test]
+    async fn warm_up_and_offset_changes_hold_the_bridge() {
+        let mut driver = bridged_driver(DriverConfig {
+            offset_settle_time: Duration::from_millis(500),
+            session_file: None,
+            ..Default::default()
+        });
+        let (flow, _) = fake_flow(&driver, running_waveforms());
+        let pa = driver.pa.clone();
+        let settings = WavegenSettings {
+            period: Duration::from_millis(250),
+            ..session_settings()
+        };
+        let warming_up = async {
+            driver.aquire_n_waves_report(settings, 1, 2, false).await?;
+            Ok(())
+        };
+        let after = Duration::from_millis(100);
+        assert!(bridge_is_held(pa.clone(), warming_up, after).await);
+        let settling = async {
+            driver.aquire_offset_sweep(settings, &[1.], 1).await?;
+            Ok(())
+        };
+        assert!(bridge_is_held(pa.clone(), settling, after).await);
+        assert!(pa.lock_acquisition(false).await.is_ok());
+        flow.abort();
+    }
+
+    fn saved_session() -> SessionState {
+        let mut saved = SessionState::default();
+        saved.set_settings(session_settings());
+        saved.set_running(false);
+        saved
+    }
+
+    fn load_session(driver: &AquisitionDriver) -> SessionState {
+        let path = driver.config.session_file.as_ref().unwrap();
+        SessionState::load(path).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn reconcile_accepts_a_matching_device() {
+        let dir = TempDir::new("session_match");
+        let mut driver = reconciling_driver(&dir, &saved_session(), false);
+        let answer = device(&driver.config, session_settings(), false);
+        let (flow, _) = fake_flow(&driver, answer);
+        assert_eq!(driver.reconcile_session().await, Vec::<String>::new());
+        let cached = driver.current_settings().unwrap();
+        assert!(settings_match(cached.pkpk, 200.));
+        assert_eq!(load_session(&driver).wavegen_running, Some(false));
+        flow.abort();
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_a_mismatched_device() {
+        let dir = TempDir::new("session_mismatch");
+        let mut driver = reconciling_driver(&dir, &saved_session(), false);
+        let changed = WavegenSettings {
+            pkpk: 100.,
+            ..session_settings()
+        };
+        let answer = device(&driver.config, changed, true);
+        let (flow, _) = fake_flow(&driver, answer);
+        let discrepancies = driver.reconcile_session().await;
+        assert_eq!(discrepancies.len(), 2, "{discrepancies:?}");
+        assert!(discrepancies[0].contains("pkpk"));
+        assert!(discrepancies[1].contains("running"));
+        let session = load_session(&driver);
+        assert!(settings_match(session.settings.unwrap().pkpk, 100.));
+        assert_eq!(session.wavegen_running, Some(true));
+        flow.abort();
+    }
+
+    #[tokio::test]
+    async fn reset_session_skips_the_device() {
+        let dir = TempDir::new("session_reset");
+        let mut driver = reconciling_driver(&dir, &saved_session(), true);
+        let answer = device(&driver.config, session_settings(), false);
+        let (flow, seen) = fake_flow(&driver, answer);
+        assert!(driver.reconcile_session().await.is_empty());
+        assert!(seen.lock().unwrap().is_empty());
+        assert_eq!(load_session(&driver), SessionState::default());
+        flow.abort();
+    }
+
+    #[tokio::test]
+    async fn failed_device_reads_leave_the_cache_empty() {
+        let saved = saved_session();
+        let dir = TempDir::new("session_unreadable");
+        let mut driver = reconciling_driver(&dir, &saved, false);
+        let answer: Answer = Box::new(|_| Some(json!({ "Err": "WaveForms is busy" })));
+        let (flow, seen) = fake_flow(&driver, answer);
+        assert!(driver.reconcile_session().await.is_empty());
+        assert!(seen
+            .lock()
+            .unwrap()
+            .contains(&"wavegen_is_running".to_string()));
+        assert_eq!(driver.current_settings(), None);
+        assert_eq!(load_session(&driver), saved);
+        flow.abort();
+    }
+}
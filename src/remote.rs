@@ -0,0 +1,409 @@
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, SeekFrom},
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    body::StreamBody,
+    extract::{Path as AxumPath, State},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Local};
+use futures::stream;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Notify,
+    },
+};
+
+use crate::{
+    events::{summarize, EVENTS_FILE},
+    power_automate::AquisitionDriver,
+    sweep::{RunFolder, SweepCommand, SweepOptions, SweepPoint, SweepRunner},
+};
+
+const EVENTS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub struct RemoteOptions {
+    pub addr: SocketAddr,
+    // required as `Authorization: Bearer <token>` on every request
+    pub token: Option<String>,
+    // runs are written to `<data_dir>/<sample>/<n>`
+    pub data_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunConfig {
+    pub sample: String,
+    pub points: Vec<SweepPoint>,
+    #[serde(default)]
+    pub options: SweepOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum RunState {
+    Queued,
+    Running,
+    Complete,
+    Failed { error: String },
+    Cancelled,
+}
+impl RunState {
+    fn is_finished(&self) -> bool {
+        !matches!(self, RunState::Queued | RunState::Running)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PointProgress {
+    pub name: String,
+    pub started: DateTime<Local>,
+    pub finished: Option<DateTime<Local>>,
+    pub status: &'static str,
+    pub warnings: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RunStatus {
+    pub id: u64,
+    #[serde(flatten)]
+    pub state: RunState,
+    pub submitted_at: DateTime<Local>,
+    pub folder: Option<PathBuf>,
+    pub config: RunConfig,
+    pub points: Vec<PointProgress>,
+}
+
+struct Run {
+    config: RunConfig,
+    state: RunState,
+    submitted_at: DateTime<Local>,
+    folder: Option<PathBuf>,
+    commands: Option<UnboundedSender<SweepCommand>>,
+    cancel_requested: bool,
+}
+
+#[derive(Default)]
+struct Runs {
+    next_id: u64,
+    runs: BTreeMap<u64, Run>,
+}
+
+#[derive(Clone, Default)]
+struct Remote {
+    runs: Arc<Mutex<Runs>>,
+    queued: Arc<Notify>,
+}
+impl Remote {
+    fn submit(&self, config: RunConfig) -> u64 {
+        let mut runs = self.runs.lock().unwrap();
+        runs.next_id += 1;
+        let id = runs.next_id;
+        runs.runs.insert(
+            id,
+            Run {
+                config,
+                state: RunState::Queued,
+                submitted_at: Local::now(),
+                folder: None,
+                commands: None,
+                cancel_requested: false,
+            },
+        );
+        self.queued.notify_one();
+        id
+    }
+    // runs execute one at a time, oldest submission first
+    async fn next_queued(&self) -> (u64, RunConfig, UnboundedReceiver<SweepCommand>) {
+        loop {
+            {
+                let mut runs = self.runs.lock().unwrap();
+                let next = runs
+                    .runs
+                    .iter_mut()
+                    .find(|(_, run)| run.state == RunState::Queued);
+                if let Some((&id, run)) = next {
+                    let (commands, receiver) = mpsc::unbounded_channel();
+                    run.state = RunState::Running;
+                    run.commands = Some(commands);
+                    return (id, run.config.clone(), receiver);
+                }
+            }
+            self.queued.notified().await;
+        }
+    }
+    fn set_folder(&self, id: u64, folder: PathBuf) {
+        if let Some(run) = self.runs.lock().unwrap().runs.get_mut(&id) {
+            run.folder = Some(folder);
+        }
+    }
+    fn finish(&self, id: u64, res: Result<()>) {
+        if let Some(run) = self.runs.lock().unwrap().runs.get_mut(&id) {
+            run.commands = None;
+            run.state = match res {
+                Ok(()) => RunState::Complete,
+                Err(_) if run.cancel_requested => RunState::Cancelled,
+                Err(e) => RunState::Failed {
+                    error: format!("{e:#}"),
+                },
+            };
+        }
+    }
+    fn status(&self, id: u64) -> Option<RunStatus> {
+        let mut status = {
+            let runs = self.runs.lock().unwrap();
+            let run = runs.runs.get(&id)?;
+            RunStatus {
+                id,
+                state: run.state.clone(),
+                submitted_at: run.submitted_at,
+                folder: run.folder.clone(),
+                config: run.config.clone(),
+                points: vec![],
+            }
+        };
+        // the event log may not exist until the first point starts
+        if let Some(folder) = &status.folder {
+            let summaries = summarize(folder.join(EVENTS_FILE)).unwrap_or_default();
+            status.points = summaries
+                .into_iter()
+                .map(|p| PointProgress {
+                    name: p.name,
+                    started: p.started,
+                    finished: p.finished,
+                    status: p.status,
+                    warnings: p.warnings,
+                })
+                .collect();
+        }
+        Some(status)
+    }
+    fn is_finished(&self, id: u64) -> bool {
+        let runs = self.runs.lock().unwrap();
+        runs.runs.get(&id).is_none_or(|run| run.state.is_finished())
+    }
+}
+
+// accepts runs over http and executes them on `driver` until the program is stopped
+pub async fn serve(driver: &mut AquisitionDriver, options: RemoteOptions) -> Result<()> {
+    if options.token.is_none() && !options.addr.ip().is_loopback() {
+        bail!("A token is required to accept runs on {}", options.addr);
+    }
+    let remote = Remote::default();
+    let app = Router::new()
+        .route("/runs", get(list_runs).post(submit_run))
+        .route("/runs/:id", get(show_run))
+        .route("/runs/:id/cancel", post(cancel_run))
+        .route("/runs/:id/events", get(run_events))
+        .route_layer(middleware::from_fn_with_state(
+            options.token.clone().map(Arc::<str>::from),
+            authorize,
+        ))
+        .with_state(remote.clone());
+    let server = axum::Server::try_bind(&options.addr)?.serve(app.into_make_service());
+    let mut server = tokio::spawn(server);
+    println!("Accepting runs on http://{}", options.addr);
+
+    loop {
+        // a run in progress is finished before a failed server is reported
+        let (id, config, commands) = tokio::select! {
+            next = remote.next_queued() => next,
+            res = &mut server => {
+                res.context("Remote server panicked")?
+                    .context("Remote server failed")?;
+                bail!("Remote server stopped");
+            }
+        };
+        println!("Starting run {id}");
+        let res = execute(driver, &options.data_dir, &remote, id, config, commands).await;
+        if let Err(e) = &res {
+            eprintln!("Run {id} failed: {e:#}");
+        }
+        remote.finish(id, res);
+    }
+}
+
+async fn execute(
+    driver: &mut AquisitionDriver,
+    data_dir: &Path,
+    remote: &Remote,
+    id: u64,
+    config: RunConfig,
+    commands: UnboundedReceiver<SweepCommand>,
+) -> Result<()> {
+    let folder = RunFolder::new(data_dir, &config.sample).create_next()?;
+    remote.set_folder(id, folder.clone());
+    SweepRunner::new(driver, folder, config.options)?
+        .with_commands(commands)
+        .run(&config.points)
+        .await
+}
+
+async fn authorize<B>(
+    State(token): State<Option<Arc<str>>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    if let Some(token) = token {
+        let provided = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if provided != Some(&*token) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+    Ok(next.run(req).await)
+}
+
+async fn submit_run(
+    State(remote): State<Remote>,
+    Json(config): Json<RunConfig>,
+) -> Result<(StatusCode, Json<RunStatus>), (StatusCode, String)> {
+    check_sample(&config.sample).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let id = remote.submit(config);
+    Ok((StatusCode::CREATED, Json(remote.status(id).unwrap())))
+}
+
+// the sample names a folder under the data directory, so it must be a single plain component.
+// both separators and the `:` of a drive prefix are refused whatever the platform the server
+// runs on
+fn check_sample(sample: &str) -> Result<(), String> {
+    let mut components = Path::new(sample).components();
+    let plain = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+    if !plain || sample.contains(['/', '\\', ':']) {
+        return Err(format!("`{sample}` is not a valid sample name"));
+    }
+    Ok(())
+}
+
+async fn list_runs(State(remote): State<Remote>) -> Json<Vec<RunStatus>> {
+    let ids = remote
+        .runs
+        .lock()
+        .unwrap()
+        .runs
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    Json(ids.into_iter().filter_map(|id| remote.status(id)).collect())
+}
+
+async fn show_run(
+    State(remote): State<Remote>,
+    AxumPath(id): AxumPath<u64>,
+) -> Result<Json<RunStatus>, StatusCode> {
+    remote.status(id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn cancel_run(State(remote): State<Remote>, AxumPath(id): AxumPath<u64>) -> StatusCode {
+    let mut runs = remote.runs.lock().unwrap();
+    let Some(run) = runs.runs.get_mut(&id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    match run.state {
+        RunState::Queued => {
+            run.state = RunState::Cancelled;
+            StatusCode::OK
+        }
+        RunState::Running => {
+            run.cancel_requested = true;
+            if let Some(commands) = &run.commands {
+                commands.send(SweepCommand::Abort).ok();
+            }
+            StatusCode::ACCEPTED
+        }
+        _ => StatusCode::CONFLICT,
+    }
+}
+
+// follows the run's event log as json lines until the run finishes
+async fn run_events(
+    State(remote): State<Remote>,
+    AxumPath(id): AxumPath<u64>,
+) -> Result<Response, StatusCode> {
+    let status = remote.status(id).ok_or(StatusCode::NOT_FOUND)?;
+    let folder = status.folder.ok_or(StatusCode::CONFLICT)?;
+    let path = folder.join(EVENTS_FILE);
+    let lines = stream::unfold(0, move |offset| {
+        let (remote, path) = (remote.clone(), path.clone());
+        async move {
+            loop {
+                let finished = remote.is_finished(id);
+                let chunk = match read_lines_from(&path, offset).await {
+                    Ok(chunk) => chunk,
+                    Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+                    Err(_) => return None,
+                };
+                if !chunk.is_empty() {
+                    let offset = offset + chunk.len() as u64;
+                    return Some((Ok::<_, std::io::Error>(chunk), offset));
+                }
+                if finished {
+                    return None;
+                }
+                tokio::time::sleep(EVENTS_POLL_INTERVAL).await;
+            }
+        }
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(lines),
+    )
+        .into_response())
+}
+
+// a line still being written is left for the next read
+async fn read_lines_from(path: &Path, offset: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut buf = vec![];
+    file.read_to_end(&mut buf).await?;
+    let complete = buf.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    buf.truncate(complete);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_names_stay_inside_the_data_dir() {
+        for sample in ["bead 3", "sample.v2", "..hidden"] {
+            assert_eq!(check_sample(sample), Ok(()), "{sample}");
+        }
+        for sample in [
+            "",
+            ".",
+            "..",
+            "../up",
+            "a/b",
+            "a\\b",
+            "/abs",
+            "C:\\abs",
+            "C:",
+            "nested/..",
+        ] {
+            assert!(check_sample(sample).is_err(), "{sample}");
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use nanonis::DatFile;
+use serde::Deserialize;
+
+use crate::{
+    aquisition::{AcqAttributes, OFFSET_KEY, PERIOD_KEY, PKPK_KEY, SYMMETRY_KEY},
+    power_automate::WavegenSettings,
+};
+
+const ATTRIBUTE_TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug, Clone, Default)]
+pub struct ReprocessOptions {
+    // corrected settings keyed by file name, taking priority over the file name template
+    pub mapping: BTreeMap<String, WavegenSettings>,
+    // offset to use when neither the mapping nor the file itself records one
+    pub offset: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReprocessStatus {
+    Rewritten,
+    Skipped,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReprocessSummary {
+    pub file: PathBuf,
+    pub status: ReprocessStatus,
+}
+impl Display for ReprocessSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.file.display();
+        match &self.status {
+            ReprocessStatus::Rewritten => write!(f, "{name}: rewritten"),
+            ReprocessStatus::Skipped => write!(f, "{name}: already correct, skipped"),
+            ReprocessStatus::Failed(e) => write!(f, "{name}: FAILED: {e}"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MappingRow {
+    file: String,
+    period_s: f64,
+    pkpk: f64,
+    symmetry_p: f64,
+    offset: f64,
+}
+
+// reads a csv with `file,period_s,pkpk,symmetry_p,offset` columns
+pub fn load_mapping(path: impl AsRef<Path>) -> Result<BTreeMap<String, WavegenSettings>> {
+    let path = path.as_ref();
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("Could not open mapping `{}`", path.display()))?;
+    reader
+        .deserialize::<MappingRow>()
+        .map(|row| {
+            let row = row?;
+            let period = Duration::try_from_secs_f64(row.period_s).with_context(|| {
+                format!("`{}` has an invalid period of {} s", row.file, row.period_s)
+            })?;
+            let settings = WavegenSettings {
+                pkpk: row.pkpk,
+                period,
+                symmetry_p: row.symmetry_p,
+                offset: row.offset,
+            };
+            Ok((row.file, settings))
+        })
+        .collect()
+}
+
+// parses the `trap_{period}s_{pkpk}v_{symmetry}p` template, which does not record the offset
+// unless it was a grid axis. grid tags like `_offset2=50` may follow the template
+pub fn settings_from_filename(name: &str) -> Option<WavegenSettings> {
+    let mut stem = name.strip_prefix("trap_")?;
+    stem = stem.strip_suffix(".gz").unwrap_or(stem);
+    if let Some((rest, ext)) = stem.rsplit_once('.') {
+        if ext.chars().all(|c| c.is_ascii_alphabetic()) {
+            stem = rest;
+        }
+    }
+    let mut parts = stem.split('_');
+    let mut period: f64 = parts.next()?.strip_suffix('s')?.parse().ok()?;
+    let mut pkpk = parts.next()?.strip_suffix('v')?.parse().ok()?;
+    let mut symmetry_p = parts.next()?.strip_suffix('p')?.parse().ok()?;
+    let mut offset = 0.;
+    for tag in parts {
+        let (axis, value) = tag.split_once('=')?;
+        let label = axis.trim_end_matches(|c: char| c.is_ascii_digit());
+        if label.len() == axis.len() {
+            return None;
+        }
+        let value: f64 = value.parse().ok()?;
+        match label {
+            // the tags are at full precision, unlike the template
+            "pkpk" => pkpk = value,
+            "period" => period = value,
+            "symmetry" => symmetry_p = value,
+            "offset" => offset = value,
+            _ => return None,
+        }
+    }
+    Some(WavegenSettings {
+        pkpk,
+        period: Duration::try_from_secs_f64(period).ok()?,
+        symmetry_p,
+        offset,
+    })
+}
+
+// rewrites the settings attributes of every .dat file in `src` into `dst`,
+// leaving the originals untouched
+pub fn reprocess(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    options: &ReprocessOptions,
+) -> Result<Vec<ReprocessSummary>> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    if src == dst {
+        bail!("Reprocessed files must go to a different folder than the originals");
+    }
+    std::fs::create_dir_all(dst)?;
+    let mut files = std::fs::read_dir(src)
+        .with_context(|| format!("Could not read `{}`", src.display()))?
+        .map(|e| Ok(e?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    files.retain(|p| p.extension().is_some_and(|e| e == "dat"));
+    files.sort();
+
+    let summaries = files
+        .into_iter()
+        .map(|file| {
+            let status = match reprocess_file(&file, dst, options) {
+                Ok(true) => ReprocessStatus::Rewritten,
+                Ok(false) => ReprocessStatus::Skipped,
+                Err(e) => ReprocessStatus::Failed(format!("{e:#}")),
+            };
+            ReprocessSummary { file, status }
+        })
+        .collect();
+    Ok(summaries)
+}
+
+fn reprocess_file(file: &Path, dst: &Path, options: &ReprocessOptions) -> Result<bool> {
+    let name = file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("File name is not valid unicode")?;
+    let mut datfile = DatFile::read_from_file(file).context("Could not read file")?;
+
+    let settings = match options.mapping.get(name) {
+        Some(settings) => *settings,
+        None => {
+            let mut settings = settings_from_filename(name)
+                .context("File is not in the mapping and its name does not match the template")?;
+            let attrs = AcqAttributes(&datfile.attributes);
+            let recorded = |key: &str| attrs.get_f64(key).ok();
+            // the name is rounded to 2 decimals, so keep recorded values that agree with it
+            let refine = |named: f64, recorded: Option<f64>| match recorded {
+                Some(r) if format!("{r:.2}") == format!("{named:.2}") => r,
+                _ => named,
+            };
+            settings.pkpk = refine(settings.pkpk, recorded(PKPK_KEY));
+            settings.symmetry_p = refine(settings.symmetry_p, recorded(SYMMETRY_KEY));
+            let period = refine(settings.period.as_secs_f64(), recorded(PERIOD_KEY));
+            settings.period = Duration::from_secs_f64(period);
+            settings.offset = match (recorded(OFFSET_KEY), options.offset) {
+                (Some(offset), _) | (None, Some(offset)) => offset,
+                (None, None) => bail!("No offset recorded or provided"),
+            };
+            settings
+        }
+    };
+
+    let attributes = [
+        (PKPK_KEY, settings.pkpk),
+        (PERIOD_KEY, settings.period.as_secs_f64()),
+        (SYMMETRY_KEY, settings.symmetry_p),
+        (OFFSET_KEY, settings.offset),
+    ];
+    let attrs = AcqAttributes(&datfile.attributes);
+    let correct = attributes.iter().all(|(key, value)| {
+        attrs
+            .get_f64(key)
+            .is_ok_and(|v| (v - value).abs() <= ATTRIBUTE_TOLERANCE * value.abs().max(1.))
+    });
+    if correct {
+        return Ok(false);
+    }
+    AcqAttributes(&mut datfile.attributes).set_settings(settings);
+    let mut writer = BufWriter::new(File::create(dst.join(name))?);
+    datfile.write_to(&mut writer)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn invalid_periods_are_rejected_not_panicked_on() {
+        let parsed = settings_from_filename("trap_2.00s_200.00v_100.00p.dat").unwrap();
+        assert_eq!(parsed.period, Duration::from_secs(2));
+        for name in [
+            "trap_-1.00s_200.00v_100.00p.dat",
+            "trap_NaNs_200.00v_100.00p.dat",
+            "trap_2.00s_200.00v_100.00p_period1=-0.5.dat",
+        ] {
+            assert_eq!(settings_from_filename(name), None, "{name}");
+        }
+
+        let dir = TempDir::new("reprocess_mapping");
+        let path = dir.join("mapping.csv");
+        std::fs::write(
+            &path,
+            "file,period_s,pkpk,symmetry_p,offset\na.dat,2,200,100,0\nb.dat,-1,200,100,0\n",
+        )
+        .unwrap();
+        let err = format!("{:#}", load_mapping(&path).unwrap_err());
+        assert!(err.contains("`b.dat`") && err.contains("-1 s"), "{err}");
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+
+// every scratch file goes under this folder in the temp directory, so leftovers from a crash
+// are easy to find and to tell apart from other programs' files
+pub const SCRATCH_DIR: &str = "power-automate-acq";
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+// files this process has created and not yet removed, never touched by `clean`
+static LIVE: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+pub fn default_dir() -> PathBuf {
+    std::env::temp_dir().join(SCRATCH_DIR)
+}
+
+// a uniquely named file in the scratch folder, removed again when dropped whether or not
+// whatever wrote it succeeded
+#[derive(Debug)]
+pub struct ScratchFile {
+    path: PathBuf,
+}
+impl ScratchFile {
+    // unique per process and call, so back to back reads never pick up each other's file
+    pub fn new(dir: &Path, extension: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create scratch folder {}", dir.display()))?;
+        let path = dir.join(format!(
+            "temp{}_{}_{}.{extension}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_millis(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        LIVE.lock().unwrap().insert(path.clone());
+        Ok(Self { path })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                eprintln!(
+                    "WARNING: could not remove scratch file {}: {e}",
+                    self.path.display()
+                );
+            }
+        }
+        LIVE.lock().unwrap().remove(&self.path);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CleanSummary {
+    pub removed: usize,
+    pub bytes: u64,
+    pub failed: usize,
+}
+impl std::fmt::Display for CleanSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "removed {} stale scratch file(s), {:.1} MB",
+            self.removed,
+            self.bytes as f64 / 1e6
+        )?;
+        if self.failed > 0 {
+            write!(f, ", {} could not be removed", self.failed)?;
+        }
+        Ok(())
+    }
+}
+
+// deletes the files in `dir` last modified more than `max_age` ago, leaving anything this
+// process is still using. a missing folder has nothing to clean
+pub fn clean(dir: &Path, max_age: Duration) -> Result<CleanSummary> {
+    let mut summary = CleanSummary::default();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(summary),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Could not list {}", dir.display()));
+        }
+    };
+    let now = SystemTime::now();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Could not list {}", dir.display()))?;
+        let path = entry.path();
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        // a modified time in the future counts as fresh
+        let age = meta
+            .modified()
+            .ok()
+            .and_then(|m| now.duration_since(m).ok())
+            .unwrap_or_default();
+        if !meta.is_file() || age <= max_age || LIVE.lock().unwrap().contains(&path) {
+            continue;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                summary.removed += 1;
+                summary.bytes += meta.len();
+            }
+            Err(e) => {
+                eprintln!("WARNING: could not remove {}: {e}", path.display());
+                summary.failed += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn write_aged(path: &Path, age: Duration) {
+        std::fs::write(path, b"data").unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn clean_reaps_stale_files_only() {
+        let temp = TempDir::new("scratch");
+        let dir = temp.path();
+        let hour = Duration::from_secs(60 * 60);
+        let (stale, fresh) = (dir.join("stale.dat"), dir.join("fresh.dat"));
+        let live = ScratchFile::new(dir, "dat").unwrap();
+        write_aged(&stale, 2 * hour);
+        write_aged(&fresh, Duration::ZERO);
+        write_aged(live.path(), 2 * hour);
+        std::fs::create_dir(dir.join("old folder")).unwrap();
+
+        let summary = clean(dir, hour).unwrap();
+        assert_eq!((summary.removed, summary.bytes, summary.failed), (1, 4, 0));
+        assert!(!stale.exists());
+        assert!(fresh.exists() && live.path().exists());
+        let path = live.path().to_path_buf();
+        drop(live);
+        assert!(!path.exists());
+        assert_eq!(clean(&dir.join("missing"), hour).unwrap().removed, 0);
+    }
+}
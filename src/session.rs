@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::power_automate::WavegenSettings;
+
+pub const SESSION_FILE: &str = "session.json";
+
+// what the driver last commanded, kept on disk so a restarted process knows what the previous one
+// left the wavegen doing
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionState {
+    pub settings: Option<WavegenSettings>,
+    pub settings_at: Option<DateTime<Local>>,
+    pub wavegen_running: Option<bool>,
+    pub running_at: Option<DateTime<Local>>,
+}
+impl SessionState {
+    // `None` if no session has been saved there yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read `{}`", path.display()))?;
+        serde_json::from_str(&contents)
+            .map(Some)
+            .with_context(|| format!("Failed to parse `{}`", path.display()))
+    }
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(folder) = path.parent() {
+            std::fs::create_dir_all(folder)?;
+        }
+        // write then rename so a crash never leaves a half-written session
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+    pub fn set_settings(&mut self, settings: WavegenSettings) {
+        self.settings = Some(settings);
+        self.settings_at = Some(Local::now());
+    }
+    pub fn set_running(&mut self, running: bool) {
+        self.wavegen_running = Some(running);
+        self.running_at = Some(Local::now());
+    }
+}
+
+// `%APPDATA%\power-automate\session.json`, or under the temp directory where there is no APPDATA
+pub fn default_path() -> PathBuf {
+    std::env::var_os("APPDATA")
+        .map_or_else(std::env::temp_dir, PathBuf::from)
+        .join("power-automate")
+        .join(SESSION_FILE)
+}
@@ -0,0 +1,1799 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    future::{pending, Future},
+    io::{BufWriter, ErrorKind},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use flate2::{write::GzEncoder, Compression};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use itertools::Itertools;
+use nanonis::DatFile;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc::UnboundedReceiver, task::JoinHandle, time::MissedTickBehavior};
+
+use crate::{
+    aquisition::{format_attribute, is_gzip_path, Aquisition, FrequencyResponse, OutputFormat},
+    auxiliary::{AuxLogger, AuxReading, AuxStage},
+    events::{Event, EventLog, EVENTS_FILE},
+    hooks::AcqHook,
+    notify::{Notifier, SweepStatus},
+    power_automate::{
+        wait_with_progress, AcquisitionInfo, AcquisitionReport, AquisitionDriver, CommandStats,
+        DriverConfig, ProgressStatus, RecoveryFailed, WavegenSettings,
+    },
+};
+
+const RUN_FOLDER_RETRIES: usize = 100;
+const MANIFEST_FILE: &str = "manifest.json";
+const BODE_FILE: &str = "bode.csv";
+
+#[derive(Debug, Clone)]
+pub struct RunFolder {
+    sample_dir: PathBuf,
+}
+impl RunFolder {
+    pub fn new(base: impl AsRef<Path>, sample: &str) -> Self {
+        Self {
+            sample_dir: base.as_ref().join(sample),
+        }
+    }
+    pub fn create_next(&self) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.sample_dir)?;
+        for _ in 0..RUN_FOLDER_RETRIES {
+            let next = self.latest_number()?.map_or(1, |n| n + 1);
+            let path = self.run_path(next);
+            // create_dir fails if another run claimed this number first
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(path),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        bail!(
+            "Could not claim a run folder in `{}`",
+            self.sample_dir.display()
+        )
+    }
+    pub fn open_latest(&self) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.sample_dir)?;
+        match self.latest_number()? {
+            Some(n) => Ok(self.run_path(n)),
+            None => self.create_next(),
+        }
+    }
+    fn latest_number(&self) -> Result<Option<u32>> {
+        let mut latest = None;
+        for entry in std::fs::read_dir(&self.sample_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(n) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                latest = latest.max(Some(n));
+            }
+        }
+        Ok(latest)
+    }
+    fn run_path(&self, n: u32) -> PathBuf {
+        self.sample_dir.join(format!("{n:04}"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SweepPoint {
+    pub settings: WavegenSettings,
+    pub n_waves: usize,
+    pub warmup_periods: usize,
+    #[serde(default)]
+    pub discard_first_period: bool,
+    // runs the point at the requested amplitude even if `DriverConfig::amplitude_correction` is on
+    #[serde(default)]
+    pub skip_amplitude_correction: bool,
+    // where the point sits in a `SweepGrid`, added to its filename
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grid: Option<GridPosition>,
+}
+
+// a wavegen setting a `SweepGrid` steps through, with the period in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SweepAxis {
+    Pkpk,
+    Period,
+    Symmetry,
+    Offset,
+}
+impl SweepAxis {
+    pub fn label(self) -> &'static str {
+        match self {
+            SweepAxis::Pkpk => "pkpk",
+            SweepAxis::Period => "period",
+            SweepAxis::Symmetry => "symmetry",
+            SweepAxis::Offset => "offset",
+        }
+    }
+    pub fn value(self, settings: &WavegenSettings) -> f64 {
+        match self {
+            SweepAxis::Pkpk => settings.pkpk,
+            SweepAxis::Period => settings.period.as_secs_f64(),
+            SweepAxis::Symmetry => settings.symmetry_p,
+            SweepAxis::Offset => settings.offset,
+        }
+    }
+    fn set(self, settings: &mut WavegenSettings, value: f64) -> Result<()> {
+        match self {
+            SweepAxis::Pkpk => settings.pkpk = value,
+            SweepAxis::Period => {
+                settings.period = Duration::try_from_secs_f64(value)
+                    .with_context(|| format!("{value} s is not a valid period"))?
+            }
+            SweepAxis::Symmetry => settings.symmetry_p = value,
+            SweepAxis::Offset => settings.offset = value,
+        }
+        Ok(())
+    }
+}
+
+// either listed explicitly or `n` evenly spaced values from `start` to `stop` inclusive,
+// log spaced if `log` is set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AxisValues {
+    List(Vec<f64>),
+    Range {
+        start: f64,
+        stop: f64,
+        n: usize,
+        #[serde(default)]
+        log: bool,
+    },
+}
+impl AxisValues {
+    pub fn values(&self) -> Vec<f64> {
+        match *self {
+            AxisValues::List(ref values) => values.clone(),
+            AxisValues::Range {
+                start,
+                stop,
+                n,
+                log,
+            } => {
+                if log {
+                    return FrequencySweep::log_spaced(start, stop, n);
+                }
+                if n < 2 {
+                    return vec![start; n];
+                }
+                let step = (stop - start) / (n - 1) as f64;
+                (0..n).map(|i| start + step * i as f64).collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GridAxis {
+    pub axis: SweepAxis,
+    pub values: AxisValues,
+}
+
+// every combination of two settings, running the whole inner axis for each outer value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SweepGrid {
+    // the settings and capture lengths shared by every point
+    pub base: SweepPoint,
+    pub outer: GridAxis,
+    pub inner: GridAxis,
+    // runs the inner axis backwards on every other outer step, so moving to the next outer
+    // value never jumps across the whole inner range
+    #[serde(default)]
+    pub snake: bool,
+}
+impl SweepGrid {
+    pub fn points(&self) -> Result<Vec<SweepPoint>> {
+        let (outer, inner) = (self.outer.axis, self.inner.axis);
+        if outer == inner {
+            bail!("both grid axes step the {}", outer.label());
+        }
+        let outer_values = self.outer.values.values();
+        let inner_values = self.inner.values.values();
+        if outer_values.is_empty() || inner_values.is_empty() {
+            bail!("a grid axis has no values");
+        }
+        let mut points = Vec::with_capacity(outer_values.len() * inner_values.len());
+        for (outer_index, &outer_value) in outer_values.iter().enumerate() {
+            let mut inner_indices = (0..inner_values.len()).collect_vec();
+            if self.snake && outer_index % 2 == 1 {
+                inner_indices.reverse();
+            }
+            for inner_index in inner_indices {
+                let mut point = self.base;
+                outer.set(&mut point.settings, outer_value)?;
+                inner.set(&mut point.settings, inner_values[inner_index])?;
+                point.grid = Some(GridPosition {
+                    outer,
+                    outer_index,
+                    inner,
+                    inner_index,
+                });
+                points.push(point);
+            }
+        }
+        Ok(points)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GridPosition {
+    pub outer: SweepAxis,
+    pub outer_index: usize,
+    pub inner: SweepAxis,
+    pub inner_index: usize,
+}
+impl GridPosition {
+    // `_pkpk2=200_period5=0.5`, each axis with its index and value
+    fn tag(&self, settings: &WavegenSettings) -> String {
+        [
+            (self.outer, self.outer_index),
+            (self.inner, self.inner_index),
+        ]
+        .iter()
+        .map(|(axis, i)| {
+            format!(
+                "_{}{i}={}",
+                axis.label(),
+                format_attribute(axis.value(settings))
+            )
+        })
+        .collect()
+    }
+}
+
+// the wavegen only produces trapezoids, so each point is driven with a triangle
+// and the response is taken at its fundamental
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrequencySweep {
+    pub frequencies_hz: Vec<f64>,
+    pub pkpk: f64,
+    pub offset: f64,
+    pub settle_cycles: usize,
+    pub measure_cycles: usize,
+    pub keep_raw: bool,
+}
+impl FrequencySweep {
+    pub fn log_spaced(start_hz: f64, stop_hz: f64, n: usize) -> Vec<f64> {
+        if n < 2 {
+            return vec![start_hz; n];
+        }
+        let ratio = (stop_hz / start_hz).ln() / (n - 1) as f64;
+        (0..n)
+            .map(|i| start_hz * (ratio * i as f64).exp())
+            .collect()
+    }
+    pub fn points(&self) -> Vec<SweepPoint> {
+        self.frequencies_hz
+            .iter()
+            .map(|f| SweepPoint {
+                settings: WavegenSettings {
+                    pkpk: self.pkpk,
+                    period: Duration::from_secs_f64(1. / f),
+                    symmetry_p: 100.,
+                    offset: self.offset,
+                },
+                n_waves: self.measure_cycles,
+                warmup_periods: self.settle_cycles,
+                discard_first_period: false,
+                skip_amplitude_correction: false,
+                grid: None,
+            })
+            .collect()
+    }
+}
+
+impl SweepPoint {
+    pub fn filename(&self, options: SweepOptions) -> String {
+        let stem = match &self.grid {
+            Some(grid) => self.settings.file_stem() + &grid.tag(&self.settings),
+            None => self.settings.file_stem(),
+        };
+        with_extension(stem, options)
+    }
+    pub fn estimated_duration(&self, config: &DriverConfig) -> Duration {
+        let warmup = Duration::try_from_secs_f64(
+            self.settings.period.as_secs_f64() * self.warmup_periods as f64,
+        )
+        .unwrap_or(Duration::MAX);
+        config
+            .estimate_duration(self.settings, self.n_waves)
+            .saturating_add(warmup)
+    }
+}
+
+// every point plus the rests between them, as if none were already complete
+pub fn estimate_sweep(
+    config: &DriverConfig,
+    points: &[SweepPoint],
+    options: &SweepOptions,
+) -> Duration {
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, point)| {
+            point
+                .estimated_duration(config)
+                .saturating_add(rest_before(options, i))
+        })
+        .fold(Duration::ZERO, Duration::saturating_add)
+}
+
+fn rest_before(options: &SweepOptions, i: usize) -> Duration {
+    options
+        .rest_between_points
+        .filter(|rest| i > 0 || rest.before_first)
+        .map_or(Duration::ZERO, |rest| rest.duration)
+}
+
+// a sweep as it would run, for checking before anything is driven
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SweepPlan {
+    pub points: Vec<SweepPoint>,
+    pub options: SweepOptions,
+}
+impl SweepPlan {
+    pub fn describe(&self, config: &DriverConfig) -> PlanDescription {
+        let mut names = BTreeSet::new();
+        let points = self
+            .points
+            .iter()
+            .enumerate()
+            .map(|(i, point)| {
+                let name = point.filename(self.options);
+                let mut errors = validate_point(point, config);
+                if !names.insert(name.clone()) {
+                    errors.push("writes the same file as an earlier point".into());
+                }
+                let rest = rest_before(&self.options, i);
+                PlannedPoint {
+                    windows: config.history_windows(point.settings, point.n_waves),
+                    estimated: point.estimated_duration(config).saturating_add(rest),
+                    name,
+                    point: *point,
+                    rest,
+                    errors,
+                }
+            })
+            .collect_vec();
+        let mut errors = vec![];
+        if let Err(e) = check_writable(&points) {
+            errors.push(format!("{e:#}"));
+        }
+        PlanDescription {
+            points,
+            total: estimate_sweep(config, &self.points, &self.options),
+            errors,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedPoint {
+    pub name: String,
+    pub point: SweepPoint,
+    pub windows: usize,
+    // rest before the point, included in `estimated`
+    pub rest: Duration,
+    pub estimated: Duration,
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanDescription {
+    pub points: Vec<PlannedPoint>,
+    pub total: Duration,
+    // problems with the plan as a whole rather than one point
+    pub errors: Vec<String>,
+}
+impl PlanDescription {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty() && self.points.iter().all(|p| p.errors.is_empty())
+    }
+}
+impl Display for PlanDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<40} {:>6} {:>8} {:>7} {:>11}",
+            "file", "waves", "windows", "rest s", "estimate s"
+        )?;
+        for p in &self.points {
+            writeln!(
+                f,
+                "{:<40} {:>6} {:>8} {:>7.0} {:>11.0}",
+                p.name,
+                p.point.n_waves,
+                p.windows,
+                p.rest.as_secs_f64(),
+                p.estimated.as_secs_f64()
+            )?;
+            for e in &p.errors {
+                writeln!(f, "    ERROR: {e}")?;
+            }
+        }
+        for e in &self.errors {
+            writeln!(f, "ERROR: {e}")?;
+        }
+        write_grid(f, &self.points)?;
+        write!(f, "total {:.1} min", self.total.as_secs_f64() / 60.)
+    }
+}
+
+// the step each grid point is run at, one row per outer value. a plan mixing grids with other
+// points numbers the grid points by their place in the whole plan
+fn write_grid(f: &mut std::fmt::Formatter<'_>, points: &[PlannedPoint]) -> std::fmt::Result {
+    let steps = points
+        .iter()
+        .enumerate()
+        .filter_map(|(step, p)| Some((step + 1, p.point.grid?, p.point.settings)))
+        .collect_vec();
+    let Some(&(_, first, _)) = steps.first() else {
+        return Ok(());
+    };
+    let (mut outer_values, mut inner_values) = (BTreeMap::new(), BTreeMap::new());
+    let mut cells = BTreeMap::new();
+    for (step, grid, settings) in &steps {
+        outer_values.insert(grid.outer_index, grid.outer.value(settings));
+        inner_values.insert(grid.inner_index, grid.inner.value(settings));
+        cells.insert((grid.outer_index, grid.inner_index), *step);
+    }
+    writeln!(
+        f,
+        "grid order, {} down, {} across",
+        first.outer.label(),
+        first.inner.label()
+    )?;
+    write!(f, "{:>10}", "")?;
+    for value in inner_values.values() {
+        write!(f, " {:>8}", format_attribute(*value))?;
+    }
+    writeln!(f)?;
+    for (outer_index, value) in &outer_values {
+        write!(f, "{:>10}", format_attribute(*value))?;
+        for inner_index in inner_values.keys() {
+            match cells.get(&(*outer_index, *inner_index)) {
+                Some(step) => write!(f, " {step:>8}")?,
+                None => write!(f, " {:>8}", "-")?,
+            }
+        }
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+fn validate_point(point: &SweepPoint, config: &DriverConfig) -> Vec<String> {
+    let settings = point.settings;
+    let mut errors = vec![];
+    if settings.period.is_zero() {
+        errors.push("period is zero".to_string());
+    }
+    if !settings.pkpk.is_finite() || settings.pkpk < 0. {
+        errors.push(format!(
+            "pkpk {} is not a non-negative number",
+            settings.pkpk
+        ));
+    }
+    if !settings.offset.is_finite() {
+        errors.push(format!("offset {} is not a number", settings.offset));
+    }
+    if !(0. ..=100.).contains(&settings.symmetry_p) {
+        errors.push(format!(
+            "symmetry {}% is outside 0-100%",
+            settings.symmetry_p
+        ));
+    }
+    if let Err(e) = config.check_output_range(settings.pkpk, settings.offset) {
+        errors.push(format!("{e:#}"));
+    }
+    if point.n_waves == 0 {
+        errors.push("no waves to capture".to_string());
+    }
+    let capture = u32::try_from(point.n_waves + 1)
+        .ok()
+        .and_then(|n| settings.period.checked_mul(n));
+    match (capture, config.max_duration) {
+        (None, _) => errors.push("capture duration overflows".to_string()),
+        (Some(capture), Some(max)) if capture > max => errors.push(format!(
+            "capture of {:.0} s exceeds the configured maximum of {:.0} s",
+            capture.as_secs_f64(),
+            max.as_secs_f64()
+        )),
+        _ => {}
+    }
+    errors
+}
+
+// creates every output file in a scratch folder, catching names the filesystem rejects
+fn check_writable(points: &[PlannedPoint]) -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("power-automate-plan-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Could not create `{}`", dir.display()))?;
+    let res = points.iter().try_for_each(|p| {
+        std::fs::File::create(dir.join(&p.name))
+            .with_context(|| format!("Could not create `{}`", p.name))?;
+        Ok(())
+    });
+    std::fs::remove_dir_all(&dir)?;
+    res
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("the sweep deadline was reached")]
+struct DeadlineReached;
+
+// sent to a running sweep from the keyboard, or anything else holding the sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SweepCommand {
+    // finish the current point, then wait for `Resume`
+    Pause,
+    Resume,
+    // stop the current point and discard its data
+    Skip,
+    Abort,
+}
+
+enum PointOutcome {
+    Finished(Result<PathBuf>),
+    Deadline,
+    Skipped,
+    Aborted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RestPolicy {
+    pub hold_voltage: f64,
+    pub duration: Duration,
+    pub before_first: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SweepOptions {
+    pub format: OutputFormat,
+    pub compress: bool,
+    pub plot: bool,
+    pub rest_between_points: Option<RestPolicy>,
+    // wait for this time before the first point
+    pub start_at: Option<DateTime<Local>>,
+    // points that would run past this are deferred, and a point still running is cut short
+    pub deadline: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PointStatus {
+    InProgress,
+    Complete,
+    Deferred,
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub settings: WavegenSettings,
+    pub n_waves: usize,
+    pub size: u64,
+    pub checksum: u32,
+    pub status: PointStatus,
+    #[serde(default)]
+    pub report: Option<AcquisitionInfo>,
+    #[serde(default)]
+    pub aux: Vec<AuxReading>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grid: Option<GridPosition>,
+    // hook name to the error it failed with on this point
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hook_errors: BTreeMap<String, String>,
+}
+
+// completed points keyed by their output filename
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub points: BTreeMap<String, ManifestEntry>,
+}
+impl Manifest {
+    pub fn load(folder: &Path) -> Result<Self> {
+        let path = folder.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse `{}`", path.display()))
+    }
+    pub fn save(&self, folder: &Path) -> Result<()> {
+        // write then rename so a crash never leaves a half-written manifest
+        let tmp = folder.join(format!("{MANIFEST_FILE}.tmp"));
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(tmp, folder.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+    pub fn is_complete(&self, folder: &Path, filename: &str) -> Result<bool> {
+        let Some(entry) = self.points.get(filename) else {
+            return Ok(false);
+        };
+        let path = folder.join(filename);
+        if entry.status != PointStatus::Complete || !path.exists() {
+            return Ok(false);
+        }
+        let (size, checksum) = file_checksum(&path)?;
+        Ok(entry.size == size && entry.checksum == checksum)
+    }
+}
+
+// size and checksum of the written file, and the data handed back for the hooks
+type Written = ((u64, u32), DatFile);
+
+// a point's file being written on a blocking thread while the sweep moves on
+struct PendingWrite {
+    name: String,
+    point: SweepPoint,
+    path: PathBuf,
+    info: AcquisitionInfo,
+    task: JoinHandle<Result<Written>>,
+}
+impl PendingWrite {
+    async fn join(&mut self) -> Result<Written> {
+        (&mut self.task)
+            .await
+            .with_context(|| format!("Writing {} panicked", self.name))?
+    }
+}
+
+pub struct SweepRunner<'a> {
+    driver: &'a mut AquisitionDriver,
+    folder: PathBuf,
+    options: SweepOptions,
+    manifest: Manifest,
+    events: EventLog,
+    notifier: Notifier,
+    commands: Option<UnboundedReceiver<SweepCommand>>,
+    paused: bool,
+    aux: Option<Box<dyn AuxLogger>>,
+    aux_interval: Option<Duration>,
+    pending_write: Option<PendingWrite>,
+    hooks: Vec<Box<dyn AcqHook>>,
+    succeeded: usize,
+    failed: usize,
+}
+impl<'a> SweepRunner<'a> {
+    pub fn new(
+        driver: &'a mut AquisitionDriver,
+        folder: impl Into<PathBuf>,
+        options: SweepOptions,
+    ) -> Result<Self> {
+        let folder = folder.into();
+        let manifest = Manifest::load(&folder)?;
+        let events = EventLog::open(folder.join(EVENTS_FILE))?;
+        driver.set_event_log(Some(events.clone()));
+        Ok(Self {
+            driver,
+            folder,
+            options,
+            manifest,
+            events,
+            notifier: Notifier::default(),
+            commands: None,
+            paused: false,
+            aux: None,
+            aux_interval: None,
+            pending_write: None,
+            hooks: vec![],
+            succeeded: 0,
+            failed: 0,
+        })
+    }
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+    // samples before and after every point, and every `interval` while one is running
+    pub fn with_aux_logger(
+        mut self,
+        logger: impl AuxLogger + 'static,
+        interval: Option<Duration>,
+    ) -> Self {
+        self.aux = Some(Box::new(logger));
+        self.aux_interval = interval.filter(|i| !i.is_zero());
+        self
+    }
+    // run after each point's file is written, see `AcqHook`
+    pub fn with_hook(mut self, hook: impl AcqHook + 'static) -> Self {
+        self.add_hook(hook);
+        self
+    }
+    pub fn add_hook(&mut self, hook: impl AcqHook + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+    pub fn with_commands(mut self, commands: UnboundedReceiver<SweepCommand>) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+    pub async fn run(&mut self, points: &[SweepPoint]) -> Result<()> {
+        if let Some(start_at) = self.options.start_at {
+            wait_until(&self.driver.progress(), start_at).await?;
+        }
+        let start = Instant::now();
+        // WaveForms may have been touched by hand since the driver last applied anything
+        self.driver.invalidate_cache();
+        let guard = self.driver.wavegen_guard();
+        let bar = self
+            .driver
+            .progress()
+            .add(
+                ProgressBar::new(points.len() as u64).with_style(ProgressStyle::with_template(
+                    "point {pos} of {len} {bar:40.green/white} {msg}",
+                )?),
+            );
+        let res = self.run_points(points, start, &bar).await;
+        let flushed = self.finish_write(start).await;
+        let res = res.and(flushed);
+        bar.finish();
+        let (title, message) = match &res {
+            Ok(()) => (
+                "Sweep complete".to_string(),
+                format!("{} points", points.len()),
+            ),
+            Err(e) => ("Sweep aborted".to_string(), format!("{e:#}")),
+        };
+        self.notify(title, message, start).await;
+        let commands = self.driver.bridge_stats();
+        print_bridge_stats(&commands);
+        self.events.log(Event::BridgeStats { commands });
+        if res.is_ok() {
+            guard.disarm();
+        }
+        res
+    }
+    async fn run_points(
+        &mut self,
+        points: &[SweepPoint],
+        start: Instant,
+        bar: &ProgressBar,
+    ) -> Result<()> {
+        let mut first = true;
+        for (i, point) in points.iter().enumerate() {
+            bar.set_position(i as u64 + 1);
+            bar.set_message(point.filename(self.options));
+            if self.is_complete(point)? {
+                let name = point.filename(self.options);
+                self.events.log(Event::PointSkipped { name });
+                continue;
+            }
+            self.wait_if_paused().await?;
+            let rest = self
+                .options
+                .rest_between_points
+                .filter(|rest| !first || rest.before_first);
+            if let Some(deadline) = self.options.deadline {
+                let needed = point.estimated_duration(&self.driver.config)
+                    + rest.map_or(Duration::ZERO, |r| r.duration);
+                let remaining = (deadline - Local::now()).to_std().unwrap_or_default();
+                if needed > remaining {
+                    return self.defer(&points[i..]);
+                }
+            }
+            // the previous point's file is still being written while the next is set up and
+            // warmed up
+            let mut write = self.pending_write.take();
+            let driver = &mut *self.driver;
+            let prepare = async {
+                if let Some(rest) = rest {
+                    driver.rest(rest.hold_voltage, rest.duration).await?;
+                }
+                match point.warmup_periods {
+                    0 => driver.apply_wavegen_settings(point.settings).await,
+                    n => driver.warm_up(point.settings, n).await,
+                }
+            };
+            let written = async {
+                match &mut write {
+                    Some(write) => Some(write.join().await),
+                    None => None,
+                }
+            };
+            let (prepared, written) = tokio::join!(prepare, written);
+            if let (Some(write), Some(written)) = (write, written) {
+                self.complete_write(write, written, start).await?;
+            }
+            prepared?;
+            first = false;
+            let res = self.start_point(*point, true).await;
+            if self.driver.recovery_attempts() > 0 {
+                self.driver.invalidate_cache();
+            }
+            match res {
+                Ok(_) => {}
+                Err(e) if e.is::<DeadlineReached>() => {
+                    self.driver.stop_wavegen().await?;
+                    return self.defer(&points[i + 1..]);
+                }
+                // the point is left in progress so a resumed sweep tries it again
+                Err(e) if e.is::<RecoveryFailed>() => {
+                    let name = point.filename(self.options);
+                    self.notify(format!("Point {name} failed"), format!("{e:#}"), start)
+                        .await;
+                }
+                Err(e) => {
+                    let name = point.filename(self.options);
+                    self.notify(format!("Point {name} failed"), format!("{e:#}"), start)
+                        .await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+    // applies commands sent between points, holding here while paused
+    async fn wait_if_paused(&mut self) -> Result<()> {
+        let Some(commands) = &mut self.commands else {
+            return Ok(());
+        };
+        apply_queued(commands, &mut self.paused).await?;
+        self.driver.progress_status().set(None);
+        Ok(())
+    }
+    // records the points that didn't fit before the deadline so a resumed run picks them up
+    fn defer(&mut self, points: &[SweepPoint]) -> Result<()> {
+        let mut deferred = 0;
+        for point in points {
+            if self.is_complete(point)? {
+                continue;
+            }
+            let name = point.filename(self.options);
+            self.manifest.points.insert(
+                name.clone(),
+                ManifestEntry {
+                    settings: point.settings,
+                    n_waves: point.n_waves,
+                    size: 0,
+                    checksum: 0,
+                    status: PointStatus::Deferred,
+                    report: None,
+                    aux: vec![],
+                    grid: point.grid,
+                    hook_errors: BTreeMap::new(),
+                },
+            );
+            self.events.log(Event::PointDeferred { name });
+            deferred += 1;
+        }
+        self.manifest.save(&self.folder)?;
+        println!("Deadline reached, deferred {deferred} points");
+        Ok(())
+    }
+    async fn notify(&self, title: String, message: String, start: Instant) {
+        let status = SweepStatus {
+            title,
+            message,
+            folder: self.folder.clone(),
+            elapsed: start.elapsed(),
+            succeeded: self.succeeded,
+            failed: self.failed,
+        };
+        self.notifier.notify(self.driver, &status).await;
+    }
+    // writes `bode.csv` to the run folder, and the raw aquisitions if `keep_raw` is set
+    pub async fn run_frequency_sweep(
+        &mut self,
+        sweep: &FrequencySweep,
+    ) -> Result<Vec<FrequencyResponse>> {
+        let mut responses = vec![];
+        for point in sweep.points() {
+            let frequency_hz = 1. / point.settings.period.as_secs_f64();
+            println!("Running {frequency_hz:.3} Hz");
+            let datfile = self
+                .driver
+                .aquire_n_waves(
+                    point.settings,
+                    point.n_waves,
+                    point.warmup_periods,
+                    point.discard_first_period,
+                )
+                .await?;
+            if sweep.keep_raw {
+                let path = self.folder.join(point.filename(self.options));
+                save(&datfile, &path, self.options.format)?;
+            }
+            let response = Aquisition::from_datfile(&datfile)?.frequency_response(frequency_hz)?;
+            responses.push(response);
+            write_bode(&self.folder.join(BODE_FILE), &responses)?;
+        }
+        Ok(responses)
+    }
+    fn is_complete(&self, point: &SweepPoint) -> Result<bool> {
+        let name = point.filename(self.options);
+        self.manifest.is_complete(&self.folder, &name)
+    }
+    pub async fn run_point(&mut self, point: SweepPoint) -> Result<Option<PathBuf>> {
+        let res = self.start_point(point, false).await;
+        let flushed = self.finish_write(Instant::now()).await;
+        let path = res?;
+        flushed?;
+        Ok(path)
+    }
+    // waits for the last point's file to be written and marks it complete
+    async fn finish_write(&mut self, start: Instant) -> Result<()> {
+        let Some(mut write) = self.pending_write.take() else {
+            return Ok(());
+        };
+        let written = write.join().await;
+        self.complete_write(write, written, start).await
+    }
+    async fn complete_write(
+        &mut self,
+        write: PendingWrite,
+        written: Result<Written>,
+        start: Instant,
+    ) -> Result<()> {
+        let PendingWrite {
+            name,
+            point,
+            path,
+            info,
+            ..
+        } = write;
+        let ((size, checksum), data) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                // the point stays in progress so a resumed sweep records it again
+                let e = e.context(format!("Could not write {name}"));
+                self.failed += 1;
+                self.events.log(Event::PointFailed {
+                    name: name.clone(),
+                    error: format!("{e:#}"),
+                });
+                self.events.log(Event::error(&e));
+                self.notify(format!("Point {name} failed"), format!("{e:#}"), start)
+                    .await;
+                return Err(e);
+            }
+        };
+        if let Some(entry) = self.manifest.points.get_mut(&name) {
+            entry.size = size;
+            entry.checksum = checksum;
+            entry.status = PointStatus::Complete;
+            entry.report = Some(info.clone());
+        }
+        self.manifest.save(&self.folder)?;
+        self.events.log(Event::PointFinished {
+            name: name.clone(),
+            report: info.clone(),
+        });
+        self.succeeded += 1;
+        let report = AcquisitionReport { data, info };
+        self.run_hooks(&name, &point, &path, &report).await
+    }
+    // every hook runs, in the order they were added, even after one fails. the first critical
+    // failure is returned once they have all run
+    async fn run_hooks(
+        &mut self,
+        name: &str,
+        point: &SweepPoint,
+        path: &Path,
+        report: &AcquisitionReport,
+    ) -> Result<()> {
+        if self.hooks.is_empty() {
+            return Ok(());
+        }
+        let mut critical = None;
+        for hook in &self.hooks {
+            let Err(e) = hook.on_point_complete(point, path, report).await else {
+                continue;
+            };
+            let (hook_name, error) = (hook.name().to_string(), format!("{e:#}"));
+            eprintln!("WARNING: hook `{hook_name}` failed on {name}: {error}");
+            self.events.log(Event::HookFailed {
+                name: name.to_string(),
+                hook: hook_name.clone(),
+                error: error.clone(),
+            });
+            if let Some(entry) = self.manifest.points.get_mut(name) {
+                entry.hook_errors.insert(hook_name.clone(), error);
+            }
+            if hook.is_critical() && critical.is_none() {
+                critical = Some(e.context(format!("Critical hook `{hook_name}` failed on {name}")));
+            }
+        }
+        self.manifest.save(&self.folder)?;
+        critical.map_or(Ok(()), Err)
+    }
+    // leaves the point's file writing in the background, see `finish_write`. `warmed_up` when
+    // the caller has already run the point's warm-up
+    async fn start_point(&mut self, point: SweepPoint, warmed_up: bool) -> Result<Option<PathBuf>> {
+        if self.is_complete(&point)? {
+            return Ok(None);
+        }
+        let name = point.filename(self.options);
+        println!("Running {name}: {}", point.settings);
+        self.driver.reset_recoveries();
+        if self.driver.config.check_history_each_point {
+            self.driver.check_history_recording().await?;
+        }
+        let mut entry = ManifestEntry {
+            settings: point.settings,
+            n_waves: point.n_waves,
+            size: 0,
+            checksum: 0,
+            status: PointStatus::InProgress,
+            report: None,
+            aux: vec![],
+            grid: point.grid,
+            hook_errors: BTreeMap::new(),
+        };
+        self.manifest.points.insert(name.clone(), entry.clone());
+        self.manifest.save(&self.folder)?;
+        self.events.log(Event::PointStarted {
+            name: name.clone(),
+            settings: point.settings,
+        });
+        let remaining = self
+            .options
+            .deadline
+            .map(|deadline| (deadline - Local::now()).to_std().unwrap_or_default());
+        let status = self.driver.progress_status();
+        let mut commands = self.commands.take();
+        let mut paused = self.paused;
+        let events = self.events.clone();
+        let (aux, aux_interval) = (self.aux.take(), self.aux_interval);
+        let mut aux_readings = vec![];
+        if let Some(logger) = &aux {
+            aux_readings.extend(sample_aux(&**logger, &events, &name, AuxStage::Before).await);
+        }
+        // put back after the point, however it ends
+        let correction = self.driver.config.amplitude_correction;
+        if point.skip_amplitude_correction {
+            self.driver.config.amplitude_correction = None;
+        }
+        let recording = sample_during(
+            self.record_point(point, &name, warmed_up),
+            aux.as_deref(),
+            aux_interval,
+            &events,
+            &name,
+            &mut aux_readings,
+        );
+        let outcome = supervise(
+            recording,
+            remaining,
+            commands.as_mut(),
+            &status,
+            &mut paused,
+        )
+        .await;
+        self.driver.config.amplitude_correction = correction;
+        self.commands = commands;
+        self.paused = paused;
+        if let Some(logger) = &aux {
+            aux_readings.extend(sample_aux(&**logger, &events, &name, AuxStage::After).await);
+        }
+        self.aux = aux;
+        entry.aux = aux_readings;
+        let res = match outcome {
+            PointOutcome::Finished(res) => {
+                if let Some(recorded) = self.manifest.points.get_mut(&name) {
+                    recorded.aux = entry.aux;
+                }
+                self.manifest.save(&self.folder)?;
+                res
+            }
+            PointOutcome::Deadline => return self.cut_short(name, entry).await,
+            PointOutcome::Skipped => return self.skip(name, entry).await,
+            PointOutcome::Aborted => {
+                self.driver.stop_wavegen().await?;
+                bail!("Sweep aborted during {name}");
+            }
+        };
+        match res {
+            Ok(path) => Ok(Some(path)),
+            Err(e) => {
+                self.failed += 1;
+                self.events.log(Event::PointFailed {
+                    name,
+                    error: format!("{e:#}"),
+                });
+                self.events.log(Event::error(&e));
+                Err(e)
+            }
+        }
+    }
+    async fn skip(&mut self, name: String, mut entry: ManifestEntry) -> Result<Option<PathBuf>> {
+        self.driver.stop_wavegen().await?;
+        println!("Skipped {name}");
+        entry.status = PointStatus::Skipped;
+        self.manifest.points.insert(name.clone(), entry);
+        self.manifest.save(&self.folder)?;
+        self.events.log(Event::PointSkipped { name });
+        Ok(None)
+    }
+    // stops the wavegen at the deadline, keeping whatever history has been captured
+    async fn cut_short(
+        &mut self,
+        name: String,
+        mut entry: ManifestEntry,
+    ) -> Result<Option<PathBuf>> {
+        self.driver.stop_wavegen().await?;
+        let partial = self.folder.join(format!("{name}.partial.dat"));
+        if let Err(e) = self.driver.save_dat(&partial).await {
+            eprintln!("WARNING: could not save partial data: {e:#}");
+        }
+        entry.status = PointStatus::Deferred;
+        self.manifest.points.insert(name.clone(), entry);
+        self.manifest.save(&self.folder)?;
+        self.events.log(Event::PointDeferred { name });
+        Err(DeadlineReached.into())
+    }
+    async fn record_point(
+        &mut self,
+        point: SweepPoint,
+        name: &str,
+        warmed_up: bool,
+    ) -> Result<PathBuf> {
+        let path = self.folder.join(name);
+        let warmup_periods = if warmed_up { 0 } else { point.warmup_periods };
+        let mut report = self
+            .driver
+            .aquire_n_waves_report(
+                point.settings,
+                point.n_waves,
+                warmup_periods,
+                point.discard_first_period,
+            )
+            .await?;
+        report
+            .data
+            .attributes
+            .insert("warmup_periods".into(), point.warmup_periods.to_string());
+        if !report.info.warnings.is_empty() {
+            println!(
+                "{name} finished with {} warnings",
+                report.info.warnings.len()
+            );
+        }
+        for message in &report.info.warnings {
+            self.events.log(Event::Warning {
+                message: message.clone(),
+            });
+        }
+        let (data, format, plot) = (report.data, self.options.format, self.options.plot);
+        let written = path.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            save(&data, &written, format)?;
+            if plot {
+                plot_summary(&data, &written)?;
+            }
+            Ok((file_checksum(&written)?, data))
+        });
+        self.pending_write = Some(PendingWrite {
+            name: name.to_string(),
+            point,
+            path: path.clone(),
+            info: report.info,
+            task,
+        });
+        Ok(path)
+    }
+}
+
+// applies the commands queued between points, waiting for `Resume` while paused
+async fn apply_queued(
+    commands: &mut UnboundedReceiver<SweepCommand>,
+    paused: &mut bool,
+) -> Result<()> {
+    loop {
+        let command = if *paused {
+            println!("Paused, press r to resume");
+            commands.recv().await
+        } else {
+            commands.try_recv().ok()
+        };
+        match command {
+            Some(SweepCommand::Pause) => *paused = true,
+            Some(SweepCommand::Resume) => *paused = false,
+            // there is no point running to skip
+            Some(SweepCommand::Skip) => {}
+            Some(SweepCommand::Abort) => bail!("Sweep aborted"),
+            None => {
+                *paused = false;
+                return Ok(());
+            }
+        }
+    }
+}
+
+// runs a point until it finishes, the deadline passes or a command stops it
+async fn supervise(
+    recording: impl Future<Output = Result<PathBuf>>,
+    remaining: Option<Duration>,
+    mut commands: Option<&mut UnboundedReceiver<SweepCommand>>,
+    status: &ProgressStatus,
+    paused: &mut bool,
+) -> PointOutcome {
+    tokio::pin!(recording);
+    let deadline = async {
+        match remaining {
+            Some(remaining) => tokio::time::sleep(remaining).await,
+            None => pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+    loop {
+        let command = async {
+            match commands.as_mut() {
+                Some(commands) => commands.recv().await,
+                None => pending().await,
+            }
+        };
+        tokio::select! {
+            res = &mut recording => return PointOutcome::Finished(res),
+            _ = &mut deadline => return PointOutcome::Deadline,
+            Some(command) = command => match command {
+                SweepCommand::Pause => {
+                    *paused = true;
+                    status.set(Some("pausing after this point".into()));
+                }
+                SweepCommand::Resume => {
+                    *paused = false;
+                    status.set(None);
+                }
+                SweepCommand::Skip => return PointOutcome::Skipped,
+                SweepCommand::Abort => return PointOutcome::Aborted,
+            },
+        }
+    }
+}
+
+// a failed read is only a warning, the point goes ahead without it
+async fn sample_aux(
+    logger: &dyn AuxLogger,
+    events: &EventLog,
+    name: &str,
+    stage: AuxStage,
+) -> Option<AuxReading> {
+    match logger.sample().await {
+        Ok(values) => {
+            let reading = AuxReading {
+                time: Local::now(),
+                stage,
+                values,
+            };
+            events.log(Event::AuxSampled {
+                name: name.into(),
+                reading: reading.clone(),
+            });
+            Some(reading)
+        }
+        Err(e) => {
+            let message = format!("Could not read the auxiliary channels for {name}: {e:#}");
+            eprintln!("WARNING: {message}");
+            events.log(Event::Warning { message });
+            None
+        }
+    }
+}
+
+async fn sample_during<T>(
+    recording: impl Future<Output = T>,
+    logger: Option<&dyn AuxLogger>,
+    interval: Option<Duration>,
+    events: &EventLog,
+    name: &str,
+    readings: &mut Vec<AuxReading>,
+) -> T {
+    let (Some(logger), Some(interval)) = (logger, interval) else {
+        return recording.await;
+    };
+    tokio::pin!(recording);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            out = &mut recording => return out,
+            _ = ticker.tick() => {
+                readings.extend(sample_aux(logger, events, name, AuxStage::During).await);
+            }
+        }
+    }
+}
+
+async fn wait_until(progress: &MultiProgress, start_at: DateTime<Local>) -> Result<()> {
+    let Ok(wait) = (start_at - Local::now()).to_std() else {
+        return Ok(());
+    };
+    println!(
+        "Waiting until {}, press Ctrl+C to cancel",
+        start_at.format("%Y-%m-%d %H:%M:%S")
+    );
+    let message = format!("starting at {}", start_at.format("%H:%M"));
+    tokio::select! {
+        res = wait_with_progress(progress, wait, message) => res,
+        _ = tokio::signal::ctrl_c() => bail!("Cancelled while waiting to start"),
+    }
+}
+
+fn write_bode(path: &Path, responses: &[FrequencyResponse]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["frequency_hz", "magnitude", "phase_deg", "coherence"])?;
+    for r in responses {
+        writer.write_record(
+            [r.frequency_hz, r.magnitude, r.phase_deg, r.coherence].map(|v| v.to_string()),
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn print_bridge_stats(commands: &BTreeMap<String, CommandStats>) {
+    if commands.is_empty() {
+        return;
+    }
+    println!(
+        "{:<28} {:>6} {:>6} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "command", "count", "failed", "timeouts", "queue ms", "flow ms", "mean ms", "p95 ms"
+    );
+    for (name, s) in commands {
+        println!(
+            "{:<28} {:>6} {:>6} {:>8} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+            name,
+            s.count,
+            s.failures,
+            s.queue_timeouts + s.flow_timeouts,
+            s.mean_queue_ms,
+            s.mean_turnaround_ms,
+            s.mean_ms,
+            s.p95_ms
+        );
+    }
+}
+
+fn file_checksum(path: &Path) -> Result<(u64, u32)> {
+    let bytes = std::fs::read(path)?;
+    Ok((bytes.len() as u64, crc32fast::hash(&bytes)))
+}
+
+pub fn filename(settings: WavegenSettings, options: SweepOptions) -> String {
+    with_extension(settings.file_stem(), options)
+}
+
+fn with_extension(stem: String, options: SweepOptions) -> String {
+    format!(
+        "{stem}.{}{}",
+        options.format.extension(),
+        if options.compress { ".gz" } else { "" },
+    )
+}
+
+#[cfg(feature = "plot")]
+fn plot_summary(datfile: &DatFile, path: &Path) -> Result<()> {
+    let aq = Aquisition::from_datfile(datfile)?;
+    aq.plot_summary(path.with_extension("png"))?;
+    aq.plot_hysteresis(path.with_extension("loop.png"))
+}
+
+#[cfg(not(feature = "plot"))]
+fn plot_summary(_datfile: &DatFile, _path: &Path) -> Result<()> {
+    bail!("Plotting requires the `plot` feature")
+}
+
+fn save(datfile: &DatFile, path: &Path, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Dat => {
+            let writer = BufWriter::new(std::fs::File::create(path)?);
+            if is_gzip_path(path) {
+                let mut encoder = GzEncoder::new(writer, Compression::default());
+                datfile.write_to(&mut encoder)?;
+                encoder.finish()?;
+            } else {
+                datfile.write_to(writer)?;
+            }
+        }
+        _ => Aquisition::from_datfile(datfile)?.write_as(path, format)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auxiliary::MockAuxLogger, power_automate::fake::*, test_util::TempDir};
+
+    fn settings() -> WavegenSettings {
+        WavegenSettings {
+            pkpk: 200.,
+            period: Duration::from_secs(2),
+            symmetry_p: 100.,
+            offset: 0.,
+        }
+    }
+
+    fn entry(path: &Path, status: PointStatus) -> ManifestEntry {
+        let (size, checksum) = file_checksum(path).unwrap();
+        ManifestEntry {
+            settings: settings(),
+            n_waves: 3,
+            size,
+            checksum,
+            status,
+            report: None,
+            aux: vec![],
+            grid: None,
+            hook_errors: BTreeMap::new(),
+        }
+    }
+
+    fn grid(snake: bool) -> SweepGrid {
+        SweepGrid {
+            base: SweepPoint {
+                settings: settings(),
+                n_waves: 3,
+                warmup_periods: 1,
+                discard_first_period: false,
+                skip_amplitude_correction: false,
+                grid: None,
+            },
+            outer: GridAxis {
+                axis: SweepAxis::Pkpk,
+                values: AxisValues::List(vec![100., 200., 300.]),
+            },
+            inner: GridAxis {
+                axis: SweepAxis::Period,
+                values: AxisValues::Range {
+                    start: 1.,
+                    stop: 2.5,
+                    n: 4,
+                    log: false,
+                },
+            },
+            snake,
+        }
+    }
+
+    #[test]
+    fn grid_visits_every_cell_once() {
+        for snake in [false, true] {
+            let points = grid(snake).points().unwrap();
+            let cells = points
+                .iter()
+                .map(|p| {
+                    let g = p.grid.unwrap();
+                    let expected = (
+                        100. * (g.outer_index + 1) as f64,
+                        1. + 0.5 * g.inner_index as f64,
+                    );
+                    assert_eq!((p.settings.pkpk, p.settings.period.as_secs_f64()), expected);
+                    assert_eq!(p.settings.symmetry_p, 100.);
+                    (g.outer_index, g.inner_index)
+                })
+                .collect_vec();
+            let all = (0..3).cartesian_product(0..4).collect_vec();
+            assert_eq!(cells.iter().copied().sorted().collect_vec(), all, "{snake}");
+            // snaking runs every other row backwards, so each step lands on a neighbouring cell
+            let inner_order = cells.iter().map(|c| c.1).collect_vec();
+            match snake {
+                false => assert_eq!(inner_order, [0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3]),
+                true => assert_eq!(inner_order, [0, 1, 2, 3, 3, 2, 1, 0, 0, 1, 2, 3]),
+            }
+        }
+        let mut same_axis = grid(false);
+        same_axis.inner.axis = SweepAxis::Pkpk;
+        assert!(same_axis.points().is_err());
+    }
+
+    // a recording that never finishes, so only a command or the deadline ends it
+    fn endless() -> impl Future<Output = Result<PathBuf>> {
+        pending()
+    }
+
+    async fn recorded_after(delay: Duration) -> Result<PathBuf> {
+        tokio::time::sleep(delay).await;
+        Ok("point.dat".into())
+    }
+
+    #[tokio::test]
+    async fn commands_stop_or_pause_a_running_point() {
+        let (send, mut commands) = tokio::sync::mpsc::unbounded_channel();
+        let status = ProgressStatus::default();
+        let mut paused = false;
+        for (command, stopped) in [
+            (SweepCommand::Skip, "skipped"),
+            (SweepCommand::Abort, "aborted"),
+        ] {
+            send.send(command).unwrap();
+            let outcome =
+                supervise(endless(), None, Some(&mut commands), &status, &mut paused).await;
+            let outcome = match outcome {
+                PointOutcome::Skipped => "skipped",
+                PointOutcome::Aborted => "aborted",
+                _ => "other",
+            };
+            assert_eq!(outcome, stopped);
+        }
+
+        // a pause lets the point finish
+        send.send(SweepCommand::Pause).unwrap();
+        let recording = recorded_after(Duration::from_millis(50));
+        let outcome = supervise(recording, None, Some(&mut commands), &status, &mut paused).await;
+        assert!(matches!(outcome, PointOutcome::Finished(Ok(_))));
+        assert!(paused);
+        assert_eq!(status.get().as_deref(), Some("pausing after this point"));
+        send.send(SweepCommand::Resume).unwrap();
+        let recording = recorded_after(Duration::from_millis(50));
+        supervise(recording, None, Some(&mut commands), &status, &mut paused).await;
+        assert!(!paused);
+        assert_eq!(status.get(), None);
+
+        let remaining = Some(Duration::from_millis(20));
+        let outcome = supervise(
+            endless(),
+            remaining,
+            Some(&mut commands),
+            &status,
+            &mut paused,
+        )
+        .await;
+        assert!(matches!(outcome, PointOutcome::Deadline));
+        let outcome = supervise(
+            recorded_after(Duration::ZERO),
+            None,
+            None,
+            &status,
+            &mut paused,
+        )
+        .await;
+        assert!(matches!(outcome, PointOutcome::Finished(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn paused_sweeps_wait_for_resume_between_points() {
+        let (send, mut commands) = tokio::sync::mpsc::unbounded_channel();
+        let mut paused = false;
+        // nothing queued goes straight through, and a skip between points is ignored
+        send.send(SweepCommand::Skip).unwrap();
+        apply_queued(&mut commands, &mut paused).await.unwrap();
+        assert!(!paused);
+
+        send.send(SweepCommand::Pause).unwrap();
+        let waiting = tokio::time::timeout(
+            Duration::from_millis(50),
+            apply_queued(&mut commands, &mut paused),
+        )
+        .await;
+        assert!(waiting.is_err() && paused);
+        send.send(SweepCommand::Resume).unwrap();
+        apply_queued(&mut commands, &mut paused).await.unwrap();
+        assert!(!paused);
+
+        send.send(SweepCommand::Pause).unwrap();
+        send.send(SweepCommand::Abort).unwrap();
+        let err = apply_queued(&mut commands, &mut paused).await.unwrap_err();
+        assert!(err.to_string().contains("aborted"), "{err}");
+    }
+
+    #[test]
+    fn frequency_sweep_is_log_spaced_triangles() {
+        let frequencies = FrequencySweep::log_spaced(0.1, 10., 5);
+        let expected = [0.1, 0.316227766, 1., 3.16227766, 10.];
+        for (f, e) in frequencies.iter().zip(expected) {
+            assert!((f / e - 1.).abs() < 1e-8, "{frequencies:?}");
+        }
+        assert_eq!(FrequencySweep::log_spaced(2., 10., 1), [2.]);
+        let sweep = FrequencySweep {
+            frequencies_hz: vec![0.5, 4.],
+            pkpk: 20.,
+            offset: 1.,
+            settle_cycles: 2,
+            measure_cycles: 5,
+            keep_raw: false,
+        };
+        let points = sweep.points();
+        assert_eq!(points[0].settings.period, Duration::from_secs(2));
+        assert_eq!(points[1].settings.period, Duration::from_millis(250));
+        assert!(points.iter().all(|p| p.settings.symmetry_p == 100.
+            && p.settings.pkpk == 20.
+            && p.n_waves == 5
+            && p.warmup_periods == 2));
+    }
+
+    #[test]
+    fn manifest_only_trusts_intact_complete_files() {
+        let dir = TempDir::new("manifest");
+        let folder = dir.path();
+        let mut manifest = Manifest::default();
+        for name in [
+            "intact.dat",
+            "truncated.dat",
+            "corrupt.dat",
+            "missing.dat",
+            "running.dat",
+        ] {
+            let path = folder.join(name);
+            std::fs::write(&path, "pkpk\t200\t\n\n[DATA]\n1\t2\t3\n").unwrap();
+            let status = match name {
+                "running.dat" => PointStatus::InProgress,
+                _ => PointStatus::Complete,
+            };
+            manifest.points.insert(name.into(), entry(&path, status));
+        }
+        std::fs::write(folder.join("truncated.dat"), "pkpk\t200\t\n\n[DATA]\n1\t2").unwrap();
+        std::fs::write(
+            folder.join("corrupt.dat"),
+            "pkpk\t200\t\n\n[DATA]\n1\t2\t4\n",
+        )
+        .unwrap();
+        std::fs::remove_file(folder.join("missing.dat")).unwrap();
+        manifest.save(folder).unwrap();
+
+        let manifest = Manifest::load(folder).unwrap();
+        assert_eq!(manifest.points.len(), 5);
+        let complete = |name| manifest.is_complete(folder, name).unwrap();
+        assert!(complete("intact.dat"));
+        for name in [
+            "truncated.dat",
+            "corrupt.dat",
+            "missing.dat",
+            "running.dat",
+            "unknown.dat",
+        ] {
+            assert!(!complete(name), "{name}");
+        }
+        assert!(Manifest::load(TempDir::new("no_manifest").path())
+            .unwrap()
+            .points
+            .is_empty());
+    }
+
+    fn point(period_ms: u64, warmup_periods: usize) -> SweepPoint {
+        SweepPoint {
+            settings: WavegenSettings {
+                period: Duration::from_millis(period_ms),
+                ..settings()
+            },
+            n_waves: 1,
+            warmup_periods,
+            discard_first_period: false,
+            skip_amplitude_correction: false,
+            grid: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_writes_overlap_the_next_warm_up() {
+        let folder = TempDir::new("slow_writer");
+        let mut driver = bridged_driver(DriverConfig {
+            session_file: None,
+            ..Default::default()
+        });
+        let (flow, seen) = fake_flow(&driver, running_waveforms());
+        let mut runner =
+            SweepRunner::new(&mut driver, folder.path(), SweepOptions::default()).unwrap();
+        // the last point recorded, its file failing to write after as long as the next warm-up
+        let (last, next) = (point(2000, 0), point(100, 3));
+        let name = last.filename(runner.options);
+        let delay = Duration::from_millis(300);
+        let now = Local::now();
+        let entry = ManifestEntry {
+            settings: last.settings,
+            n_waves: last.n_waves,
+            size: 0,
+            checksum: 0,
+            status: PointStatus::InProgress,
+            report: None,
+            aux: vec![],
+            grid: None,
+            hook_errors: BTreeMap::new(),
+        };
+        runner.manifest.points.insert(name.clone(), entry);
+        runner.manifest.save(folder.path()).unwrap();
+        runner.pending_write = Some(PendingWrite {
+            name: name.clone(),
+            point: last,
+            path: folder.join(&name),
+            info: AcquisitionInfo {
+                windows: 1,
+                skipped_windows: 0,
+                unmatched_seams: 0,
+                gaps: 0,
+                trimmed_samples: 0,
+                started_at: now,
+                finished_at: now,
+                retries: 0,
+                window_intervals: vec![],
+                warnings: vec![],
+            },
+            task: tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                bail!("disk full")
+            }),
+        });
+
+        let start = Instant::now();
+        let res = runner
+            .run_points(&[next], start, &ProgressBar::hidden())
+            .await;
+        let elapsed = start.elapsed();
+        assert!(format!("{:#}", res.unwrap_err()).contains("disk full"));
+        // the write and the warm-up ran side by side, where one after the other would take at
+        // least twice the delay
+        assert!(elapsed >= delay && elapsed < delay * 2, "{elapsed:?}");
+        assert!(seen
+            .lock()
+            .unwrap()
+            .contains(&"wavegen_set_period".to_string()));
+        drop(runner);
+        let manifest = Manifest::load(folder.path()).unwrap();
+        // the next point never started, and the failed one is left to be recorded again
+        assert_eq!(manifest.points.len(), 1);
+        assert_eq!(manifest.points[&name].status, PointStatus::InProgress);
+        let events = std::fs::read_to_string(folder.join(EVENTS_FILE)).unwrap();
+        assert!(events.contains("disk full"));
+        flow.abort();
+    }
+
+    #[tokio::test]
+    async fn aux_readings_are_kept_and_failures_only_warn() {
+        for fail in [false, true] {
+            let folder = TempDir::new(&format!("aux_{fail}"));
+            let mut driver = bridged_driver(DriverConfig {
+                session_file: None,
+                ..Default::default()
+            });
+            let (flow, seen) = fake_flow(&driver, running_waveforms());
+            let logger = MockAuxLogger {
+                values: BTreeMap::from([("temperature".into(), 4.2)]),
+                fail,
+            };
+            let (send, commands) = tokio::sync::mpsc::unbounded_channel();
+            let mut runner = SweepRunner::new(&mut driver, folder.path(), SweepOptions::default())
+                .unwrap()
+                .with_aux_logger(logger, Some(Duration::from_millis(50)))
+                .with_commands(commands);
+            // skipped part way through the warm-up, so no history is read
+            let skip = tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                send.send(SweepCommand::Skip).unwrap();
+            });
+            let point = point(100, 10);
+            let name = point.filename(runner.options);
+            assert_eq!(runner.run_point(point).await.unwrap(), None, "{fail}");
+            skip.await.unwrap();
+            drop(runner);
+            // the point went ahead whether or not the readings could be taken
+            assert!(seen
+                .lock()
+                .unwrap()
+                .contains(&"wavegen_is_running".to_string()));
+            let entry = &Manifest::load(folder.path()).unwrap().points[&name];
+            assert_eq!(entry.status, PointStatus::Skipped);
+            let events = std::fs::read_to_string(folder.join(EVENTS_FILE)).unwrap();
+            if fail {
+                assert!(entry.aux.is_empty());
+                assert!(!events.contains("aux_sampled"));
+                assert!(events.contains("Could not read the auxiliary channels"));
+                flow.abort();
+                continue;
+            }
+            let stages = entry.aux.iter().map(|r| r.stage).dedup().collect_vec();
+            assert_eq!(
+                stages,
+                [AuxStage::Before, AuxStage::During, AuxStage::After]
+            );
+            assert!(entry.aux.iter().all(|r| r.values["temperature"] == 4.2));
+            let sampled = events.lines().filter(|l| l.contains("aux_sampled")).count();
+            assert_eq!(sampled, entry.aux.len());
+            assert!(!events.contains("Could not read the auxiliary channels"));
+            flow.abort();
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::path::{Path, PathBuf};
+
+// an empty folder under the temp directory, unique to this process and test, removed again
+// when dropped
+pub(crate) struct TempDir(PathBuf);
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = Self::root().join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+    fn root() -> PathBuf {
+        std::env::temp_dir().join(format!("power-automate-test-{}", std::process::id()))
+    }
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+    pub(crate) fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.0.join(name)
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+        // only succeeds once the last test's folder is gone
+        let _ = std::fs::remove_dir(Self::root());
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use power_automate::bridge::PowerAutomate;
+use serde_json::{json, Value};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Debug, Clone, Copy)]
+enum Flow {
+    Polling,
+    WebSocket,
+}
+
+// what the flow sends back for a command, `None` to leave it unanswered
+type Answer = fn(&Value) -> Option<String>;
+
+fn answer(command: &Value) -> Option<String> {
+    let response = match command["command"].as_str()? {
+        "echo" => json!({ "Ok": command["message"] }),
+        "wavegen_is_running" => json!({ "Ok": true }),
+        _ => json!({ "Ok": null }),
+    };
+    Some(response.to_string())
+}
+
+fn bridge() -> PowerAutomate {
+    PowerAutomate::bind("127.0.0.1:0".parse().unwrap()).with_command_timeout(Duration::from_secs(5))
+}
+
+async fn http(addr: SocketAddr, method: Method, path: &str, body: String) -> (StatusCode, String) {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("http://{addr}{path}"))
+        .body(Body::from(body))
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+// a stand-in for the power automate flow, recording every command it receives
+fn spawn_flow(
+    pa: &PowerAutomate,
+    flow: Flow,
+    answer: Answer,
+) -> (JoinHandle<()>, Arc<Mutex<Vec<Value>>>) {
+    let addr = pa.local_addr();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let recorded = seen.clone();
+    let handle = tokio::spawn(async move {
+        match flow {
+            Flow::Polling => loop {
+                let (_, body) = http(addr, Method::GET, "/?instance=fake", String::new()).await;
+                if body.is_empty() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    continue;
+                }
+                let command: Value = serde_json::from_str(&body).unwrap();
+                recorded.lock().unwrap().push(command.clone());
+                if let Some(response) = answer(&command) {
+                    let path = format!("/{}?instance=fake", command["id"]);
+                    http(addr, Method::POST, &path, response).await;
+                }
+            },
+            Flow::WebSocket => {
+                let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+                    .await
+                    .unwrap();
+                while let Some(Ok(message)) = socket.next().await {
+                    let Message::Text(text) = message else {
+                        continue;
+                    };
+                    let command: Value = serde_json::from_str(&text).unwrap();
+                    recorded.lock().unwrap().push(command.clone());
+                    if let Some(response) = answer(&command) {
+                        let response: Value = serde_json::from_str(&response).unwrap();
+                        let frame = json!({ "id": command["id"], "response": response });
+                        socket.send(Message::Text(frame.to_string())).await.unwrap();
+                    }
+                }
+            }
+        }
+    });
+    (handle, seen)
+}
+
+async fn connect(
+    pa: &PowerAutomate,
+    flow: Flow,
+    answer: Answer,
+) -> (JoinHandle<()>, Arc<Mutex<Vec<Value>>>) {
+    let spawned = spawn_flow(pa, flow, answer);
+    if let Flow::WebSocket = flow {
+        assert!(pa.wait_for_websocket(Duration::from_secs(5)).await);
+    }
+    spawned
+}
+
+#[tokio::test]
+async fn commands_round_trip_over_both_transports() {
+    for flow in [Flow::Polling, Flow::WebSocket] {
+        let pa = bridge();
+        let (handle, seen) = connect(&pa, flow, answer).await;
+        assert_eq!(pa.echo("hello").await.unwrap(), "hello", "{flow:?}");
+        assert!(pa.wavegen_is_running().await.unwrap(), "{flow:?}");
+        assert_eq!(pa.stats()["echo"].count, 1, "{flow:?}");
+        assert_eq!(seen.lock().unwrap().len(), 2, "{flow:?}");
+        handle.abort();
+    }
+}
+
+#[tokio::test]
+async fn unanswered_commands_time_out_on_both_transports() {
+    for flow in [Flow::Polling, Flow::WebSocket] {
+        let pa = bridge().with_command_timeout(Duration::from_millis(200));
+        let (handle, seen) = connect(&pa, flow, |_| None).await;
+        let err = pa.echo("hello").await.unwrap_err();
+        assert!(
+            err.to_string().contains("did not answer `echo`"),
+            "{flow:?}: {err}"
+        );
+        assert_eq!(seen.lock().unwrap().len(), 1, "{flow:?}");
+        assert_eq!(pa.stats()["echo"].flow_timeouts, 1, "{flow:?}");
+        handle.abort();
+    }
+}
+
+#[tokio::test]
+async fn commands_queued_past_their_timeout_are_never_handed_out() {
+    let pa = bridge().with_command_timeout(Duration::from_millis(200));
+    assert!(pa.echo("stale").await.is_err());
+    assert_eq!(pa.stats()["echo"].queue_timeouts, 1);
+    let (handle, seen) = connect(&pa, Flow::Polling, answer).await;
+    assert_eq!(pa.echo("fresh").await.unwrap(), "fresh");
+    let messages = seen
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|c| c["message"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, [json!("fresh")]);
+    handle.abort();
+}
+
+#[tokio::test]
+async fn websocket_disconnect_falls_back_to_polling() {
+    let pa = bridge();
+    let addr = pa.local_addr();
+    // takes one command and hangs up without answering it
+    let dropper = tokio::spawn(async move {
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+        socket.next().await;
+    });
+    assert!(pa.wait_for_websocket(Duration::from_secs(5)).await);
+    let (handle, seen) = spawn_flow(&pa, Flow::Polling, answer);
+    assert_eq!(pa.echo("hello").await.unwrap(), "hello");
+    assert_eq!(seen.lock().unwrap().len(), 1);
+    assert!(!pa.websocket_connected());
+    dropper.await.unwrap();
+    handle.abort();
+}
+
+#[tokio::test]
+async fn polling_responses_cannot_answer_websocket_commands() {
+    let pa = bridge().with_command_timeout(Duration::from_millis(500));
+    let addr = pa.local_addr();
+    let (ids_send, mut ids) = mpsc::unbounded_channel();
+    let silent = tokio::spawn(async move {
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+        while let Some(Ok(Message::Text(text))) = socket.next().await {
+            let command: Value = serde_json::from_str(&text).unwrap();
+            ids_send.send(command["id"].as_u64().unwrap()).unwrap();
+        }
+    });
+    assert!(pa.wait_for_websocket(Duration::from_secs(5)).await);
+    let poster = async {
+        let id = ids.recv().await.unwrap();
+        let wrong = json!({ "Ok": "wrong" }).to_string();
+        let (next, _) = http(addr, Method::POST, "/", wrong.clone()).await;
+        let (by_id, _) = http(addr, Method::POST, &format!("/{id}"), wrong).await;
+        (next, by_id)
+    };
+    let (res, (next, by_id)) = tokio::join!(pa.echo("hello"), poster);
+    assert_eq!((next, by_id), (StatusCode::OK, StatusCode::OK));
+    assert!(res.unwrap_err().to_string().contains("did not answer"));
+    silent.abort();
+}
+
+// the polling flow posts its responses form encoded, with python-style booleans
+fn form_encoded(command: &Value) -> Option<String> {
+    let response = match command["command"].as_str()? {
+        "wavegen_is_running" => "%7B%22Ok%22%3A+True%7D",
+        "echo" => "%7B%22Ok%22%3A+%22two+words%22%7D",
+        _ => "%7B%22Ok%22%3A+null%7D",
+    };
+    Some(response.to_string())
+}
+
+#[tokio::test]
+async fn polling_responses_are_decoded_and_patched() {
+    let pa = bridge();
+    let (handle, seen) = connect(&pa, Flow::Polling, form_encoded).await;
+    assert!(pa.wavegen_is_running().await.unwrap());
+    pa.wavegen_set_period(2.5).await.unwrap();
+    assert_eq!(pa.echo("two words").await.unwrap(), "two words");
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen[0]["command"], "wavegen_is_running");
+    assert_eq!(seen[1]["command"], "wavegen_set_period");
+    assert_eq!(seen[1]["period"], 2.5);
+    handle.abort();
+}
+
+fn garbled(command: &Value) -> Option<String> {
+    if command["message"] == "garble" {
+        return Some("{\"Ok\": ".to_string());
+    }
+    answer(command)
+}
+
+#[tokio::test]
+async fn server_survives_spurious_posts_and_malformed_responses() {
+    let pa = bridge();
+    let addr = pa.local_addr();
+    let ok = json!({ "Ok": true }).to_string();
+    assert_eq!(
+        http(addr, Method::POST, "/", ok.clone()).await.0,
+        StatusCode::OK
+    );
+    assert_eq!(http(addr, Method::POST, "/999", ok).await.0, StatusCode::OK);
+    let (handle, _) = connect(&pa, Flow::Polling, garbled).await;
+    let err = pa.echo("garble").await.unwrap_err();
+    assert!(err.to_string().contains("invalid json"), "{err}");
+    assert!(pa.wavegen_is_running().await.unwrap());
+    assert_eq!(pa.echo("hello").await.unwrap(), "hello");
+    assert_eq!(pa.stats()["echo"].failures, 1);
+    handle.abort();
+}
+
+#[tokio::test]
+async fn only_the_instance_that_took_a_command_can_answer_it() {
+    let pa = bridge();
+    let addr = pa.local_addr();
+    let flows = async {
+        let command = loop {
+            let (_, body) = http(addr, Method::GET, "/?instance=a", String::new()).await;
+            if !body.is_empty() {
+                break serde_json::from_str::<Value>(&body).unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let id = &command["id"];
+        let stale = json!({ "Ok": "stale" }).to_string();
+        let (by_id, _) = http(
+            addr,
+            Method::POST,
+            &format!("/{id}?instance=b"),
+            stale.clone(),
+        )
+        .await;
+        let (next, _) = http(addr, Method::POST, "/?instance=b", stale).await;
+        let answer = json!({ "Ok": command["message"] }).to_string();
+        let (own, _) = http(addr, Method::POST, &format!("/{id}?instance=a"), answer).await;
+        (by_id, next, own)
+    };
+    let (echoed, statuses) = tokio::join!(pa.echo("hello"), flows);
+    assert_eq!(
+        statuses,
+        (StatusCode::CONFLICT, StatusCode::CONFLICT, StatusCode::OK)
+    );
+    assert_eq!(echoed.unwrap(), "hello");
+}
+
+// holds each echo for as many milliseconds as its message says before answering it
+async fn slow_flow(addr: SocketAddr, start_after: Duration) {
+    tokio::time::sleep(start_after).await;
+    loop {
+        let (_, body) = http(addr, Method::GET, "/?instance=slow", String::new()).await;
+        if body.is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            continue;
+        }
+        let command: Value = serde_json::from_str(&body).unwrap();
+        let hold = command["message"].as_str().unwrap_or("0").parse().unwrap();
+        tokio::time::sleep(Duration::from_millis(hold)).await;
+        let path = format!("/{}?instance=slow", command["id"]);
+        http(addr, Method::POST, &path, answer(&command).unwrap()).await;
+    }
+}
+
+#[tokio::test]
+async fn latency_splits_queue_wait_from_turnaround() {
+    let pa = bridge();
+    let flow = tokio::spawn(slow_flow(pa.local_addr(), Duration::from_millis(150)));
+    // 18 quick answers and 2 slow ones, so the slow ones are the top 5 percent
+    for i in 0..20 {
+        let hold = if i % 10 == 9 { "200" } else { "0" };
+        assert_eq!(pa.echo(hold).await.unwrap(), hold);
+    }
+    let stats = &pa.stats()["echo"];
+    assert_eq!(stats.count, 20);
+    assert!(stats.p95_ms >= 200., "{stats:?}");
+    assert!(stats.mean_ms < stats.p95_ms / 2., "{stats:?}");
+    // only the first echo waited for the flow to start polling
+    assert!(stats.mean_queue_ms >= 150. / 20., "{stats:?}");
+    assert!(stats.mean_turnaround_ms >= 400. / 20., "{stats:?}");
+    flow.abort();
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use power_automate::{Acquisition, WavegenSettings};
+
+const FIXTURE: &str = "tests/fixtures/nanonis_history.dat";
+
+fn acquisition() -> Acquisition {
+    let settings = WavegenSettings {
+        pkpk: 200.,
+        period: Duration::from_secs(2),
+        symmetry_p: 100.,
+        offset: 0.,
+    };
+    let mut aq = Acquisition::new(
+        vec![1e-6, 2e-6, 3e-6],
+        vec![0.5, 0.25, -0.125],
+        vec![1., 2., 3.],
+        settings,
+        1.,
+    )
+    .unwrap();
+    for (key, value) in [
+        ("Date", "17.10.2026 09:30:00"),
+        ("Saved Date", "17.10.2026 09:35:12"),
+        ("windows", "3"),
+    ] {
+        aq.metadata.insert(key.into(), value.into());
+    }
+    aq
+}
+
+#[test]
+fn nanonis_dat_matches_the_reference_export() {
+    let mut bytes = vec![];
+    acquisition().write_nanonis_dat(&mut bytes).unwrap();
+    assert_eq!(
+        String::from_utf8(bytes).unwrap(),
+        std::fs::read_to_string(FIXTURE).unwrap()
+    );
+}
+
+#[test]
+fn reference_export_reads_back() {
+    let aq = Acquisition::read_from_file(FIXTURE).unwrap();
+    let expected = acquisition();
+    assert_eq!(aq.probe, expected.probe);
+    assert_eq!(aq.current, expected.current);
+    assert_eq!(aq.voltage, expected.voltage);
+    assert_eq!(aq.wavegen_settings, expected.wavegen_settings);
+    assert_eq!(aq.experiment(), Some("History Data"));
+    assert_eq!(aq.get_meta("Saved Date"), Some("17.10.2026 09:35:12"));
+}